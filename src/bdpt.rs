@@ -0,0 +1,582 @@
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ndarray::Array3;
+use ultraviolet::DVec3;
+use crate::camera::{Camera, CameraConfig, THREAD_COUNT, unit_disk_samp};
+use crate::raytracing::{HittableList, LightSample, Ray, RayHit, environment_light, emitters, occluded, punctual_light, punctual_lights, sample_emitter, get_world_hit_index, square_samp};
+use crate::spectral::{Wavelengths, WAVELENGTH_COUNT};
+
+// thin lens camera model with the importance and pdfs light tracing needs.
+// the image rectangle lives on the focus plane, a pinhole counts as a lens of area 1
+struct Lens {
+    center: DVec3,
+    forward: DVec3,
+    corner: DVec3,
+    pixel_delta_u: DVec3,
+    pixel_delta_v: DVec3,
+    width: usize,
+    height: usize,
+    focus_dist: f64,
+    disk_u: DVec3,
+    disk_v: DVec3,
+    lens_area: f64,
+    image_area: f64,
+}
+
+impl Lens {
+    fn new(camera: &Camera, config: &CameraConfig) -> Lens {
+        let forward = config.pixel_delta_u.cross(config.pixel_delta_v).normalized();
+        let pinhole = config.defocus_angle <= 0.0;
+        let radius = config.defocus_disk_u.mag();
+        Lens {
+            center: config.camera_center,
+            forward,
+            corner: config.pixel_zero_loc - 0.5 * (config.pixel_delta_u + config.pixel_delta_v),
+            pixel_delta_u: config.pixel_delta_u,
+            pixel_delta_v: config.pixel_delta_v,
+            width: camera.width as usize,
+            height: camera.height as usize,
+            focus_dist: (config.pixel_zero_loc - config.camera_center).dot(forward),
+            disk_u: if pinhole {DVec3::zero()} else {config.defocus_disk_u},
+            disk_v: if pinhole {DVec3::zero()} else {config.defocus_disk_v},
+            lens_area: if pinhole || radius == 0.0 {1.0} else {PI * radius * radius},
+            image_area: config.pixel_delta_u.mag() * camera.width as f64 * config.pixel_delta_v.mag() * camera.height as f64,
+        }
+    }
+
+    fn sample_point(&self) -> DVec3 {
+        let p = unit_disk_samp();
+        self.center + p.x * self.disk_u + p.y * self.disk_v
+    }
+
+    // pixel hit by a ray leaving lens point o along dir
+    fn raster(&self, o: DVec3, dir: DVec3) -> Option<(usize, usize)> {
+        let dir = dir.normalized();
+        let cos = dir.dot(self.forward);
+        if cos <= 0.0 {return None};
+        let q = o + dir * (self.focus_dist / cos);
+        let rel = q - self.corner;
+        let x = rel.dot(self.pixel_delta_u) / self.pixel_delta_u.mag_sq();
+        let y = rel.dot(self.pixel_delta_v) / self.pixel_delta_v.mag_sq();
+        if x < 0.0 || y < 0.0 || x >= self.width as f64 || y >= self.height as f64 {return None};
+        Some((x as usize, y as usize))
+    }
+
+    // solid angle density of primary rays from o along dir
+    fn pdf_dir(&self, o: DVec3, dir: DVec3) -> f64 {
+        if self.raster(o, dir).is_none() {return 0.0};
+        let cos = dir.normalized().dot(self.forward);
+        self.focus_dist * self.focus_dist / (self.image_area * cos * cos * cos)
+    }
+
+    // normalized so a primary ray carries a throughput of exactly one
+    fn importance(&self, o: DVec3, dir: DVec3) -> f64 {
+        if self.raster(o, dir).is_none() {return 0.0};
+        let cos = dir.normalized().dot(self.forward);
+        self.pdf_dir(o, dir) / (self.lens_area * cos)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    p: DVec3,
+    // zero for the camera
+    n: DVec3,
+    beta: DVec3,
+    delta: bool,
+    // area densities of sampling this vertex from the camera side and from the light side
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    // index into the world for light and surface vertices
+    object: usize,
+    // the emitter a light vertex was sampled on
+    light: Option<LightSample>,
+    // the ray that arrived at a surface vertex and what it hit
+    hit: Option<(Ray, RayHit)>,
+}
+
+impl Vertex {
+    fn camera(p: DVec3) -> Vertex {
+        Vertex{kind: VertexKind::Camera, p, n: DVec3::zero(), beta: DVec3::one(), delta: false, pdf_fwd: 0.0, pdf_rev: 0.0, object: 0, light: None, hit: None}
+    }
+
+    // where connection rays towards w start, off the surface the vertex lies on
+    fn spawn_point(&self, w: DVec3) -> DVec3 {
+        match (&self.hit, &self.light) {
+            (Some((_, rec)), _) => rec.spawn_point(w),
+            (None, Some(light)) => light.spawn_point(w),
+            (None, None) => self.p,
+        }
+    }
+
+    fn connectible(&self) -> bool {
+        self.kind != VertexKind::Surface || !self.delta
+    }
+
+    fn is_emitter(&self) -> bool {
+        match &self.hit {
+            Some((_, rec)) => rec.mat.is_emissive(),
+            None => self.kind == VertexKind::Light
+        }
+    }
+
+    // bsdf value for scattering between the arriving ray and next, no cosine
+    fn f(&self, next: &Vertex) -> DVec3 {
+        match &self.hit {
+            Some((r_in, rec)) => rec.mat.eval(r_in, rec, (next.p - self.p).normalized()),
+            None => DVec3::zero()
+        }
+    }
+
+    // |cos| between the surface normal and the direction to other
+    fn cos_to(&self, other: &Vertex) -> f64 {
+        if self.kind == VertexKind::Camera {return 1.0};
+        self.n.dot((other.p - self.p).normalized()).abs()
+    }
+
+    // area density at `to` for a solid angle density leaving this vertex
+    fn convert_density(&self, pdf_dir: f64, to: &Vertex) -> f64 {
+        let w = to.p - self.p;
+        let dist2 = w.mag_sq();
+        if dist2 == 0.0 {return 0.0};
+        let mut pdf = pdf_dir / dist2;
+        if to.kind != VertexKind::Camera {
+            pdf *= to.n.dot(w / dist2.sqrt()).abs();
+        }
+        pdf
+    }
+
+    // area density of choosing this point when starting a light path
+    fn pdf_light_origin(&self, world: &HittableList, light_count: usize) -> f64 {
+        if !self.is_emitter() || light_count == 0 {return 0.0};
+        1.0 / (light_count as f64 * world[self.object].area())
+    }
+
+    fn emits_two_sided(&self) -> bool {
+        match (&self.light, &self.hit) {
+            (Some(light), _) => light.mat.emits_two_sided(),
+            (_, Some((_, rec))) => rec.mat.emits_two_sided(),
+            _ => false
+        }
+    }
+
+    // area density at `to` of emitting towards it from this light, matching LightSample::sample_direction
+    fn pdf_light(&self, to: &Vertex) -> f64 {
+        let cos = self.n.dot((to.p - self.p).normalized());
+        if self.emits_two_sided() {return self.convert_density(cos.abs() / (2.0 * PI), to)};
+        if cos <= 0.0 {return 0.0};
+        self.convert_density(cos / PI, to)
+    }
+
+    // area density at `next` of sampling it from this vertex, having arrived from prev
+    fn pdf(&self, lens: &Lens, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        match self.kind {
+            VertexKind::Camera => self.convert_density(lens.pdf_dir(self.p, next.p - self.p), next),
+            VertexKind::Light => self.pdf_light(next),
+            VertexKind::Surface => {
+                let (Some(prev), Some((r_in, rec))) = (prev, &self.hit) else {return 0.0};
+                if rec.mat.is_delta() {return 0.0};
+                let r = r_in.spawn(prev.p, self.p - prev.p, DVec3::one());
+                self.convert_density(rec.mat.pdf(&r, rec, (next.p - self.p).normalized()), next)
+            }
+        }
+    }
+
+    fn terminated(&self) -> bool {
+        match &self.hit {
+            Some((r_in, _)) => r_in.wavelengths.is_some_and(|w| w.secondary_terminated),
+            None => false
+        }
+    }
+}
+
+fn visible(world: &HittableList, a: &Vertex, b: &Vertex) -> bool {
+    !occluded(world, a.spawn_point(b.p - a.p), b.spawn_point(a.p - b.p))
+}
+
+// extends path by following material scattering, returns the ray that escaped the scene if any
+fn random_walk(world: &HittableList, mut ray: Ray, mut pdf_dir: f64, max_vertices: usize, path: &mut Vec<Vertex>) -> Option<Ray> {
+    while path.len() < max_vertices {
+        let Some((object, rec)) = get_world_hit_index(&ray, 0.0, f64::INFINITY, world) else {
+            return Some(ray);
+        };
+        let prev = path.len() - 1;
+        let mut vertex = Vertex{
+            kind: VertexKind::Surface, p: rec.hit_point, n: rec.normal, beta: ray.color, delta: false,
+            pdf_fwd: 0.0, pdf_rev: 0.0, object, light: None, hit: None,
+        };
+        vertex.pdf_fwd = path[prev].convert_density(pdf_dir, &vertex);
+        vertex.hit = Some((ray.clone(), rec.clone()));
+        path.push(vertex);
+
+        if rec.mat.is_emissive() {break};
+        let Some(scattered) = rec.mat.scatter(&ray, &rec) else {break};
+        let wi = scattered.direction.normalized();
+        let cur = path.len() - 1;
+
+        let (pdf_fwd, pdf_rev) = if rec.mat.is_delta() {
+            path[cur].delta = true;
+            (0.0, 0.0)
+        } else {
+            let reversed = ray.spawn(rec.hit_point - wi, wi * -1.0, DVec3::one());
+            (rec.mat.pdf(&ray, &rec, wi), rec.mat.pdf(&reversed, &rec, -ray.direction.normalized()))
+        };
+        path[prev].pdf_rev = path[cur].convert_density(pdf_rev, &path[prev]);
+        pdf_dir = pdf_fwd;
+        ray = scattered;
+    }
+    None
+}
+
+fn camera_subpath(world: &HittableList, lens: &Lens, ray: Ray, max_depth: usize) -> (Vec<Vertex>, Option<Ray>) {
+    let mut path = vec![Vertex::camera(ray.origin)];
+    let pdf_dir = lens.pdf_dir(ray.origin, ray.direction);
+    let escaped = random_walk(world, ray, pdf_dir, max_depth + 2, &mut path);
+    (path, escaped)
+}
+
+fn sample_light(world: &HittableList, lights: &[usize], wavelengths: Option<Wavelengths>) -> Option<Vertex> {
+    let light = sample_emitter(world, lights)?;
+    let beta = light.emitted(light.n, wavelengths) / light.pdf_pos;
+    Some(Vertex{kind: VertexKind::Light, p: light.p, n: light.n, beta, delta: false, pdf_fwd: light.pdf_pos, pdf_rev: 0.0, object: light.object, light: Some(light), hit: None})
+}
+
+fn light_subpath(world: &HittableList, lights: &[usize], wavelengths: Option<Wavelengths>, max_depth: usize) -> Vec<Vertex> {
+    let Some(y0) = sample_light(world, lights, wavelengths) else {return vec![]};
+    let Some(light) = &y0.light else {return vec![y0]};
+    let (dir, pdf_dir) = light.sample_direction();
+    if pdf_dir <= 0.0 {return vec![y0]};
+    let le = light.emitted(dir, wavelengths);
+    let mut ray = Ray::new(light.spawn_point(dir), dir, le * dir.dot(y0.n).abs() / (light.pdf_pos * pdf_dir));
+    ray.wavelengths = wavelengths;
+    let mut path = vec![y0];
+    random_walk(world, ray, pdf_dir, max_depth + 1, &mut path);
+    path
+}
+
+// the pdfs that change when two subpaths are joined
+struct MisVertex {
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    delta: bool,
+}
+
+fn mis_list(path: &[Vertex]) -> Vec<MisVertex> {
+    path.iter().map(|v| MisVertex{pdf_fwd: v.pdf_fwd, pdf_rev: v.pdf_rev, delta: v.delta}).collect()
+}
+
+struct Context<'a> {
+    world: &'a HittableList,
+    lens: &'a Lens,
+    lights: &'a [usize],
+    punctual: &'a [usize],
+}
+
+// power heuristic weight of the (s, t) strategy against every other way of building the same path.
+// sampled replaces the last light vertex (s == 1) or camera vertex (t == 1) when it was resampled
+fn mis_weight(ctx: &Context, light: &[Vertex], camera: &[Vertex], sampled: Option<&Vertex>, s: usize, t: usize) -> f64 {
+    if s + t == 2 {return 1.0};
+    let mut lv = mis_list(&light[..s]);
+    let mut cv = mis_list(&camera[..t]);
+
+    let qs = if s > 0 {Some(if s == 1 {sampled.unwrap_or(&light[0])} else {&light[s - 1]})} else {None};
+    let pt = if t == 1 {sampled.unwrap_or(&camera[0])} else {&camera[t - 1]};
+    if let (1, Some(v)) = (s, sampled) {
+        lv[0] = MisVertex{pdf_fwd: v.pdf_fwd, pdf_rev: v.pdf_rev, delta: v.delta};
+    }
+    let qs_minus = if s > 1 {Some(&light[s - 2])} else {None};
+    let pt_minus = if t > 1 {Some(&camera[t - 2])} else {None};
+
+    cv[t - 1].delta = false;
+    if s > 0 {lv[s - 1].delta = false};
+
+    cv[t - 1].pdf_rev = match qs {
+        Some(qs) => qs.pdf(ctx.lens, qs_minus, pt),
+        None => pt.pdf_light_origin(ctx.world, ctx.lights.len()),
+    };
+    if let Some(pt_minus) = pt_minus {
+        cv[t - 2].pdf_rev = match qs {
+            Some(qs) => pt.pdf(ctx.lens, Some(qs), pt_minus),
+            None => pt.pdf_light(pt_minus),
+        };
+    }
+    if let Some(qs) = qs {
+        lv[s - 1].pdf_rev = pt.pdf(ctx.lens, pt_minus, qs);
+        if let Some(qs_minus) = qs_minus {
+            lv[s - 2].pdf_rev = qs.pdf(ctx.lens, Some(pt), qs_minus);
+        }
+    }
+
+    let remap = |f: f64| if f != 0.0 {f} else {1.0};
+    let mut sum = 0.0;
+    let mut ri = 1.0;
+    for i in (1..t).rev() {
+        ri *= (remap(cv[i].pdf_rev) / remap(cv[i].pdf_fwd)).powi(2);
+        if !cv[i].delta && !cv[i - 1].delta {sum += ri};
+    }
+    ri = 1.0;
+    for i in (0..s).rev() {
+        ri *= (remap(lv[i].pdf_rev) / remap(lv[i].pdf_fwd)).powi(2);
+        let delta_prev = i > 0 && lv[i - 1].delta;
+        if !lv[i].delta && !delta_prev {sum += ri};
+    }
+    1.0 / (1.0 + sum)
+}
+
+// when both halves went through a dispersive event the hero weight was applied twice
+fn spectral_fixup(l: DVec3, a: &Vertex, b: &Vertex) -> DVec3 {
+    if a.terminated() && b.terminated() {l / WAVELENGTH_COUNT as f64} else {l}
+}
+
+// contribution of the (s, t) strategy, plus the pixel it lands in when it was light traced (t == 1)
+fn connect(ctx: &Context, light: &[Vertex], camera: &[Vertex], wavelengths: Option<Wavelengths>, s: usize, t: usize) -> Option<(DVec3, Option<(usize, usize)>)> {
+    if s == 0 {
+        // the camera path hit a light on its own
+        let pt = &camera[t - 1];
+        let (r_in, rec) = pt.hit.as_ref()?;
+        if !rec.mat.is_emissive() {return None};
+        let l = pt.beta * rec.mat.emitted(r_in, rec);
+        if l == DVec3::zero() {return None};
+        return Some((l * mis_weight(ctx, light, camera, None, s, t), None));
+    }
+
+    if t == 1 {
+        // connect a light vertex straight to the lens
+        let qs = &light[s - 1];
+        if !qs.connectible() {return None};
+        let o = ctx.lens.sample_point();
+        let dir = qs.p - o;
+        let raster = ctx.lens.raster(o, dir)?;
+        let importance = ctx.lens.importance(o, dir);
+        let mut cam = Vertex::camera(o);
+        if importance == 0.0 || !visible(ctx.world, qs, &cam) {return None};
+        let cos_lens = dir.normalized().dot(ctx.lens.forward);
+        let pdf = dir.mag_sq() / (cos_lens * ctx.lens.lens_area);
+        cam.beta = DVec3::one() * (importance / pdf);
+        let l = qs.beta * qs.f(&cam) * cam.beta * qs.cos_to(&cam);
+        if l == DVec3::zero() {return None};
+        return Some((l * mis_weight(ctx, light, camera, Some(&cam), s, t), Some(raster)));
+    }
+
+    let pt = &camera[t - 1];
+    if !pt.connectible() || pt.is_emitter() {return None};
+
+    if s == 1 {
+        // next event estimation against a freshly sampled light point
+        let mut sampled = sample_light(ctx.world, ctx.lights, wavelengths)?;
+        let to_pt = pt.p - sampled.p;
+        let le = sampled.light.as_ref()?.emitted(to_pt.normalized(), wavelengths);
+        let cos_light = sampled.n.dot(to_pt.normalized()).abs();
+        if le == DVec3::zero() || !visible(ctx.world, pt, &sampled) {return None};
+        let pdf_pos = sampled.pdf_fwd;
+        sampled.beta = le * cos_light / (to_pt.mag_sq() * pdf_pos);
+        let l = pt.beta * pt.f(&sampled) * sampled.beta * pt.cos_to(&sampled);
+        if l == DVec3::zero() {return None};
+        return Some((l * mis_weight(ctx, light, camera, Some(&sampled), s, t), None));
+    }
+
+    let qs = &light[s - 1];
+    if !qs.connectible() {return None};
+    let d = qs.p - pt.p;
+    let g = qs.cos_to(pt) * pt.cos_to(qs) / d.mag_sq();
+    let l = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta * g;
+    if l == DVec3::zero() || !visible(ctx.world, pt, qs) {return None};
+    let l = spectral_fixup(l, pt, qs);
+    Some((l * mis_weight(ctx, light, camera, None, s, t), None))
+}
+
+fn to_rgb(wavelengths: Option<Wavelengths>, l: DVec3) -> DVec3 {
+    match wavelengths {
+        Some(w) => w.radiance_to_rgb(l),
+        None => l
+    }
+}
+
+// one sample of pixel (i, j), camera strategies are returned, light traced ones queued in splats
+// as (x, y, rgb)
+#[allow(clippy::too_many_arguments)]
+fn sample_pixel(ctx: &Context, config: &CameraConfig, i: usize, j: usize, max_depth: usize, spectral: bool, splats: &mut Vec<(usize, usize, DVec3)>) -> DVec3 {
+    let wavelengths = if spectral {Some(Wavelengths::sample())} else {None};
+    let pixel_center = config.pixel_zero_loc + (i as f64 * config.pixel_delta_u) + (j as f64 * config.pixel_delta_v);
+    let o = ctx.lens.sample_point();
+    let mut r = Ray::new(o, pixel_center + square_samp(config.pixel_delta_u, config.pixel_delta_v) - o, DVec3::one());
+    r.wavelengths = wavelengths;
+
+    let (camera, escaped) = camera_subpath(ctx.world, ctx.lens, r, max_depth);
+    let light = light_subpath(ctx.world, ctx.lights, wavelengths, max_depth);
+
+    let mut l = DVec3::zero();
+    // the sky and punctual lights can only be found from the camera path, so they need no weighting
+    if let Some(ray) = escaped {
+        l += ray.color * environment_light(ray);
+    }
+    for pt in camera.iter().skip(1) {
+        if let Some((r_in, rec)) = &pt.hit {
+            l += pt.beta * punctual_light(ctx.world, ctx.punctual, r_in, rec);
+        }
+    }
+
+    for t in 1..=camera.len() {
+        for s in 0..=light.len() {
+            if (s == 1 && t == 1) || s + t < 2 || s + t - 2 > max_depth {continue};
+            let Some((c, raster)) = connect(ctx, &light, &camera, wavelengths, s, t) else {continue};
+            match raster {
+                Some((x, y)) => splats.push((x, y, to_rgb(wavelengths, c))),
+                None => l += c,
+            }
+        }
+    }
+    to_rgb(wavelengths, l)
+}
+
+// bidirectional path tracing with multiple importance sampling, light subpaths start on emissive objects
+pub fn render_pass(camera: &Camera, world: &Arc<HittableList>, config: &Arc<CameraConfig>) -> Array3<f64> {
+    let width = camera.width as usize;
+    let height = camera.height as usize;
+    let samples = camera.samples.max(1) as usize;
+    let max_depth = camera.max_depth.max(0) as usize;
+    let spectral = camera.spectral;
+
+    let mut img: Array3<f64> = Array3::zeros((height, width, 3));
+    // light paths land anywhere in the frame, every thread adds its splats here a row at a time
+    let light_img = Arc::new(Mutex::new(Array3::<f64>::zeros((height, width, 3))));
+    let num_chunks = THREAD_COUNT as usize;
+    let chunk_height = height.div_ceil(num_chunks);
+    let mut v = Vec::new();
+
+    for chunk_idx in 0..num_chunks {
+        let start_y = chunk_idx * chunk_height;
+        let end_y = (start_y + chunk_height).min(height);
+        if start_y >= height {break};
+
+        let world = Arc::clone(world);
+        let config = Arc::clone(config);
+        let light_img = Arc::clone(&light_img);
+        let camera = *camera;
+
+        let jh = thread::spawn(move || {
+            fastrand::seed(chunk_idx as u64 + fastrand::u64(..));
+            let lens = Lens::new(&camera, &config);
+            let lights = emitters(&world);
+            let punctual = punctual_lights(&world);
+            let ctx = Context{world: &world, lens: &lens, lights: &lights, punctual: &punctual};
+
+            let mut sub_img: Array3<f64> = Array3::zeros((end_y - start_y, width, 3));
+            let mut splats = Vec::new();
+            for j in start_y..end_y {
+                for i in 0..width {
+                    let mut pixel_color = DVec3::zero();
+                    for _ in 0..samples {
+                        pixel_color += sample_pixel(&ctx, &config, i, j, max_depth, spectral, &mut splats);
+                    }
+                    sub_img[(j - start_y, i, 0)] = pixel_color.x / samples as f64;
+                    sub_img[(j - start_y, i, 1)] = pixel_color.y / samples as f64;
+                    sub_img[(j - start_y, i, 2)] = pixel_color.z / samples as f64;
+                }
+                let mut light_img = light_img.lock().unwrap();
+                for (x, y, rgb) in splats.drain(..) {
+                    light_img[(y, x, 0)] += rgb.x;
+                    light_img[(y, x, 1)] += rgb.y;
+                    light_img[(y, x, 2)] += rgb.z;
+                }
+            }
+            (start_y, end_y, sub_img)
+        });
+        v.push(jh);
+    }
+
+    for jh in v {
+        let (start_y, end_y, sub_img) = jh.join().unwrap();
+        for j in start_y..end_y {
+            for i in 0..width {
+                for c in 0..3 {
+                    img[(j, i, c)] += sub_img[(j - start_y, i, c)];
+                }
+            }
+        }
+    }
+    // every camera sample traced one light path, so splats average over samples per pixel
+    img.scaled_add(1.0 / samples as f64, &light_img.lock().unwrap());
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::IntegratorKind;
+    use crate::materials::{Emissive, Lambertian};
+    use crate::raytracing::Sphere;
+
+    // closed diffuse room, the inside of a sphere, lit by a small glowing ball
+    fn room() -> HittableList {
+        vec![
+            Box::new(Sphere{center: DVec3::zero(), radius: 3.0, mat: Box::new(Lambertian{albedo: DVec3::new(0.5, 0.4, 0.3)})}),
+            Box::new(Sphere{center: DVec3::new(0.0, 1.0, 0.0), radius: 1.0, mat: Box::new(Emissive{strength: 1.0, color: DVec3::one()})}),
+        ]
+    }
+
+    fn camera(samples: i32) -> (Camera, CameraConfig) {
+        let camera = Camera{width: 4, height: 4, samples, max_depth: 12, vfov: 90.0, spectral: false, integrator: IntegratorKind::PathTracer};
+        let config = camera.get_config(DVec3::new(0.0, -1.0, 2.0), DVec3::zero(), DVec3::unit_y(), 0.0, 1.0);
+        (camera, config)
+    }
+
+    // the surface seen from `from` towards `to`, as a path vertex
+    fn surface(world: &HittableList, from: DVec3, to: DVec3) -> Vertex {
+        let ray = Ray::new(from, to - from, DVec3::one());
+        let (object, rec) = get_world_hit_index(&ray, 1e-6, f64::INFINITY, world).unwrap();
+        Vertex{kind: VertexKind::Surface, p: rec.hit_point, n: rec.normal, beta: DVec3::one(), delta: false, pdf_fwd: 0.0, pdf_rev: 0.0, object, light: None, hit: Some((ray, rec))}
+    }
+
+    #[test]
+    fn mis_weights_of_one_path_sum_to_one() {
+        let world = room();
+        let (camera, config) = camera(1);
+        let lens = Lens::new(&camera, &config);
+        let lights = emitters(&world);
+        let ctx = Context{world: &world, lens: &lens, lights: &lights, punctual: &[]};
+
+        // the camera, two bounces off the walls, then the light
+        let mut path = vec![Vertex::camera(config.camera_center)];
+        path.push(surface(&world, path[0].p, config.pixel_zero_loc + 1.5 * (config.pixel_delta_u + config.pixel_delta_v)));
+        path.push(surface(&world, path[1].p, DVec3::new(2.0, 1.0, -1.0)));
+        path.push(surface(&world, path[2].p, DVec3::new(0.0, 1.0, 0.0)));
+        assert_eq!(path[3].object, 1);
+        let mat = dyn_clone::clone_box(world[1].material().unwrap());
+        let sample = LightSample{object: 1, p: path[3].p, n: path[3].n, pdf_pos: 1.0 / world[1].area(), mat};
+        let light = Vertex{kind: VertexKind::Light, hit: None, light: Some(sample), ..path[3].clone()};
+
+        // densities of every vertex sampled from the camera side and from the light side
+        let fwd = [0.0, path[0].pdf(&lens, None, &path[1]), path[1].pdf(&lens, Some(&path[0]), &path[2]), path[2].pdf(&lens, Some(&path[1]), &path[3])];
+        let rev = [0.0, path[2].pdf(&lens, Some(&light), &path[1]), light.pdf(&lens, None, &path[2]), light.pdf_light_origin(&world, lights.len())];
+        let camera_path: Vec<Vertex> = (0..4).map(|i| Vertex{pdf_fwd: fwd[i], pdf_rev: rev[i], ..path[i].clone()}).collect();
+        let light_path: Vec<Vertex> = (0..3).map(|j| {
+            let v = if j == 0 {&light} else {&path[3 - j]};
+            Vertex{pdf_fwd: rev[3 - j], pdf_rev: fwd[3 - j], ..v.clone()}
+        }).collect();
+
+        let weights: Vec<f64> = (1..=4).map(|t| mis_weight(&ctx, &light_path, &camera_path, None, 4 - t, t)).collect();
+        assert!(weights.iter().all(|w| *w > 0.0 && *w < 1.0), "{:?}", weights);
+        assert!((weights.iter().sum::<f64>() - 1.0).abs() < 1e-9, "{:?}", weights);
+    }
+
+    #[test]
+    fn agrees_with_the_path_tracer() {
+        let world = Arc::new(room());
+        let (camera, config) = camera(4096);
+        let config = Arc::new(config);
+        let path_traced = camera.render_pass(&world, &config, &mut None).mean().unwrap();
+        let bidirectional = render_pass(&camera, &world, &config).mean().unwrap();
+        assert!((path_traced - bidirectional).abs() < 0.05 * path_traced, "{} {}", path_traced, bidirectional);
+    }
+}
@@ -0,0 +1,323 @@
+use ultraviolet::{DVec3, f64x4};
+use wide::CmpLe;
+use crate::raytracing::{BoundingBox, Ray, gamma};
+
+// primitives per leaf, also the width of the triangle tests
+pub const LEAF_SIZE: usize = 4;
+const BINS: usize = 12;
+// deeper than this the builder stops looking for good splits and halves the range
+const MAX_DEPTH: usize = 48;
+const EMPTY: u32 = u32::MAX;
+// three pending siblings per level, with room for the halving below MAX_DEPTH
+const STACK_SIZE: usize = 3 * (MAX_DEPTH + 32) + 1;
+
+// four child boxes side by side so one ray is tested against all of them at once
+#[derive(Clone)]
+pub struct Node {
+    min: [f64x4; 3],
+    max: [f64x4; 3],
+    // node index of inner children, first primitive of leaves
+    child: [u32; 4],
+    // primitives in leaf children, 0 for inner ones
+    count: [u8; 4],
+}
+
+// 4 wide bounding volume hierarchy. primitives are referred to by position in the order the
+// build returns, so callers store them sorted that way and leaves are contiguous runs
+pub struct Bvh {
+    pub nodes: Vec<Node>,
+}
+
+enum Binary {
+    Leaf{bounds: BoundingBox, start: usize, count: usize},
+    Inner{bounds: BoundingBox, left: usize, right: usize},
+}
+
+impl Binary {
+    fn bounds(&self) -> &BoundingBox {
+        match self {
+            Binary::Leaf{bounds, ..} | Binary::Inner{bounds, ..} => bounds,
+        }
+    }
+}
+
+// binned surface area heuristic split of order[start..end], returns the end of the left half or
+// None when a leaf is cheaper
+fn split(order: &mut [u32], bounds: &impl Fn(usize) -> BoundingBox, node: &BoundingBox, depth: usize) -> Option<usize> {
+    let n = order.len();
+    let mut centroids = BoundingBox::empty();
+    for &i in order.iter() {
+        centroids.grow(bounds(i as usize).centroid());
+    }
+    let extent = centroids.max - centroids.min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {0} else if extent.y > extent.z {1} else {2};
+
+    if extent[axis] <= 0.0 || depth >= MAX_DEPTH {
+        if n <= LEAF_SIZE {return None};
+        // nothing to tell the primitives apart, halve them
+        return Some(n / 2);
+    }
+
+    let bin = |c: DVec3| (((c[axis] - centroids.min[axis]) / extent[axis] * BINS as f64) as usize).min(BINS - 1);
+    let mut boxes = [const { BoundingBox::empty() }; BINS];
+    let mut counts = [0usize; BINS];
+    for &i in order.iter() {
+        let b = bounds(i as usize);
+        let k = bin(b.centroid());
+        boxes[k] = boxes[k].union(&b);
+        counts[k] += 1;
+    }
+
+    // cost of splitting after every bin, from running areas in both directions
+    let mut right_area = [0.0; BINS];
+    let mut acc = BoundingBox::empty();
+    for k in (1..BINS).rev() {
+        acc = acc.union(&boxes[k]);
+        right_area[k] = acc.surface_area();
+    }
+    let (mut best, mut best_cost) = (0, f64::INFINITY);
+    let (mut acc, mut left_count) = (BoundingBox::empty(), 0);
+    for k in 0..BINS - 1 {
+        acc = acc.union(&boxes[k]);
+        left_count += counts[k];
+        let right_count = n - left_count;
+        if left_count == 0 || right_count == 0 {continue};
+        let cost = acc.surface_area() * left_count as f64 + right_area[k + 1] * right_count as f64;
+        if cost < best_cost {
+            best = k;
+            best_cost = cost;
+        }
+    }
+
+    // a box test against the children is taken to cost as much as a primitive
+    let leaf_cost = node.surface_area() * n as f64;
+    if n <= LEAF_SIZE && best_cost + node.surface_area() >= leaf_cost {return None};
+    if best_cost == f64::INFINITY {return Some(n / 2)};
+
+    let mut mid = 0;
+    for k in 0..n {
+        if bin(bounds(order[k] as usize).centroid()) <= best {
+            order.swap(k, mid);
+            mid += 1;
+        }
+    }
+    Some(mid)
+}
+
+fn build_binary(order: &mut [u32], start: usize, bounds: &impl Fn(usize) -> BoundingBox, depth: usize, tree: &mut Vec<Binary>) -> usize {
+    let node = order.iter().fold(BoundingBox::empty(), |b, &i| b.union(&bounds(i as usize)));
+    let count = order.len();
+    let index = tree.len();
+    match split(order, bounds, &node, depth) {
+        None => tree.push(Binary::Leaf{bounds: node, start, count}),
+        Some(mid) => {
+            tree.push(Binary::Leaf{bounds: BoundingBox::empty(), start, count: 0});
+            let (left_order, right_order) = order.split_at_mut(mid);
+            let left = build_binary(left_order, start, bounds, depth + 1, tree);
+            let right = build_binary(right_order, start + mid, bounds, depth + 1, tree);
+            tree[index] = Binary::Inner{bounds: node, left, right};
+        }
+    }
+    index
+}
+
+// pulls the binary tree up into nodes of four, always opening the inner child with the largest area
+fn collapse(tree: &[Binary], root: usize, nodes: &mut Vec<Node>) -> u32 {
+    let mut children = match tree[root] {
+        Binary::Inner{left, right, ..} => vec![left, right],
+        Binary::Leaf{..} => vec![root],
+    };
+    while children.len() < 4 {
+        let widest = children.iter().enumerate()
+            .filter(|(_, &c)| matches!(tree[c], Binary::Inner{..}))
+            .max_by(|a, b| tree[*a.1].bounds().surface_area().total_cmp(&tree[*b.1].bounds().surface_area()));
+        let Some((k, _)) = widest else {break};
+        let Binary::Inner{left, right, ..} = tree[children.swap_remove(k)] else {unreachable!()};
+        children.push(left);
+        children.push(right);
+    }
+
+    let index = nodes.len();
+    nodes.push(Node{
+        min: [f64x4::splat(f64::INFINITY); 3],
+        max: [f64x4::splat(-f64::INFINITY); 3],
+        child: [EMPTY; 4],
+        count: [0; 4]
+    });
+    let (mut min, mut max) = ([[f64::INFINITY; 4]; 3], [[-f64::INFINITY; 4]; 3]);
+    let (mut child, mut count) = ([EMPTY; 4], [0; 4]);
+    for (slot, &c) in children.iter().enumerate() {
+        let b = tree[c].bounds();
+        for axis in 0..3 {
+            min[axis][slot] = b.min[axis];
+            max[axis][slot] = b.max[axis];
+        }
+        match tree[c] {
+            Binary::Leaf{start, count: n, ..} => {
+                child[slot] = start as u32;
+                count[slot] = n as u8;
+            }
+            Binary::Inner{..} => child[slot] = collapse(tree, c, nodes),
+        }
+    }
+    nodes[index] = Node{min: min.map(f64x4::from), max: max.map(f64x4::from), child, count};
+    index as u32
+}
+
+impl Bvh {
+    // bounds gives the box of primitive i, the returned order lists primitives as the leaves expect them
+    pub fn build(primitives: usize, bounds: impl Fn(usize) -> BoundingBox) -> (Bvh, Vec<u32>) {
+        let mut order: Vec<u32> = (0..primitives as u32).collect();
+        if primitives == 0 {return (Bvh{nodes: vec![]}, order)};
+        let mut tree = vec![];
+        build_binary(&mut order, 0, &bounds, 0, &mut tree);
+        let mut nodes = vec![];
+        collapse(&tree, 0, &mut nodes);
+        (Bvh{nodes}, order)
+    }
+
+    // calls leaf with the first primitive, the count and the current closest distance for every
+    // leaf the ray reaches, nearest boxes first. leaf returns the new closest distance on a hit.
+    // gives back the number of boxes and primitives tested
+    pub fn traverse(&self, r: &Ray, mut ray_tmax: f64, mut leaf: impl FnMut(usize, usize, f64) -> Option<f64>) -> usize {
+        if self.nodes.is_empty() {return 0};
+        let origin = [r.origin.x, r.origin.y, r.origin.z].map(f64x4::splat);
+        let inv = [1.0 / r.direction.x, 1.0 / r.direction.y, 1.0 / r.direction.z].map(f64x4::splat);
+        // keeps the slab test conservative under rounding
+        let widen = f64x4::splat(1.0 + 2.0 * gamma(3));
+        let mut tests = 0;
+
+        let mut stack = [(0u32, 0.0f64); STACK_SIZE];
+        let mut top = 1;
+        while top > 0 {
+            top -= 1;
+            let (index, entry) = stack[top];
+            if entry > ray_tmax {continue};
+            let node = &self.nodes[index as usize];
+            tests += 4;
+
+            let mut near = f64x4::splat(0.0);
+            let mut far = f64x4::splat(ray_tmax);
+            for axis in 0..3 {
+                let t0 = (node.min[axis] - origin[axis]) * inv[axis];
+                let t1 = (node.max[axis] - origin[axis]) * inv[axis];
+                near = near.max(t0.min(t1));
+                far = far.min(t0.max(t1) * widen);
+            }
+            let mask = near.cmp_le(far).move_mask();
+            let near = near.to_array();
+
+            let mut hits: [(f64, usize); 4] = [(0.0, 0); 4];
+            let mut n = 0;
+            for (slot, &t) in near.iter().enumerate() {
+                if mask & (1 << slot) != 0 && node.child[slot] != EMPTY {
+                    hits[n] = (t, slot);
+                    n += 1;
+                }
+            }
+            hits[..n].sort_by(|a, b| a.0.total_cmp(&b.0));
+            // leaves right away nearest first, inner children pushed so the nearest comes off next
+            for &(t, slot) in &hits[..n] {
+                if node.count[slot] == 0 || t > ray_tmax {continue};
+                tests += node.count[slot] as usize;
+                if let Some(t) = leaf(node.child[slot] as usize, node.count[slot] as usize, ray_tmax) {
+                    ray_tmax = t;
+                }
+            }
+            for &(t, slot) in hits[..n].iter().rev() {
+                if node.count[slot] == 0 {
+                    stack[top] = (node.child[slot], t);
+                    top += 1;
+                }
+            }
+        }
+        tests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_point(scale: f64) -> DVec3 {
+        DVec3::new(fastrand::f64() - 0.5, fastrand::f64() - 0.5, fastrand::f64() - 0.5) * scale
+    }
+
+    // nearest hit of the ray with a sphere past 0 and before t_max
+    fn hit_sphere(r: &Ray, (center, radius): (DVec3, f64), t_max: f64) -> Option<f64> {
+        let oc = r.origin - center;
+        let (a, half_b, c) = (r.direction.mag_sq(), oc.dot(r.direction), oc.mag_sq() - radius * radius);
+        let disc = half_b * half_b - a * c;
+        if disc < 0.0 {return None};
+        [(-half_b - disc.sqrt()) / a, (-half_b + disc.sqrt()) / a].into_iter().find(|&t| t > 0.0 && t < t_max)
+    }
+
+    fn sphere_box((center, radius): (DVec3, f64)) -> BoundingBox {
+        BoundingBox{min: center - DVec3::broadcast(radius), max: center + DVec3::broadcast(radius)}
+    }
+
+    // closest sphere through the hierarchy and by testing all of them, for rays from all over
+    fn check_against_brute_force(spheres: &[(DVec3, f64)]) {
+        let (bvh, order) = Bvh::build(spheres.len(), |i| sphere_box(spheres[i]));
+        let sorted: Vec<(DVec3, f64)> = order.iter().map(|&i| spheres[i as usize]).collect();
+        for _ in 0..2000 {
+            let r = Ray::new(random_point(30.0), random_point(2.0), DVec3::one());
+            let brute = sorted.iter().filter_map(|&s| hit_sphere(&r, s, f64::INFINITY)).min_by(f64::total_cmp);
+            let mut closest = None;
+            bvh.traverse(&r, f64::INFINITY, |start, count, t_max| {
+                let mut t_max = t_max;
+                let mut found = None;
+                for &s in &sorted[start..start + count] {
+                    if let Some(t) = hit_sphere(&r, s, t_max) {
+                        (t_max, found) = (t, Some(t));
+                        closest = Some(t);
+                    }
+                }
+                found
+            });
+            // stacked spheres tie, so the distance is what has to agree
+            assert_eq!(closest, brute);
+        }
+    }
+
+    #[test]
+    fn traversal_finds_the_closest_hit() {
+        let spheres: Vec<(DVec3, f64)> = (0..500).map(|_| (random_point(20.0), 0.2 + fastrand::f64())).collect();
+        check_against_brute_force(&spheres);
+    }
+
+    #[test]
+    fn stacked_primitives_still_build() {
+        // nothing to split on, the builder has to fall back to halving
+        let mut spheres = vec![(DVec3::zero(), 1.0); 300];
+        spheres.extend((0..50).map(|_| (random_point(20.0), 0.5)));
+        check_against_brute_force(&spheres);
+    }
+
+    #[test]
+    fn leaves_cover_every_primitive_once_inside_their_boxes() {
+        let boxes: Vec<BoundingBox> = (0..1000).map(|_| sphere_box((random_point(50.0), fastrand::f64()))).collect();
+        let (bvh, order) = Bvh::build(boxes.len(), |i| boxes[i]);
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert!(sorted.iter().enumerate().all(|(i, &p)| i == p as usize));
+
+        let mut seen = vec![0; boxes.len()];
+        for node in &bvh.nodes {
+            let (min, max) = (node.min.map(|v| v.to_array()), node.max.map(|v| v.to_array()));
+            for slot in 0..4 {
+                if node.child[slot] == EMPTY || node.count[slot] == 0 {continue};
+                assert!(node.count[slot] as usize <= LEAF_SIZE);
+                let start = node.child[slot] as usize;
+                for i in start..start + node.count[slot] as usize {
+                    seen[i] += 1;
+                    let b = boxes[order[i] as usize];
+                    for axis in 0..3 {
+                        assert!(min[axis][slot] <= b.min[axis] && b.max[axis] <= max[axis][slot]);
+                    }
+                }
+            }
+        }
+        assert!(seen.iter().all(|&n| n == 1));
+    }
+}
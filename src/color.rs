@@ -0,0 +1,431 @@
+use std::fs;
+use std::sync::Arc;
+use ndarray::Array3;
+use ultraviolet::{DMat3, DVec3};
+use serde::{Deserialize, Serialize};
+
+// matrices are written row by row, ultraviolet stores columns
+fn rows(r0: [f64; 3], r1: [f64; 3], r2: [f64; 3]) -> DMat3 {
+    DMat3::new(DVec3::from(r0), DVec3::from(r1), DVec3::from(r2)).transposed()
+}
+
+fn srgb_to_xyz() -> DMat3 {
+    rows(
+        [0.4124564, 0.3575761, 0.1804375],
+        [0.2126729, 0.7151522, 0.0721750],
+        [0.0193339, 0.1191920, 0.9503041],
+    )
+}
+
+pub fn xyz_to_srgb() -> DMat3 {
+    rows(
+        [3.2404542, -1.5371385, -0.4985314],
+        [-0.9692660, 1.8760108, 0.0415560],
+        [0.0556434, -0.2040259, 1.0572252],
+    )
+}
+
+fn bradford() -> DMat3 {
+    rows(
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296],
+    )
+}
+
+pub fn luminance(c: DVec3) -> f64 {
+    c.dot(DVec3::new(0.2126729, 0.7151522, 0.0721750))
+}
+
+// the sRGB EOTF, turns an 8 bit style sRGB value into linear rec.709
+pub fn srgb_to_linear(c: DVec3) -> DVec3 {
+    c.map(|v| if v <= 0.04045 {v / 12.92} else {((v + 0.055) / 1.055).powf(2.4)})
+}
+
+pub fn linear_to_srgb(c: DVec3) -> DVec3 {
+    c.map(|v| if v <= 0.0031308 {v * 12.92} else {1.055 * v.powf(1.0 / 2.4) - 0.055})
+}
+
+fn linear_to_rec709(c: DVec3) -> DVec3 {
+    c.map(|v| if v < 0.018 {v * 4.5} else {1.099 * v.powf(0.45) - 0.099})
+}
+
+// how colors handed to materials should be interpreted
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputSpace {
+    Linear,
+    Srgb,
+}
+
+impl InputSpace {
+    pub fn to_linear(self, c: DVec3) -> DVec3 {
+        match self {
+            InputSpace::Linear => c,
+            InputSpace::Srgb => srgb_to_linear(c),
+        }
+    }
+}
+
+// chromaticity of a planckian radiator, kim et al. cubic spline fit (1667K - 25000K)
+fn planckian_xy(temperature: f64) -> (f64, f64) {
+    let t = temperature.clamp(1667.0, 25000.0);
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / t.powi(3) - 0.2343589e6 / t.powi(2) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / t.powi(3) + 2.1070379e6 / t.powi(2) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.1063814 * x.powi(3) - 1.34811020 * x.powi(2) + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x.powi(3) - 1.37418593 * x.powi(2) + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x.powi(3) - 5.87338670 * x.powi(2) + 3.75112997 * x - 0.37001483
+    };
+    (x, y)
+}
+
+fn white_xyz(temperature: f64, tint: f64) -> DVec3 {
+    let (x, y) = planckian_xy(temperature);
+    // tint follows the lightroom convention, positive is magenta
+    let y = y - tint * 1e-4;
+    DVec3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+// von kries adaptation in bradford space that maps a light of the given color
+// temperature to neutral, 6500K with no tint is the identity
+pub fn white_balance(temperature: f64, tint: f64) -> DMat3 {
+    let src = bradford() * white_xyz(temperature, tint);
+    let dst = bradford() * white_xyz(6500.0, 0.0);
+    let scale = DMat3::from_nonuniform_scale(dst / src);
+    xyz_to_srgb() * bradford().inversed() * scale * bradford() * srgb_to_xyz()
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Tonemap {
+    Clamp,
+    Reinhard,
+    Hable,
+    Aces,
+    AgX,
+}
+
+impl Tonemap {
+    pub const ALL: [Tonemap; 5] = [Tonemap::Clamp, Tonemap::Reinhard, Tonemap::Hable, Tonemap::Aces, Tonemap::AgX];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tonemap::Clamp => "None (clamp)",
+            Tonemap::Reinhard => "Reinhard",
+            Tonemap::Hable => "Hable filmic",
+            Tonemap::Aces => "ACES",
+            Tonemap::AgX => "AgX",
+        }
+    }
+
+    // scene linear in, display linear [0, 1] out
+    pub fn apply(self, c: DVec3) -> DVec3 {
+        match self {
+            Tonemap::Clamp => c,
+            Tonemap::Reinhard => reinhard(c),
+            Tonemap::Hable => hable(c),
+            Tonemap::Aces => aces(c),
+            Tonemap::AgX => agx(c),
+        }
+    }
+}
+
+const REINHARD_WHITE: f64 = 4.0;
+
+// extended reinhard on luminance, so saturated colors keep their hue
+fn reinhard(c: DVec3) -> DVec3 {
+    let l = luminance(c);
+    if l <= 0.0 {return DVec3::zero()};
+    let mapped = l * (1.0 + l / (REINHARD_WHITE * REINHARD_WHITE)) / (1.0 + l);
+    c * (mapped / l)
+}
+
+fn hable_partial(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+// john hable's uncharted 2 curve
+fn hable(c: DVec3) -> DVec3 {
+    let exposure_bias = 2.0;
+    let white = 11.2;
+    c.map(|v| hable_partial(v * exposure_bias) / hable_partial(white))
+}
+
+// stephen hill's fit of the ACES RRT + sRGB ODT
+fn aces(c: DVec3) -> DVec3 {
+    let input = rows(
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    );
+    let output = rows(
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    );
+    let v = input * c;
+    let v = v.map(|x| (x * (x + 0.0245786) - 0.000090537) / (x * (0.983729 * x + 0.4329510) + 0.238081));
+    output * v
+}
+
+// troy sobotka's AgX with the polynomial contrast fit, returns display linear values
+fn agx(c: DVec3) -> DVec3 {
+    let inset = DMat3::new(
+        DVec3::new(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        DVec3::new(0.0784335999999992, 0.878468636469772, 0.0784336),
+        DVec3::new(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+    );
+    let outset = DMat3::new(
+        DVec3::new(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        DVec3::new(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        DVec3::new(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    let v = (inset * c).map(|x| (x.max(1e-10).log2().clamp(min_ev, max_ev) - min_ev) / (max_ev - min_ev));
+    let v = v.map(|x| {
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+    (outset * v).map(|x| x.max(0.0).powf(2.2))
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TransferFunction {
+    Linear,
+    Srgb,
+    Rec709,
+}
+
+impl TransferFunction {
+    pub const ALL: [TransferFunction; 3] = [TransferFunction::Srgb, TransferFunction::Rec709, TransferFunction::Linear];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TransferFunction::Linear => "Linear",
+            TransferFunction::Srgb => "sRGB",
+            TransferFunction::Rec709 => "Rec.709",
+        }
+    }
+
+    pub fn encode(self, c: DVec3) -> DVec3 {
+        match self {
+            TransferFunction::Linear => c,
+            TransferFunction::Srgb => linear_to_srgb(c),
+            TransferFunction::Rec709 => linear_to_rec709(c),
+        }
+    }
+}
+
+// a 3D lut in the .cube format, applied to display encoded values
+#[derive(Serialize, Deserialize)]
+pub struct CubeLut {
+    pub size: usize,
+    pub domain_min: DVec3,
+    pub domain_max: DVec3,
+    // red varies fastest, then green, then blue
+    pub table: Vec<DVec3>,
+}
+
+fn parse_vec3(parts: &[&str]) -> Result<DVec3, String> {
+    if parts.len() != 3 {return Err(format!("expected 3 values, got {}", parts.len()))};
+    let mut v = [0.0; 3];
+    for (i, p) in parts.iter().enumerate() {
+        v[i] = p.parse::<f64>().map_err(|e| format!("bad number {:?}: {}", p, e))?;
+    }
+    Ok(DVec3::from(v))
+}
+
+impl CubeLut {
+    pub fn load(filename: &str) -> Result<CubeLut, String> {
+        let text = fs::read_to_string(filename).map_err(|e| format!("couldn't read {}: {}", filename, e))?;
+        CubeLut::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<CubeLut, String> {
+        let mut size = 0;
+        let mut domain_min = DVec3::zero();
+        let mut domain_max = DVec3::one();
+        let mut table = Vec::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {continue};
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts[0] {
+                "TITLE" => {}
+                "LUT_1D_SIZE" => return Err("1D luts are not supported".to_string()),
+                "LUT_3D_SIZE" => size = parts.get(1).and_then(|s| s.parse().ok()).ok_or("bad LUT_3D_SIZE")?,
+                "DOMAIN_MIN" => domain_min = parse_vec3(&parts[1..])?,
+                "DOMAIN_MAX" => domain_max = parse_vec3(&parts[1..])?,
+                // resolve's form of the domain, the same range on every channel
+                "LUT_3D_INPUT_RANGE" => {
+                    let range: Vec<f64> = parts[1..].iter().filter_map(|p| p.parse().ok()).collect();
+                    let [min, max] = range[..] else {return Err("bad LUT_3D_INPUT_RANGE".to_string())};
+                    domain_min = DVec3::broadcast(min);
+                    domain_max = DVec3::broadcast(max);
+                }
+                // other keywords, e.g. the range of a 1D shaper we'd have refused, don't change the table
+                keyword if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {}
+                _ => table.push(parse_vec3(&parts)?),
+            }
+        }
+
+        if size < 2 {return Err("missing LUT_3D_SIZE".to_string())};
+        if table.len() != size * size * size {
+            return Err(format!("expected {} entries, got {}", size * size * size, table.len()));
+        }
+        Ok(CubeLut{size, domain_min, domain_max, table})
+    }
+
+    fn at(&self, r: usize, g: usize, b: usize) -> DVec3 {
+        self.table[(b * self.size + g) * self.size + r]
+    }
+
+    // trilinear lookup
+    pub fn sample(&self, c: DVec3) -> DVec3 {
+        let n = (self.size - 1) as f64;
+        let p = ((c - self.domain_min) / (self.domain_max - self.domain_min)).clamped(DVec3::zero(), DVec3::one()) * n;
+        let i0 = p.map(|x| x.floor().min(n - 1.0));
+        let f = p - i0;
+        let (r, g, b) = (i0.x as usize, i0.y as usize, i0.z as usize);
+
+        let c00 = self.at(r, g, b) * (1.0 - f.x) + self.at(r + 1, g, b) * f.x;
+        let c10 = self.at(r, g + 1, b) * (1.0 - f.x) + self.at(r + 1, g + 1, b) * f.x;
+        let c01 = self.at(r, g, b + 1) * (1.0 - f.x) + self.at(r + 1, g, b + 1) * f.x;
+        let c11 = self.at(r, g + 1, b + 1) * (1.0 - f.x) + self.at(r + 1, g + 1, b + 1) * f.x;
+        let c0 = c00 * (1.0 - f.y) + c10 * f.y;
+        let c1 = c01 * (1.0 - f.y) + c11 * f.y;
+        c0 * (1.0 - f.z) + c1 * f.z
+    }
+}
+
+// scene linear radiance to display encoded values:
+// exposure -> white balance -> tonemap -> OETF -> optional look LUT
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OutputTransform {
+    pub exposure: f64,
+    pub temperature: f64,
+    pub tint: f64,
+    pub tonemap: Tonemap,
+    pub oetf: TransferFunction,
+    pub lut: Option<Arc<CubeLut>>,
+}
+
+impl Default for OutputTransform {
+    fn default() -> OutputTransform {
+        OutputTransform {
+            exposure: 0.0,
+            temperature: 6500.0,
+            tint: 0.0,
+            tonemap: Tonemap::Clamp,
+            oetf: TransferFunction::Srgb,
+            lut: None,
+        }
+    }
+}
+
+impl OutputTransform {
+    pub fn apply(&self, c: DVec3) -> DVec3 {
+        let wb = white_balance(self.temperature, self.tint);
+        self.apply_balanced(&wb, c)
+    }
+
+    fn apply_balanced(&self, wb: &DMat3, c: DVec3) -> DVec3 {
+        let c = *wb * (c * 2f64.powf(self.exposure));
+        let c = self.tonemap.apply(c.max_by_component(DVec3::zero()));
+        let c = self.oetf.encode(c.clamped(DVec3::zero(), DVec3::one()));
+        match &self.lut {
+            Some(lut) => lut.sample(c).clamped(DVec3::zero(), DVec3::one()),
+            None => c,
+        }
+    }
+
+    // averages an accumulation buffer and converts it to 8 bit rgb
+    pub fn to_bytes(&self, accum: &Array3<f64>, count: i32) -> Vec<u8> {
+        let wb = white_balance(self.temperature, self.tint);
+        let (height, width, _) = accum.dim();
+        let mut buffer = vec![0u8; height * width * 3];
+        for y in 0..height {
+            for x in 0..width {
+                let c = DVec3::new(accum[(y, x, 0)], accum[(y, x, 1)], accum[(y, x, 2)]) / count as f64;
+                let out = self.apply_balanced(&wb, c);
+                let i = (y * width + x) * 3;
+                buffer[i] = (out.x * 255.0).round() as u8;
+                buffer[i + 1] = (out.y * 255.0).round() as u8;
+                buffer[i + 2] = (out.z * 255.0).round() as u8;
+            }
+        }
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2x2x2 lut that swaps red and blue
+    const SWAP: &str = "TITLE \"swap\"\n# comment\nLUT_3D_SIZE 2\n\
+        0 0 0\n0 0 1\n0 1 0\n0 1 1\n1 0 0\n1 0 1\n1 1 0\n1 1 1\n";
+
+    #[test]
+    fn cube_lut_parses_and_interpolates() {
+        let lut = CubeLut::parse(SWAP).unwrap();
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.table.len(), 8);
+        let c = lut.sample(DVec3::new(0.25, 0.5, 0.75));
+        assert!((c - DVec3::new(0.75, 0.5, 0.25)).mag() < 1e-12);
+        // out of domain values are clamped
+        let c = lut.sample(DVec3::new(2.0, -1.0, 0.0));
+        assert!((c - DVec3::new(0.0, 0.0, 1.0)).mag() < 1e-12);
+    }
+
+    #[test]
+    fn cube_lut_domain() {
+        let text = format!("DOMAIN_MIN 0 0 0\nDOMAIN_MAX 2 2 2\n{}", SWAP);
+        let lut = CubeLut::parse(&text).unwrap();
+        let c = lut.sample(DVec3::new(1.0, 0.0, 2.0));
+        assert!((c - DVec3::new(1.0, 0.0, 0.5)).mag() < 1e-12);
+    }
+
+    #[test]
+    fn cube_lut_input_range_and_unknown_keywords() {
+        let text = format!("LUT_1D_INPUT_RANGE 0 1\nLUT_3D_INPUT_RANGE 0 2\nLUT_IN_VIDEO_RANGE\n{}", SWAP);
+        let lut = CubeLut::parse(&text).unwrap();
+        assert_eq!((lut.domain_min, lut.domain_max), (DVec3::zero(), DVec3::broadcast(2.0)));
+        let c = lut.sample(DVec3::new(1.0, 0.0, 2.0));
+        assert!((c - DVec3::new(1.0, 0.0, 0.5)).mag() < 1e-12);
+        assert!(CubeLut::parse(&format!("LUT_3D_INPUT_RANGE 0\n{}", SWAP)).is_err());
+    }
+
+    #[test]
+    fn cube_lut_rejects_bad_files() {
+        assert!(CubeLut::parse("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n").is_err());
+        assert!(CubeLut::parse("LUT_3D_SIZE 2\n0 0 0\n").is_err());
+        assert!(CubeLut::parse("0 0 0\n").is_err());
+        assert!(CubeLut::parse("LUT_3D_SIZE 2\n0 0 x\n").is_err());
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for v in [0.0, 0.002, 0.04, 0.2, 0.5, 1.0] {
+            let c = DVec3::broadcast(v);
+            assert!((srgb_to_linear(linear_to_srgb(c)) - c).mag() < 1e-9);
+        }
+        assert_eq!(InputSpace::Linear.to_linear(DVec3::broadcast(0.5)), DVec3::broadcast(0.5));
+    }
+
+    #[test]
+    fn neutral_white_balance_is_identity() {
+        let wb = white_balance(6500.0, 0.0);
+        let c = DVec3::new(0.2, 0.5, 0.9);
+        assert!((wb * c - c).mag() < 1e-3);
+    }
+}
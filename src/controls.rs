@@ -0,0 +1,202 @@
+use fltk::{prelude::*, app::Sender, button::{Button, CheckButton}, enums::{Align, CallbackTrigger}, frame::Frame, group::Group, input::{FloatInput, IntInput}, menu::Choice, misc::Progress};
+use crate::camera::Camera;
+use crate::integrator::IntegratorKind;
+use crate::color::{OutputTransform, Tonemap, TransferFunction};
+use serde::{Deserialize, Serialize};
+
+pub const PANEL_WIDTH: i32 = 220;
+pub const PANEL_HEIGHT: i32 = 550;
+
+const MAX_RESOLUTION: i32 = 8192;
+
+#[derive(Clone, Copy)]
+pub enum Message {
+    PassDone { samples_per_sec: f64 },
+    Pause,
+    Resume,
+    Restart,
+    Save,
+    SaveScene,
+    LoadLut,
+    ClearLut,
+    SettingsChanged,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RenderSettings {
+    pub width: i32,
+    pub height: i32,
+    pub samples: i32,
+    pub max_depth: i32,
+    pub spectral: bool,
+    pub integrator: IntegratorKind,
+    pub target_passes: i32,
+    pub output: OutputTransform,
+    #[serde(skip)]
+    pub paused: bool,
+    // bumped whenever accumulation has to start over
+    #[serde(skip)]
+    pub generation: u64,
+}
+
+impl RenderSettings {
+    pub fn camera(&self, vfov: f64) -> Camera {
+        Camera{width: self.width, height: self.height, samples: self.samples, max_depth: self.max_depth, vfov, spectral: self.spectral, integrator: self.integrator}
+    }
+}
+
+pub struct ControlPanel {
+    pub group: Group,
+    progress: Progress,
+    stats: Frame,
+    samples: IntInput,
+    max_depth: IntInput,
+    width: IntInput,
+    height: IntInput,
+    target_passes: IntInput,
+    spectral: CheckButton,
+    integrator: Choice,
+    exposure: FloatInput,
+    tonemap: Choice,
+    oetf: Choice,
+    temperature: FloatInput,
+    tint: FloatInput,
+}
+
+fn int_field(x: i32, y: i32, label: &'static str, value: i32, s: Sender<Message>) -> IntInput {
+    let mut input = IntInput::new(x + 100, y, PANEL_WIDTH - 110, 24, label);
+    input.set_value(&value.to_string());
+    input.set_trigger(CallbackTrigger::EnterKeyAlways | CallbackTrigger::ReleaseAlways);
+    input.emit(s, Message::SettingsChanged);
+    input
+}
+
+fn float_field(x: i32, y: i32, label: &'static str, value: f64, s: Sender<Message>) -> FloatInput {
+    let mut input = FloatInput::new(x + 100, y, PANEL_WIDTH - 110, 24, label);
+    input.set_value(&value.to_string());
+    input.set_trigger(CallbackTrigger::EnterKeyAlways | CallbackTrigger::ReleaseAlways);
+    input.emit(s, Message::SettingsChanged);
+    input
+}
+
+fn parse_field<T: std::str::FromStr + PartialOrd>(value: String, min: T, max: T) -> Option<T> {
+    value.trim().parse::<T>().ok().filter(|v| *v >= min && *v <= max)
+}
+
+impl ControlPanel {
+    pub fn new(x: i32, settings: &RenderSettings, s: Sender<Message>) -> ControlPanel {
+        let mut group = Group::new(x, 0, PANEL_WIDTH, PANEL_HEIGHT, None);
+
+        let mut progress = Progress::new(x + 10, 10, PANEL_WIDTH - 20, 20, None);
+        progress.set_minimum(0.0);
+        progress.set_maximum(settings.target_passes as f64);
+
+        let mut stats = Frame::new(x + 10, 35, PANEL_WIDTH - 20, 40, None);
+        stats.set_align(Align::Left | Align::Inside);
+        stats.set_label_size(12);
+
+        let button_width = (PANEL_WIDTH - 20) / 3;
+        let mut pause = Button::new(x + 10, 80, button_width, 24, "Pause");
+        pause.emit(s, Message::Pause);
+        let mut resume = Button::new(x + 10 + button_width, 80, button_width, 24, "Resume");
+        resume.emit(s, Message::Resume);
+        let mut restart = Button::new(x + 10 + 2 * button_width, 80, button_width, 24, "Restart");
+        restart.emit(s, Message::Restart);
+        let mut save = Button::new(x + 10, 110, (PANEL_WIDTH - 20) / 2, 24, "Save image...");
+        save.emit(s, Message::Save);
+        let mut save_scene = Button::new(x + 10 + (PANEL_WIDTH - 20) / 2, 110, (PANEL_WIDTH - 20) / 2, 24, "Save scene...");
+        save_scene.emit(s, Message::SaveScene);
+
+        let samples = int_field(x, 150, "Samples", settings.samples, s);
+        let max_depth = int_field(x, 180, "Max depth", settings.max_depth, s);
+        let width = int_field(x, 210, "Width", settings.width, s);
+        let height = int_field(x, 240, "Height", settings.height, s);
+        let target_passes = int_field(x, 270, "Passes", settings.target_passes, s);
+
+        let mut spectral = CheckButton::new(x + 100, 300, PANEL_WIDTH - 110, 24, "Spectral");
+        spectral.set_checked(settings.spectral);
+        spectral.emit(s, Message::SettingsChanged);
+        let mut integrator = Choice::new(x + 100, 330, PANEL_WIDTH - 110, 24, "Integrator");
+        for i in IntegratorKind::ALL {
+            integrator.add_choice(i.name());
+        }
+        integrator.set_value(IntegratorKind::ALL.iter().position(|i| *i == settings.integrator).unwrap_or(0) as i32);
+        integrator.emit(s, Message::SettingsChanged);
+
+        let output = &settings.output;
+        let exposure = float_field(x, 360, "Exposure", output.exposure, s);
+        let mut tonemap = Choice::new(x + 100, 390, PANEL_WIDTH - 110, 24, "Tonemap");
+        for t in Tonemap::ALL {
+            tonemap.add_choice(t.name());
+        }
+        tonemap.set_value(Tonemap::ALL.iter().position(|t| *t == output.tonemap).unwrap_or(0) as i32);
+        tonemap.emit(s, Message::SettingsChanged);
+        let mut oetf = Choice::new(x + 100, 420, PANEL_WIDTH - 110, 24, "Output");
+        for t in TransferFunction::ALL {
+            oetf.add_choice(t.name());
+        }
+        oetf.set_value(TransferFunction::ALL.iter().position(|t| *t == output.oetf).unwrap_or(0) as i32);
+        oetf.emit(s, Message::SettingsChanged);
+        let temperature = float_field(x, 450, "White (K)", output.temperature, s);
+        let tint = float_field(x, 480, "Tint", output.tint, s);
+
+        let mut load_lut = Button::new(x + 10, 510, (PANEL_WIDTH - 20) / 2, 24, "Load LUT...");
+        load_lut.emit(s, Message::LoadLut);
+        let mut clear_lut = Button::new(x + 10 + (PANEL_WIDTH - 20) / 2, 510, (PANEL_WIDTH - 20) / 2, 24, "Clear LUT");
+        clear_lut.emit(s, Message::ClearLut);
+
+        group.end();
+        group.make_resizable(false);
+
+        ControlPanel { group, progress, stats, samples, max_depth, width, height, target_passes, spectral, integrator, exposure, tonemap, oetf, temperature, tint }
+    }
+
+    // copies the fields into settings, restoring any field that doesn't parse.
+    // returns true if accumulation needs to restart
+    pub fn apply(&mut self, settings: &mut RenderSettings) -> bool {
+        let old = settings.clone();
+        settings.samples = parse_field(self.samples.value(), 1, 4096).unwrap_or(old.samples);
+        settings.max_depth = parse_field(self.max_depth.value(), 1, 1024).unwrap_or(old.max_depth);
+        settings.width = parse_field(self.width.value(), 1, MAX_RESOLUTION).unwrap_or(old.width);
+        settings.height = parse_field(self.height.value(), 1, MAX_RESOLUTION).unwrap_or(old.height);
+        settings.target_passes = parse_field(self.target_passes.value(), 1, i32::MAX).unwrap_or(old.target_passes);
+        settings.spectral = self.spectral.is_checked();
+        settings.integrator = IntegratorKind::ALL.get(self.integrator.value() as usize).copied().unwrap_or(old.integrator);
+        settings.output.exposure = parse_field(self.exposure.value(), -20.0, 20.0).unwrap_or(old.output.exposure);
+        settings.output.temperature = parse_field(self.temperature.value(), 1667.0, 25000.0).unwrap_or(old.output.temperature);
+        settings.output.tint = parse_field(self.tint.value(), -150.0, 150.0).unwrap_or(old.output.tint);
+        settings.output.tonemap = Tonemap::ALL.get(self.tonemap.value() as usize).copied().unwrap_or(old.output.tonemap);
+        settings.output.oetf = TransferFunction::ALL.get(self.oetf.value() as usize).copied().unwrap_or(old.output.oetf);
+        self.show(settings);
+
+        let restart = settings.samples != old.samples || settings.max_depth != old.max_depth
+            || settings.width != old.width || settings.height != old.height || settings.spectral != old.spectral
+            || settings.integrator != old.integrator;
+        if restart {
+            settings.generation += 1;
+        }
+        restart
+    }
+
+    fn show(&mut self, settings: &RenderSettings) {
+        self.samples.set_value(&settings.samples.to_string());
+        self.max_depth.set_value(&settings.max_depth.to_string());
+        self.width.set_value(&settings.width.to_string());
+        self.height.set_value(&settings.height.to_string());
+        self.target_passes.set_value(&settings.target_passes.to_string());
+        self.spectral.set_checked(settings.spectral);
+        self.exposure.set_value(&settings.output.exposure.to_string());
+        self.temperature.set_value(&settings.output.temperature.to_string());
+        self.tint.set_value(&settings.output.tint.to_string());
+        self.progress.set_maximum(settings.target_passes as f64);
+    }
+
+    pub fn update_stats(&mut self, passes: i32, settings: &RenderSettings, samples_per_sec: f64) {
+        self.progress.set_value(passes.min(settings.target_passes) as f64);
+        let state = if settings.paused {"paused"} else if passes >= settings.target_passes {"done"} else {"rendering"};
+        self.stats.set_label(&format!(
+            "{} / {} passes ({})\n{:.2} Msamples/s",
+            passes, settings.target_passes, state, samples_per_sec / 1e6
+        ));
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+use ndarray::Array3;
+use serde::{Deserialize, Serialize};
+use crate::camera::{Camera, CameraConfig};
+use crate::integrator::IntegratorKind;
+use crate::raytracing::HittableList;
+use crate::scene::parse_scene;
+
+// edge length of the square tiles a frame is split into
+const TILE_SIZE: usize = 64;
+// camera passes per task, more means fewer round trips but more work lost with a worker
+const PASSES_PER_TASK: i32 = 4;
+// workers send an empty message this often while they render, however long the task takes
+const HEARTBEAT: Duration = Duration::from_secs(10);
+// a worker that sends nothing for this long is given up on and its task handed to another
+const WORKER_TIMEOUT: Duration = Duration::from_secs(60);
+// largest scene and task a worker accepts, regions are checked against the task's size
+const MAX_SCENE_SIZE: usize = 1 << 31;
+const MAX_TASK_SIZE: usize = 1 << 10;
+
+// a rectangle of the frame and how many passes to render it with. integrators that trace from
+// the lights can't render part of a frame, their tasks cover all of it
+#[derive(Clone, Copy, Serialize, Deserialize)]
+struct Task {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    passes: i32,
+}
+
+struct Farm {
+    pending: VecDeque<Task>,
+    // tasks not merged yet, including the ones being rendered
+    remaining: usize,
+    total: usize,
+    // radiance sums and sample counts for the whole frame, laid out like Camera::render_region
+    accum: Array3<f64>,
+}
+
+// every message is its length followed by that many bytes. the scene and tasks are json,
+// rendered regions are little endian f64s and heartbeats are empty
+fn write_frame(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u64).to_le_bytes())?;
+    writer.write_all(data)?;
+    writer.flush()
+}
+
+// a peer announcing more than max_len bytes is broken or hostile, nothing is allocated for it
+fn read_frame(reader: &mut impl Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len);
+    if len > max_len as u64 {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("message of {} bytes, expected at most {}", len, max_len)));
+    }
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+// renders the scene in scene_file on every worker that connects to address, then saves the
+// frame to output once target_passes passes of every pixel have come back
+pub fn coordinate(scene_file: &str, address: &str, output: &str) -> Result<(), String> {
+    let data = std::fs::read(scene_file).map_err(|e| format!("couldn't open {}: {}", scene_file, e))?;
+    let scene = parse_scene(&data).map_err(|e| format!("couldn't load {}: {}", scene_file, e))?;
+    let camera = scene.settings.camera(scene.view.vfov);
+    let (width, height) = (camera.width as usize, camera.height as usize);
+    // photon mapping only converges as one estimate shrinks its radii pass after pass, averaging
+    // separately started ones would keep the blur of the first passes
+    if camera.integrator == IntegratorKind::PhotonMapping {
+        return Err(format!("{} uses photon mapping, which can't be split between workers, render it in the window", scene_file));
+    }
+
+    let tiled = camera.integrator.build(&camera, &scene.world).is_some();
+    let (tile_width, tile_height) = if tiled {(TILE_SIZE, TILE_SIZE)} else {(width, height)};
+    // a whole frame of bdpt is a long enough task on its own
+    let passes_per_task = if tiled {PASSES_PER_TASK} else {1};
+    // pass ranges outermost, so the whole frame fills in early
+    let mut pending = VecDeque::new();
+    for first_pass in (0..scene.settings.target_passes).step_by(passes_per_task as usize) {
+        let passes = passes_per_task.min(scene.settings.target_passes - first_pass);
+        for y in (0..height).step_by(tile_height) {
+            for x in (0..width).step_by(tile_width) {
+                pending.push_back(Task{x, y, width: tile_width.min(width - x), height: tile_height.min(height - y), passes});
+            }
+        }
+    }
+    let total = pending.len();
+    let farm = Arc::new((Mutex::new(Farm{pending, remaining: total, total, accum: Array3::zeros((height, width, 4))}), Condvar::new()));
+
+    let listener = TcpListener::bind(address).map_err(|e| format!("couldn't listen on {}: {}", address, e))?;
+    println!("waiting for workers on {}, {} tasks", address, total);
+    let data = Arc::new(data);
+    let farm_listener = Arc::clone(&farm);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let data = Arc::clone(&data);
+            let farm = Arc::clone(&farm_listener);
+            thread::spawn(move || serve(stream, &data, &farm));
+        }
+    });
+
+    let (lock, changed) = &*farm;
+    let mut state = lock.lock().unwrap();
+    while state.remaining > 0 {
+        state = changed.wait(state).unwrap();
+    }
+
+    let mut img: Array3<f64> = Array3::zeros((height, width, 3));
+    for ((j, i, c), val) in img.indexed_iter_mut() {
+        let n = state.accum[(j, i, 3)];
+        *val = if n > 0.0 {state.accum[(j, i, c)] / n} else {0.0};
+    }
+    let buffer = scene.settings.output.to_bytes(&img, 1);
+    image::save_buffer(output, &buffer, width as u32, height as u32, image::ColorType::Rgb8)
+        .map_err(|e| format!("couldn't save {}: {}", output, e))
+}
+
+// hands tasks to one worker until the frame is done. a worker that fails or times out is
+// dropped and its task goes back in the queue for the others
+fn serve(stream: TcpStream, scene: &[u8], farm: &(Mutex<Farm>, Condvar)) {
+    let (lock, changed) = farm;
+    let peer = stream.peer_addr().map_or("unknown".to_string(), |a| a.to_string());
+    let Ok(read_half) = stream.try_clone() else {return};
+    if stream.set_read_timeout(Some(WORKER_TIMEOUT)).is_err() {return};
+    let mut reader = BufReader::new(read_half);
+    let mut writer = BufWriter::new(stream);
+    if let Err(e) = write_frame(&mut writer, scene) {
+        println!("couldn't send the scene to {}: {}", peer, e);
+        return;
+    }
+    println!("worker {} joined", peer);
+
+    loop {
+        let task = {
+            let mut state = lock.lock().unwrap();
+            loop {
+                if let Some(task) = state.pending.pop_front() {break task};
+                if state.remaining == 0 {return};
+                state = changed.wait(state).unwrap();
+            }
+        };
+        match run_task(&mut reader, &mut writer, task) {
+            Ok(region) => {
+                let mut state = lock.lock().unwrap();
+                for ((j, i, c), val) in region.indexed_iter() {
+                    state.accum[(task.y + j, task.x + i, c)] += val;
+                }
+                state.remaining -= 1;
+                println!("{} of {} tasks done", state.total - state.remaining, state.total);
+                changed.notify_all();
+            }
+            Err(e) => {
+                println!("lost worker {}: {}", peer, e);
+                lock.lock().unwrap().pending.push_front(task);
+                changed.notify_all();
+                return;
+            }
+        }
+    }
+}
+
+fn run_task(reader: &mut impl Read, writer: &mut impl Write, task: Task) -> io::Result<Array3<f64>> {
+    write_frame(writer, &serde_json::to_vec(&task)?)?;
+    let size = task.width * task.height * 4 * 8;
+    let data = loop {
+        let data = read_frame(reader, size)?;
+        if !data.is_empty() {break data};
+    };
+    let values: Vec<f64> = data.chunks_exact(8).map(|b| f64::from_le_bytes(b.try_into().unwrap())).collect();
+    Array3::from_shape_vec((task.height, task.width, 4), values).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+// renders tasks from the coordinator at address until it hangs up
+pub fn work(address: &str) -> Result<(), String> {
+    let stream = TcpStream::connect(address).map_err(|e| format!("couldn't connect to {}: {}", address, e))?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+    let writer = Mutex::new(BufWriter::new(stream));
+
+    let scene = parse_scene(&read_frame(&mut reader, MAX_SCENE_SIZE).map_err(|e| format!("couldn't receive the scene: {}", e))?)?;
+    let world = Arc::new(scene.world);
+    let camera = scene.settings.camera(scene.view.vfov);
+    let view = scene.view;
+    let config = Arc::new(camera.get_config(view.lookfrom, view.lookat, view.vup, view.defocus_angle, view.focus_dist));
+
+    loop {
+        let data = match read_frame(&mut reader, MAX_TASK_SIZE) {
+            Ok(data) => data,
+            // the frame is done
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(format!("lost the coordinator: {}", e)),
+        };
+        let task: Task = serde_json::from_slice(&data).map_err(|e| format!("bad task: {}", e))?;
+        let region = with_heartbeat(&writer, || render_task(&camera, &world, &config, task));
+        let bytes: Vec<u8> = region.iter().flat_map(|v| v.to_le_bytes()).collect();
+        write_frame(&mut *writer.lock().unwrap(), &bytes).map_err(|e| format!("lost the coordinator: {}", e))?;
+    }
+}
+
+// runs render while telling the coordinator every HEARTBEAT that the worker is still at it
+fn with_heartbeat<T>(writer: &Mutex<impl Write + Send>, render: impl FnOnce() -> T) -> T {
+    let done = (Mutex::new(false), Condvar::new());
+    thread::scope(|s| {
+        s.spawn(|| {
+            let (lock, wake) = &done;
+            let mut finished = lock.lock().unwrap();
+            while !*finished {
+                finished = wake.wait_timeout(finished, HEARTBEAT).unwrap().0;
+                // a broken connection shows up when the region is sent
+                if !*finished && write_frame(&mut *writer.lock().unwrap(), &[]).is_err() {break};
+            }
+        });
+        let result = render();
+        *done.0.lock().unwrap() = true;
+        done.1.notify_all();
+        result
+    })
+}
+
+fn render_task(camera: &Camera, world: &Arc<HittableList>, config: &Arc<CameraConfig>, task: Task) -> Array3<f64> {
+    let tile_camera = Camera{samples: camera.samples * task.passes, ..*camera};
+    if let Some(region) = tile_camera.render_region(world, config, task.x..task.x + task.width, task.y..task.y + task.height) {
+        return region;
+    }
+    // whole frames, one pass at a time, every pixel weighted by the samples of a pass
+    let weight = camera.samples.max(1) as f64;
+    let mut region = Array3::zeros((task.height, task.width, 4));
+    for _ in 0..task.passes {
+        let img = camera.render_pass(world, config, &mut None);
+        for j in 0..task.height {
+            for i in 0..task.width {
+                for c in 0..3 {
+                    region[(j, i, c)] += img[(j, i, c)] * weight;
+                }
+                region[(j, i, 3)] += weight;
+            }
+        }
+    }
+    region
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut stream = vec![];
+        for data in [&b"scene"[..], &[], &[7; 300]] {
+            write_frame(&mut stream, data).unwrap();
+        }
+        let mut reader = Cursor::new(stream);
+        assert_eq!(read_frame(&mut reader, 300).unwrap(), b"scene");
+        assert!(read_frame(&mut reader, 300).unwrap().is_empty());
+        assert_eq!(read_frame(&mut reader, 300).unwrap(), vec![7; 300]);
+        assert_eq!(read_frame(&mut reader, 300).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_and_truncated_frames_are_refused() {
+        let mut stream = vec![];
+        write_frame(&mut stream, &[1; 100]).unwrap();
+        assert_eq!(read_frame(&mut Cursor::new(&stream), 99).unwrap_err().kind(), ErrorKind::InvalidData);
+        stream.truncate(50);
+        assert_eq!(read_frame(&mut Cursor::new(&stream), 100).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        // a length no region could have, before anything is allocated
+        let huge = u64::MAX.to_le_bytes();
+        assert_eq!(read_frame(&mut Cursor::new(&huge), MAX_SCENE_SIZE).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn heartbeats_are_skipped_while_waiting_for_a_region() {
+        let task = Task{x: 0, y: 0, width: 2, height: 1, passes: 1};
+        let values: Vec<f64> = (0..8).map(|v| v as f64).collect();
+        let mut replies = vec![];
+        write_frame(&mut replies, &[]).unwrap();
+        write_frame(&mut replies, &[]).unwrap();
+        write_frame(&mut replies, &values.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+        let mut sent = vec![];
+        let region = run_task(&mut Cursor::new(replies), &mut sent, task).unwrap();
+        assert_eq!(region.shape(), &[1, 2, 4]);
+        assert_eq!(region.iter().copied().collect::<Vec<f64>>(), values);
+        let task: Task = serde_json::from_slice(&read_frame(&mut Cursor::new(sent), MAX_TASK_SIZE).unwrap()).unwrap();
+        assert_eq!((task.width, task.height, task.passes), (2, 1, 1));
+    }
+}
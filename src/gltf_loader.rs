@@ -0,0 +1,102 @@
+use std::path::Path;
+use gltf::{buffer, Gltf, Node};
+use gltf::mesh::Mode;
+use ultraviolet::{DMat3, DMat4, DVec3, DVec4, Vec2, Vec3};
+use crate::obj_loader::{generate_tangents, smooth_normals, MeshData, MeshGroup};
+use crate::principled::Principled;
+
+// the default scene with every node's transform baked in, one group per triangle primitive.
+// buffers come from the .glb itself or from files next to the .gltf, data uris aren't read
+pub fn load_gltf(filename: &str) -> Result<Vec<MeshGroup>, String> {
+    let gltf = Gltf::open(filename).map_err(|e| format!("couldn't load {}: {}", filename, e))?;
+    let dir = Path::new(filename).parent().unwrap_or(Path::new(""));
+    let mut buffers = vec![];
+    for buffer in gltf.buffers() {
+        let data = match buffer.source() {
+            buffer::Source::Bin => gltf.blob.clone().ok_or(format!("{} has no binary chunk", filename))?,
+            buffer::Source::Uri(uri) if uri.starts_with("data:") => {
+                return Err(format!("{} embeds its buffers as data uris, save it as .glb", filename));
+            }
+            buffer::Source::Uri(uri) => std::fs::read(dir.join(uri)).map_err(|e| format!("couldn't read {}: {}", uri, e))?,
+        };
+        buffers.push(data);
+    }
+
+    let scene = gltf.default_scene().or_else(|| gltf.scenes().next()).ok_or(format!("{} has no scene", filename))?;
+    let mut groups = vec![];
+    for node in scene.nodes() {
+        load_node(&node, DMat4::identity(), &buffers, &mut groups);
+    }
+    Ok(groups)
+}
+
+fn load_node(node: &Node, parent: DMat4, buffers: &[Vec<u8>], groups: &mut Vec<MeshGroup>) {
+    let columns = node.transform().matrix().map(|c| DVec4::new(c[0] as f64, c[1] as f64, c[2] as f64, c[3] as f64));
+    let transform = parent * DMat4::new(columns[0], columns[1], columns[2], columns[3]);
+    if let Some(mesh) = node.mesh() {
+        for primitive in mesh.primitives().filter(|p| p.mode() == Mode::Triangles) {
+            let reader = primitive.reader(|b| buffers.get(b.index()).map(Vec::as_slice));
+            let Some(positions) = reader.read_positions() else {continue};
+            let mut data = MeshData::new(true);
+            for p in positions {
+                let p = transform.transform_point3(DVec3::new(p[0] as f64, p[1] as f64, p[2] as f64));
+                data.push_vertex(p, DVec3::unit_z(), None);
+            }
+
+            let indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => (0..data.vertex_count() as u32).collect(),
+            };
+            // mirroring transforms turn the winding inside out
+            let mirrored = transform.determinant() < 0.0;
+            data.indices = indices.chunks_exact(3)
+                .map(|t| if mirrored {[t[0], t[2], t[1]]} else {[t[0], t[1], t[2]]})
+                .filter(|t| t.iter().all(|&i| (i as usize) < data.vertex_count()))
+                .collect();
+            if data.indices.is_empty() {continue};
+
+            match reader.read_normals() {
+                Some(normals) => {
+                    let normal_transform: DMat3 = transform.truncate().inversed().transposed();
+                    data.normals = normals.map(|n| {
+                        let n = normal_transform * DVec3::new(n[0] as f64, n[1] as f64, n[2] as f64);
+                        let n = n.normalized();
+                        Vec3::new(n.x as f32, n.y as f32, n.z as f32)
+                    }).collect();
+                }
+                None => smooth_normals(&mut data),
+            }
+            // gltf puts the uv origin at the top left
+            if let Some(uvs) = reader.read_tex_coords(0) {
+                data.uvs = uvs.into_f32().map(|uv| Vec2::new(uv[0], 1.0 - uv[1])).collect();
+            }
+            if let Some(colors) = reader.read_colors(0) {
+                data.colors = colors.into_rgb_f32().map(Vec3::from).collect();
+            }
+            if data.normals.len() != data.vertex_count() {continue};
+            if data.uvs.len() != data.vertex_count() {data.uvs.clear()};
+            if data.colors.len() != data.vertex_count() {data.colors.clear()};
+            generate_tangents(&mut data);
+            groups.push(MeshGroup::new(data, material(&primitive.material())));
+        }
+    }
+    for child in node.children() {
+        load_node(&child, transform, buffers, groups);
+    }
+}
+
+fn material(mat: &gltf::Material) -> Principled {
+    let pbr = mat.pbr_metallic_roughness();
+    let [r, g, b, _] = pbr.base_color_factor();
+    let [er, eg, eb] = mat.emissive_factor();
+    let emissive = DVec3::new(er as f64, eg as f64, eb as f64) * mat.emissive_strength().unwrap_or(1.0) as f64;
+    let transmission = mat.transmission().map_or(0.0, |t| t.transmission_factor() as f64);
+    let ior = mat.ior().unwrap_or(1.5) as f64;
+    Principled{
+        two_sided: mat.double_sided(),
+        ..Principled::from_gltf(
+            DVec3::new(r as f64, g as f64, b as f64), pbr.metallic_factor() as f64, pbr.roughness_factor() as f64,
+            emissive, transmission, ior
+        )
+    }
+}
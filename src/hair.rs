@@ -0,0 +1,634 @@
+use std::f64::consts::{LN_2, PI};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use ultraviolet::{DVec2, DVec3};
+use crate::bvh::Bvh;
+use crate::color::luminance;
+use crate::lights::tangent_frame;
+use crate::materials::Material;
+use crate::principled::{Frame, fresnel_dielectric};
+use crate::raytracing::{BoundingBox, Hittable, Ray, RayHit};
+use serde::{Deserialize, Serialize};
+
+// ribbons always face the ray, cylinders bend their normal around the curve like a round fiber
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CurveType {
+    Flat,
+    Cylinder,
+}
+
+// cubic bezier, the width changes linearly from the first control point to the last
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Curve {
+    pub points: [DVec3; 4],
+    pub width: [f64; 2],
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + (b - a) * t
+}
+
+// de casteljau split at the middle
+fn split_bezier(cp: &[DVec3; 4]) -> ([DVec3; 4], [DVec3; 4]) {
+    let a = (cp[0] + cp[1]) * 0.5;
+    let b = (cp[1] + cp[2]) * 0.5;
+    let c = (cp[2] + cp[3]) * 0.5;
+    let d = (a + b) * 0.5;
+    let e = (b + c) * 0.5;
+    let f = (d + e) * 0.5;
+    ([cp[0], a, d, f], [f, e, c, cp[3]])
+}
+
+// point and derivative at u
+fn eval_bezier(cp: &[DVec3; 4], u: f64) -> (DVec3, DVec3) {
+    let a = [cp[0] + (cp[1] - cp[0]) * u, cp[1] + (cp[2] - cp[1]) * u, cp[2] + (cp[3] - cp[2]) * u];
+    let b = [a[0] + (a[1] - a[0]) * u, a[1] + (a[2] - a[1]) * u];
+    let d = b[1] - b[0];
+    // degenerate end segments have no derivative from the last step
+    let d = if d.mag_sq() > 0.0 {d * 3.0} else if u < 0.5 {cp[2] - cp[0]} else {cp[3] - cp[1]};
+    (b[0] + (b[1] - b[0]) * u, d)
+}
+
+impl Curve {
+    pub fn bounds(&self) -> BoundingBox {
+        let mut b = BoundingBox::empty();
+        for p in self.points {
+            b.grow(p);
+        }
+        let pad = DVec3::broadcast(self.width[0].max(self.width[1]) * 0.5);
+        BoundingBox{min: b.min - pad, max: b.max + pad}
+    }
+
+    // the curve in a space where the ray starts at the origin and runs along +z, split until the
+    // pieces are flat enough to be treated as line segments. returns t and the hit's u and v,
+    // v runs across the width
+    fn intersect(&self, r: &Ray, ray_tmax: f64) -> Option<(f64, f64, f64)> {
+        let speed = r.direction.mag();
+        let dir = r.direction / speed;
+        let (x, y) = tangent_frame(dir);
+        let cp = self.points.map(|p| {
+            let d = p - r.origin;
+            DVec3::new(d.dot(x), d.dot(y), d.dot(dir))
+        });
+
+        // enough splits that the pieces deviate from straight by a twentieth of the width
+        let mut l0: f64 = 0.0;
+        for i in 0..2 {
+            let d = (cp[i] - cp[i + 1] * 2.0 + cp[i + 2]).abs();
+            l0 = l0.max(d.component_max());
+        }
+        let eps = self.width[0].max(self.width[1]) * 0.05;
+        let depth = if l0 > 0.0 && eps > 0.0 {
+            ((2.0f64.sqrt() * 6.0 * l0 / (8.0 * eps)).log(4.0).round()).clamp(0.0, 10.0) as u32
+        } else {0};
+
+        let mut z_max = ray_tmax * speed;
+        let (z, u, v) = self.recursive_intersect(&cp, 0.0, 1.0, depth, &mut z_max)?;
+        Some((z / speed, u, v))
+    }
+
+    fn recursive_intersect(&self, cp: &[DVec3; 4], u0: f64, u1: f64, depth: u32, z_max: &mut f64) -> Option<(f64, f64, f64)> {
+        let max_width = lerp(u0, self.width[0], self.width[1]).max(lerp(u1, self.width[0], self.width[1]));
+        let mut b = BoundingBox::empty();
+        for p in cp {
+            b.grow(*p);
+        }
+        let pad = max_width * 0.5;
+        if b.min.x - pad > 0.0 || b.max.x + pad < 0.0 || b.min.y - pad > 0.0 || b.max.y + pad < 0.0 {return None};
+        if b.max.z + pad < 0.0 || b.min.z - pad > *z_max {return None};
+
+        if depth > 0 {
+            let (left, right) = split_bezier(cp);
+            let mid = (u0 + u1) * 0.5;
+            let near = self.recursive_intersect(&left, u0, mid, depth - 1, z_max);
+            let far = self.recursive_intersect(&right, mid, u1, depth - 1, z_max);
+            return far.or(near);
+        }
+
+        // the ray has to pass between the planes through the segment ends
+        let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+        if edge < 0.0 {return None};
+        let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+        if edge < 0.0 {return None};
+
+        let segment = DVec2::new(cp[3].x - cp[0].x, cp[3].y - cp[0].y);
+        let denom = segment.mag_sq();
+        if denom == 0.0 {return None};
+        let w = DVec2::new(-cp[0].x, -cp[0].y).dot(segment) / denom;
+        let u = lerp(w, u0, u1).clamp(u0, u1);
+        let hit_width = lerp(u, self.width[0], self.width[1]);
+        let (pc, dpcdw) = eval_bezier(cp, w.clamp(0.0, 1.0));
+        let dist_sq = pc.x * pc.x + pc.y * pc.y;
+        if dist_sq > hit_width * hit_width * 0.25 {return None};
+        if pc.z < 0.0 || pc.z > *z_max {return None};
+
+        // which side of the curve the ray passes on
+        let dist = dist_sq.sqrt();
+        let side = dpcdw.x * -pc.y + pc.x * dpcdw.y;
+        let v = if side > 0.0 {0.5 + dist / hit_width} else {0.5 - dist / hit_width};
+        *z_max = pc.z;
+        Some((pc.z, u, v))
+    }
+}
+
+// a set of curves sharing one material, like the strands of a hair cut
+#[derive(Serialize, Deserialize)]
+#[serde(from = "CurvesRecord")]
+pub struct Curves {
+    // kept in the order of the bvh leaves
+    pub curves: Vec<Curve>,
+    pub kind: CurveType,
+    pub mat: Box<dyn Material + Sync + Send>,
+    #[serde(skip_serializing)]
+    pub bvh: Bvh,
+    #[serde(skip_serializing)]
+    pub bounding_box: BoundingBox,
+}
+
+// saved curves, the hierarchy is rebuilt on load
+#[derive(Deserialize)]
+struct CurvesRecord {
+    curves: Vec<Curve>,
+    kind: CurveType,
+    mat: Box<dyn Material + Sync + Send>,
+}
+
+impl From<CurvesRecord> for Curves {
+    fn from(record: CurvesRecord) -> Curves {
+        Curves::new(record.curves, record.kind, record.mat)
+    }
+}
+
+impl Curves {
+    pub fn new(curves: Vec<Curve>, kind: CurveType, mat: Box<dyn Material + Sync + Send>) -> Curves {
+        let (bvh, order) = Bvh::build(curves.len(), |i| curves[i].bounds());
+        let curves: Vec<Curve> = order.iter().map(|&i| curves[i as usize].clone()).collect();
+        let bounding_box = curves.iter().fold(BoundingBox::empty(), |b, c| b.union(&c.bounds()));
+        Curves{curves, kind, mat, bvh, bounding_box}
+    }
+}
+
+#[typetag::serde]
+impl Hittable for Curves {
+    fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit> {
+        let mut closest: Option<(usize, f64, f64, f64)> = None;
+        self.bvh.traverse(r, ray_tmax, |start, count, max_dst| {
+            let mut found = None;
+            for i in start..start + count {
+                let max_dst = found.map_or(max_dst, |(_, t, _, _)| t);
+                if let Some((t, u, v)) = self.curves[i].intersect(r, max_dst) {
+                    if t > ray_tmin {found = Some((i, t, u, v))};
+                }
+            }
+            let (i, t, u, v) = found?;
+            closest = Some((i, t, u, v));
+            Some(t)
+        });
+        let (i, t, u, v) = closest?;
+
+        let curve = &self.curves[i];
+        let (_, dpdu) = eval_bezier(&curve.points, u);
+        let tangent = dpdu.normalized();
+        let width = lerp(u, curve.width[0], curve.width[1]);
+        // facing the ray, turned up to a right angle either way across a cylinder
+        let facing = (-r.direction - tangent * tangent.dot(-r.direction)).normalized();
+        let normal = match self.kind {
+            CurveType::Flat => facing,
+            CurveType::Cylinder => {
+                let theta = lerp(v, -PI / 2.0, PI / 2.0);
+                let side = tangent.cross(facing);
+                facing * theta.cos() - side * theta.sin()
+            }
+        };
+        let mut rec = RayHit{
+            hit_point: r.clone().at(t),
+            mat: self.mat.clone(),
+            normal,
+            geometric_normal: normal,
+            hit_time: t,
+            front: true,
+            uv: DVec2::new(u, v),
+            tangent,
+            color: DVec3::one(),
+            // large enough that rays leaving through the fiber start past its far side
+            error: DVec3::broadcast(width * 2.0)
+        };
+        rec.set_face_normal(r, normal);
+        Some(rec)
+    }
+
+    fn bounding_box_hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> bool {
+        self.bounding_box.hit(r, ray_tmin, ray_tmax).is_some()
+    }
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        Some(self.mat.as_ref())
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn read_f32(data: &[u8], at: usize) -> f64 {
+    f32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as f64
+}
+
+// strands of cem yuksel's .hair format as catmull-rom splines through the points, one bezier
+// per segment. thickness is the curve width, scaled by width_scale
+pub fn load_hair(filename: &str, width_scale: f64) -> Result<Vec<Curve>, String> {
+    let mut data = vec![];
+    BufReader::new(File::open(filename).map_err(|e| format!("couldn't open {}: {}", filename, e))?)
+        .read_to_end(&mut data).map_err(|e| format!("couldn't read {}: {}", filename, e))?;
+    parse_hair(&data, width_scale).map_err(|e| format!("{} {}", filename, e))
+}
+
+// load_hair on the bytes of the file
+pub fn parse_hair(data: &[u8], width_scale: f64) -> Result<Vec<Curve>, String> {
+    if data.len() < 128 || &data[0..4] != b"HAIR" {return Err("isn't a hair file".to_string())};
+
+    let strands = read_u32(data, 4) as usize;
+    let points = read_u32(data, 8) as usize;
+    let flags = read_u32(data, 12);
+    let default_segments = read_u32(data, 16) as usize;
+    let default_thickness = read_f32(data, 20);
+    let (has_segments, has_points, has_thickness, has_transparency, has_color) =
+        (flags & 1 != 0, flags & 2 != 0, flags & 4 != 0, flags & 8 != 0, flags & 16 != 0);
+    if !has_points {return Err("has no points".to_string())};
+
+    let size = 128 + if has_segments {strands * 2} else {0} + points * 12
+        + if has_thickness {points * 4} else {0} + if has_transparency {points * 4} else {0} + if has_color {points * 12} else {0};
+    if data.len() < size {return Err("is truncated".to_string())};
+
+    let mut at = 128;
+    let segments: Vec<usize> = (0..strands).map(|i| {
+        if has_segments {u16::from_le_bytes([data[at + i * 2], data[at + i * 2 + 1]]) as usize} else {default_segments}
+    }).collect();
+    if has_segments {at += strands * 2};
+    let positions: Vec<DVec3> = (0..points).map(|i| {
+        DVec3::new(read_f32(data, at + i * 12), read_f32(data, at + i * 12 + 4), read_f32(data, at + i * 12 + 8))
+    }).collect();
+    at += points * 12;
+    let thickness: Vec<f64> = (0..points).map(|i| if has_thickness {read_f32(data, at + i * 4)} else {default_thickness}).collect();
+
+    let mut curves = vec![];
+    let mut first = 0;
+    for count in segments {
+        let last = first + count;
+        if last >= points {break};
+        let p = |i: isize| positions[(first as isize + i).clamp(first as isize, last as isize) as usize];
+        for k in 0..count as isize {
+            let (p0, p1, p2, p3) = (p(k - 1), p(k), p(k + 1), p(k + 2));
+            curves.push(Curve{
+                points: [p1, p1 + (p2 - p0) / 6.0, p2 - (p3 - p1) / 6.0, p2],
+                width: [thickness[first + k as usize] * width_scale, thickness[first + k as usize + 1] * width_scale]
+            });
+        }
+        first = last + 1;
+    }
+    Ok(curves)
+}
+
+const SQRT_PI_OVER_8: f64 = 0.626657069;
+
+fn safe_sqrt(x: f64) -> f64 {
+    x.max(0.0).sqrt()
+}
+
+// modified bessel function of the first kind
+fn i0(x: f64) -> f64 {
+    let mut val = 0.0;
+    let mut x2i = 1.0;
+    let mut ifact = 1.0;
+    let mut i4 = 1.0;
+    for i in 0..10 {
+        if i > 1 {ifact *= i as f64};
+        val += x2i / (i4 * ifact * ifact);
+        x2i *= x * x;
+        i4 *= 4.0;
+    }
+    val
+}
+
+fn log_i0(x: f64) -> f64 {
+    if x > 12.0 {x + 0.5 * (-(2.0 * PI).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))} else {i0(x).ln()}
+}
+
+// longitudinal scattering
+fn mp(cos_theta_i: f64, cos_theta_o: f64, sin_theta_i: f64, sin_theta_o: f64, v: f64) -> f64 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        (log_i0(a) - b - 1.0 / v + LN_2 + (1.0 / (2.0 * v)).ln()).exp()
+    } else {
+        (-b).exp() * i0(a) / ((1.0 / v).sinh() * 2.0 * v)
+    }
+}
+
+fn logistic(x: f64, s: f64) -> f64 {
+    let x = x.abs();
+    (-x / s).exp() / (s * (1.0 + (-x / s).exp()).powi(2))
+}
+
+fn logistic_cdf(x: f64, s: f64) -> f64 {
+    1.0 / (1.0 + (-x / s).exp())
+}
+
+fn trimmed_logistic(x: f64, s: f64) -> f64 {
+    logistic(x, s) / (logistic_cdf(PI, s) - logistic_cdf(-PI, s))
+}
+
+fn sample_trimmed_logistic(u: f64, s: f64) -> f64 {
+    let k = logistic_cdf(PI, s) - logistic_cdf(-PI, s);
+    let x = -s * (1.0 / (u * k + logistic_cdf(-PI, s)) - 1.0).ln();
+    x.clamp(-PI, PI)
+}
+
+// azimuthal exit angle of lobe p
+fn phi(p: usize, gamma_o: f64, gamma_t: f64) -> f64 {
+    2.0 * p as f64 * gamma_t - 2.0 * gamma_o + p as f64 * PI
+}
+
+// azimuthal scattering
+fn np(dphi: f64, p: usize, s: f64, gamma_o: f64, gamma_t: f64) -> f64 {
+    let mut d = dphi - phi(p, gamma_o, gamma_t);
+    while d > PI {d -= 2.0 * PI};
+    while d < -PI {d += 2.0 * PI};
+    trimmed_logistic(d, s)
+}
+
+// hair fiber scattering after chiang et al. and d'eon et al.: reflection (R), transmission (TT),
+// one internal bounce (TRT) and everything after, each a product of a longitudinal lobe, an
+// azimuthal lobe and the attenuation of the path through the pigmented fiber
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Hair {
+    // absorption per unit of fiber diameter
+    pub sigma_a: DVec3,
+    // longitudinal and azimuthal roughness, 0 to 1
+    pub beta_m: f64,
+    pub beta_n: f64,
+    // tilt of the cuticle scales in degrees
+    pub alpha: f64,
+    pub eta: f64,
+}
+
+// everything that depends only on where the fiber was hit
+struct HairFrame {
+    frame: Frame,
+    h: f64,
+    gamma_o: f64,
+    v: [f64; 4],
+    s: f64,
+    sin_2k_alpha: [f64; 3],
+    cos_2k_alpha: [f64; 3],
+}
+
+impl Hair {
+    // pigment concentrations, roughly 0.3 blond, 1.3 brown and 8 black, pheomelanin reddens
+    pub fn from_melanin(eumelanin: f64, pheomelanin: f64) -> Hair {
+        let sigma_a = DVec3::new(0.419, 0.697, 1.37) * eumelanin + DVec3::new(0.187, 0.4, 1.05) * pheomelanin;
+        Hair{sigma_a, beta_m: 0.3, beta_n: 0.3, alpha: 2.0, eta: 1.55}
+    }
+
+    // absorption that gives roughly this color after many bounces
+    pub fn from_color(color: DVec3, beta_n: f64) -> Hair {
+        let b = beta_n;
+        let k = 5.969 - 0.215 * b + 2.532 * b.powi(2) - 10.73 * b.powi(3) + 5.574 * b.powi(4) + 0.245 * b.powi(5);
+        let sigma_a = color.map(|c| (c.max(1e-4).ln() / k).powi(2));
+        Hair{sigma_a, beta_m: 0.3, beta_n, alpha: 2.0, eta: 1.55}
+    }
+
+    fn setup(&self, rec: &RayHit) -> HairFrame {
+        let h = (-1.0 + 2.0 * rec.uv.y).clamp(-1.0, 1.0);
+        let v0 = (0.726 * self.beta_m + 0.812 * self.beta_m.powi(2) + 3.7 * self.beta_m.powi(20)).powi(2);
+        let s = SQRT_PI_OVER_8 * (0.265 * self.beta_n + 1.194 * self.beta_n.powi(2) + 5.372 * self.beta_n.powi(22));
+        let mut sin_2k_alpha = [self.alpha.to_radians().sin(), 0.0, 0.0];
+        let mut cos_2k_alpha = [safe_sqrt(1.0 - sin_2k_alpha[0] * sin_2k_alpha[0]), 0.0, 0.0];
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1].powi(2) - sin_2k_alpha[i - 1].powi(2);
+        }
+        HairFrame{
+            frame: Frame::with_tangent(rec.normal, rec.tangent),
+            h,
+            gamma_o: h.asin(),
+            v: [v0, 0.25 * v0, 4.0 * v0, 4.0 * v0],
+            s,
+            sin_2k_alpha,
+            cos_2k_alpha
+        }
+    }
+
+    // attenuation of each lobe, the last one sums all longer paths
+    fn ap(&self, r_in: &Ray, cos_theta_o: f64, h: f64, t: DVec3) -> [DVec3; 4] {
+        let cos_gamma_o = safe_sqrt(1.0 - h * h);
+        let f = fresnel_dielectric(cos_theta_o * cos_gamma_o, self.eta);
+        let t = r_in.reflectance(t);
+        let ap0 = DVec3::broadcast(f);
+        let ap1 = t * (1.0 - f).powi(2);
+        let ap2 = ap1 * t * f;
+        let ap3 = ap2 * t * f / (DVec3::one() - t * f);
+        [ap0, ap1, ap2, ap3]
+    }
+
+    // transmittance of one pass through the fiber and the refracted azimuth
+    fn transmittance(&self, sin_theta_o: f64, cos_theta_o: f64, h: f64) -> (DVec3, f64) {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        let sin_gamma_t = (h / etap).clamp(-1.0, 1.0);
+        let cos_gamma_t = safe_sqrt(1.0 - sin_gamma_t * sin_gamma_t);
+        let t = (-self.sigma_a * (2.0 * cos_gamma_t / cos_theta_t)).map(f64::exp);
+        (t, sin_gamma_t.asin())
+    }
+
+    // outgoing longitude turned by the scale tilt for lobe p
+    fn tilt(hf: &HairFrame, p: usize, sin_theta_o: f64, cos_theta_o: f64) -> (f64, f64) {
+        let (sin_a, cos_a, sign) = match p {
+            0 => (hf.sin_2k_alpha[1], hf.cos_2k_alpha[1], -1.0),
+            1 => (hf.sin_2k_alpha[0], hf.cos_2k_alpha[0], 1.0),
+            2 => (hf.sin_2k_alpha[2], hf.cos_2k_alpha[2], 1.0),
+            _ => return (sin_theta_o, cos_theta_o),
+        };
+        let sin_op = sin_theta_o * cos_a + sign * cos_theta_o * sin_a;
+        let cos_op = cos_theta_o * cos_a - sign * sin_theta_o * sin_a;
+        (sin_op, cos_op.abs())
+    }
+
+    // share of each lobe in the sampling
+    fn ap_pdf(&self, r_in: &Ray, hf: &HairFrame, cos_theta_o: f64) -> [f64; 4] {
+        let sin_theta_o = safe_sqrt(1.0 - cos_theta_o * cos_theta_o);
+        let (t, _) = self.transmittance(sin_theta_o, cos_theta_o, hf.h);
+        let ap = self.ap(r_in, cos_theta_o, hf.h, t);
+        let weights = ap.map(|a| if r_in.wavelengths.is_some() {a.x} else {luminance(a)});
+        let sum: f64 = weights.iter().sum();
+        if sum <= 0.0 {return [1.0, 0.0, 0.0, 0.0]};
+        weights.map(|w| w / sum)
+    }
+
+    // both directions in the fiber frame, x along the curve
+    fn f(&self, r_in: &Ray, hf: &HairFrame, wo: DVec3, wi: DVec3) -> DVec3 {
+        let (sin_theta_o, cos_theta_o) = (wo.x, safe_sqrt(1.0 - wo.x * wo.x));
+        let (sin_theta_i, cos_theta_i) = (wi.x, safe_sqrt(1.0 - wi.x * wi.x));
+        let dphi = wi.z.atan2(wi.y) - wo.z.atan2(wo.y);
+        let (t, gamma_t) = self.transmittance(sin_theta_o, cos_theta_o, hf.h);
+        let ap = self.ap(r_in, cos_theta_o, hf.h, t);
+
+        let mut sum = DVec3::zero();
+        for (p, ap) in ap.iter().enumerate().take(3) {
+            let (sin_op, cos_op) = Hair::tilt(hf, p, sin_theta_o, cos_theta_o);
+            sum += *ap * mp(cos_theta_i, cos_op, sin_theta_i, sin_op, hf.v[p]) * np(dphi, p, hf.s, hf.gamma_o, gamma_t);
+        }
+        sum += ap[3] * mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, hf.v[3]) / (2.0 * PI);
+        // the renderer multiplies by the cosine to the normal, which fibers don't have
+        if wi.z.abs() > 0.0 {sum / wi.z.abs()} else {sum}
+    }
+
+    fn pdf_local(&self, r_in: &Ray, hf: &HairFrame, wo: DVec3, wi: DVec3) -> f64 {
+        let (sin_theta_o, cos_theta_o) = (wo.x, safe_sqrt(1.0 - wo.x * wo.x));
+        let (sin_theta_i, cos_theta_i) = (wi.x, safe_sqrt(1.0 - wi.x * wi.x));
+        let dphi = wi.z.atan2(wi.y) - wo.z.atan2(wo.y);
+        let (_, gamma_t) = self.transmittance(sin_theta_o, cos_theta_o, hf.h);
+        let ap_pdf = self.ap_pdf(r_in, hf, cos_theta_o);
+
+        let mut pdf = 0.0;
+        for (p, weight) in ap_pdf.iter().enumerate().take(3) {
+            let (sin_op, cos_op) = Hair::tilt(hf, p, sin_theta_o, cos_theta_o);
+            pdf += weight * mp(cos_theta_i, cos_op, sin_theta_i, sin_op, hf.v[p]) * np(dphi, p, hf.s, hf.gamma_o, gamma_t);
+        }
+        pdf + ap_pdf[3] * mp(cos_theta_i, cos_theta_o, sin_theta_i, sin_theta_o, hf.v[3]) / (2.0 * PI)
+    }
+}
+
+#[typetag::serde]
+impl Material for Hair {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let hf = self.setup(rec);
+        let wo = hf.frame.to_local(-r_in.direction.normalized());
+        let (sin_theta_o, cos_theta_o) = (wo.x, safe_sqrt(1.0 - wo.x * wo.x));
+
+        // pick a lobe, then its longitude and azimuth
+        let ap_pdf = self.ap_pdf(r_in, &hf, cos_theta_o);
+        let mut u = fastrand::f64();
+        let mut p = 0;
+        while p < 3 && u >= ap_pdf[p] {
+            u -= ap_pdf[p];
+            p += 1;
+        }
+        let (sin_op, cos_op) = Hair::tilt(&hf, p, sin_theta_o, cos_theta_o);
+
+        let u0 = fastrand::f64().max(1e-5);
+        let v = hf.v[p];
+        let cos_theta = 1.0 + v * (u0 + (1.0 - u0) * (-2.0 / v).exp()).ln();
+        let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+        let cos_phi = (2.0 * PI * fastrand::f64()).cos();
+        let sin_theta_i = -cos_theta * sin_op + sin_theta * cos_phi * cos_op;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+
+        let (_, gamma_t) = self.transmittance(sin_theta_o, cos_theta_o, hf.h);
+        let dphi = if p < 3 {
+            phi(p, hf.gamma_o, gamma_t) + sample_trimmed_logistic(fastrand::f64(), hf.s)
+        } else {
+            2.0 * PI * fastrand::f64()
+        };
+        let phi_i = wo.z.atan2(wo.y) + dphi;
+        let wi = DVec3::new(sin_theta_i, cos_theta_i * phi_i.cos(), cos_theta_i * phi_i.sin());
+
+        let pdf = self.pdf_local(r_in, &hf, wo, wi);
+        if pdf <= 0.0 {return None};
+        let weight = self.f(r_in, &hf, wo, wi) * wi.z.abs() / pdf;
+        Some(r_in.scatter_from(rec, hf.frame.to_world(wi), r_in.color * weight))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let hf = self.setup(rec);
+        let wo = hf.frame.to_local(-r_in.direction.normalized());
+        self.f(r_in, &hf, wo, hf.frame.to_local(wi.normalized()))
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        let hf = self.setup(rec);
+        let wo = hf.frame.to_local(-r_in.direction.normalized());
+        self.pdf_local(r_in, &hf, wo, hf.frame.to_local(wi.normalized()))
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self) -> DVec3 {
+        (-self.sigma_a).map(f64::exp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // header for strands with points in them, flags as in the format
+    fn header(strands: u32, points: u32, flags: u32, segments: u32, thickness: f32) -> Vec<u8> {
+        let mut data = b"HAIR".to_vec();
+        for v in [strands, points, flags, segments] {
+            data.extend(v.to_le_bytes());
+        }
+        data.extend(thickness.to_le_bytes());
+        data.resize(128, 0);
+        data
+    }
+
+    fn push_points(data: &mut Vec<u8>, points: &[[f32; 3]]) {
+        for p in points.iter().flatten() {
+            data.extend(p.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn strands_with_their_own_segments_and_thickness() {
+        let mut data = header(2, 5, 1 | 2 | 4, 0, 0.0);
+        for s in [1u16, 2] {
+            data.extend(s.to_le_bytes());
+        }
+        let points = [[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [2.0, 0.0, 0.0], [2.0, 1.0, 0.0], [2.0, 2.0, 1.0]];
+        push_points(&mut data, &points);
+        for t in [0.1f32, 0.2, 0.3, 0.4, 0.5] {
+            data.extend(t.to_le_bytes());
+        }
+        let curves = parse_hair(&data, 2.0).unwrap();
+        assert_eq!(curves.len(), 3);
+        // every segment runs between two consecutive points of its strand
+        let ends = [(0, 1), (2, 3), (3, 4)];
+        for (curve, (a, b)) in curves.iter().zip(ends) {
+            let p = |i: usize| DVec3::new(points[i][0] as f64, points[i][1] as f64, points[i][2] as f64);
+            assert!((curve.points[0] - p(a)).mag() < 1e-6 && (curve.points[3] - p(b)).mag() < 1e-6);
+            assert!((curve.width[0] - 0.2 * (a + 1) as f64).abs() < 1e-6 && (curve.width[1] - 0.2 * (b + 1) as f64).abs() < 1e-6);
+        }
+        // a single segment strand is a straight line
+        let c = &curves[0];
+        assert!((c.points[1] - DVec3::new(0.0, 1.0 / 6.0, 0.0)).mag() < 1e-6 && (c.points[2] - DVec3::new(0.0, 5.0 / 6.0, 0.0)).mag() < 1e-6);
+    }
+
+    #[test]
+    fn strands_with_the_default_segments_and_thickness() {
+        // colors and transparency are skipped over
+        let mut data = header(2, 4, 2 | 8 | 16, 1, 0.05);
+        push_points(&mut data, &[[0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]]);
+        data.extend([0u8; 4 * 4 + 4 * 12]);
+        let curves = parse_hair(&data, 1.0).unwrap();
+        assert_eq!(curves.len(), 2);
+        assert!(curves.iter().all(|c| c.width == [0.05f32 as f64; 2]));
+        assert!((curves[1].points[0] - DVec3::new(1.0, 0.0, 0.0)).mag() < 1e-6);
+    }
+
+    #[test]
+    fn broken_files_are_rejected() {
+        assert!(parse_hair(b"HAIR", 1.0).is_err());
+        let mut data = header(1, 2, 2, 1, 0.1);
+        data[0] = b'X';
+        assert!(parse_hair(&data, 1.0).is_err());
+        // no points
+        assert!(parse_hair(&header(1, 2, 1, 1, 0.1), 1.0).is_err());
+        let mut data = header(1, 2, 2, 1, 0.1);
+        push_points(&mut data, &[[0.0, 0.0, 0.0]]);
+        assert!(parse_hair(&data, 1.0).is_err());
+    }
+}
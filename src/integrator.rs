@@ -0,0 +1,312 @@
+use ultraviolet::DVec3;
+use crate::camera::Camera;
+use crate::raytracing::{HittableList, Ray, direct_light, emitters, environment_light, get_world_hit, intersection_tests, punctual_light, punctual_lights, unit_samp};
+use serde::{Deserialize, Serialize};
+
+// ambient occlusion rays further than this count as unoccluded
+const AO_DISTANCE: f64 = 1.0;
+// distance at which the depth view has faded to 1/e
+const DEPTH_SCALE: f64 = 10.0;
+// bounces before russian roulette starts
+const ROULETTE_DEPTH: i32 = 3;
+// paths this long or longer get the full sample count
+const ADAPTIVE_DEPTH: i32 = 8;
+// intersection tests that saturate the cost heat map
+const MAX_COST: f64 = 4096.0;
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum IntegratorKind {
+    PathTracer,
+    Bidirectional,
+    PhotonMapping,
+    Whitted,
+    Normals,
+    Albedo,
+    Depth,
+    AmbientOcclusion,
+    Bounces,
+    Cost,
+}
+
+impl IntegratorKind {
+    pub const ALL: [IntegratorKind; 10] = [
+        IntegratorKind::PathTracer, IntegratorKind::Bidirectional, IntegratorKind::PhotonMapping,
+        IntegratorKind::Whitted, IntegratorKind::Normals, IntegratorKind::Albedo, IntegratorKind::Depth,
+        IntegratorKind::AmbientOcclusion, IntegratorKind::Bounces, IntegratorKind::Cost,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntegratorKind::PathTracer => "Path tracer",
+            IntegratorKind::Bidirectional => "Bidirectional",
+            IntegratorKind::PhotonMapping => "Photon mapping",
+            IntegratorKind::Whitted => "Whitted (direct)",
+            IntegratorKind::Normals => "Normals",
+            IntegratorKind::Albedo => "Albedo",
+            IntegratorKind::Depth => "Depth",
+            IntegratorKind::AmbientOcclusion => "Ambient occlusion",
+            IntegratorKind::Bounces => "Bounce heat map",
+            IntegratorKind::Cost => "Cost heat map",
+        }
+    }
+
+    // the integrators that work one camera ray at a time, bidirectional and photon mapping render whole passes
+    pub fn build(&self, camera: &Camera, world: &HittableList) -> Option<Box<dyn Integrator + Sync + Send>> {
+        let max_depth = camera.max_depth;
+        Some(match self {
+            IntegratorKind::PathTracer => Box::new(PathTracer{max_depth, punctual: punctual_lights(world)}),
+            IntegratorKind::Whitted => Box::new(Whitted{max_depth, lights: emitters(world), punctual: punctual_lights(world)}),
+            IntegratorKind::Normals => Box::new(Normals{}),
+            IntegratorKind::Albedo => Box::new(Albedo{}),
+            IntegratorKind::Depth => Box::new(Depth{}),
+            IntegratorKind::AmbientOcclusion => Box::new(AmbientOcclusion{}),
+            IntegratorKind::Bounces => Box::new(Bounces{max_depth}),
+            IntegratorKind::Cost => Box::new(Cost{}),
+            IntegratorKind::Bidirectional | IntegratorKind::PhotonMapping => return None,
+        })
+    }
+}
+
+pub trait Integrator {
+    // radiance arriving along r, one value per wavelength when r carries wavelengths
+    fn li(&self, r: Ray, world: &HittableList) -> DVec3;
+
+    // how many samples a pixel gets, given its first camera ray
+    fn sample_count(&self, _r: Ray, _world: &HittableList, samples: i32) -> i32 {
+        samples
+    }
+
+    // debug views output rgb directly and are never traced spectrally
+    fn is_spectral(&self) -> bool {
+        false
+    }
+}
+
+// blue through green to red for t in [0, 1]
+fn heat_map(t: f64) -> DVec3 {
+    let t = t.clamp(0.0, 1.0);
+    DVec3::new((2.0 * t - 1.0).max(0.0), 1.0 - (2.0 * t - 1.0).abs(), (1.0 - 2.0 * t).max(0.0))
+}
+
+// russian roulette, chance that a path with throughput color carries on after bounce depth. dim paths
+// are killed and the survivors weighted up by its inverse, so the estimate stays unbiased
+fn survival_probability(color: DVec3, depth: i32) -> f64 {
+    let max = color.component_max();
+    if depth < ROULETTE_DEPTH || max >= 1.0 {return 1.0};
+    1.0 - (1.0 - max).max(0.05)
+}
+
+pub struct PathTracer {
+    pub max_depth: i32,
+    pub punctual: Vec<usize>,
+}
+
+impl PathTracer {
+    // bounces until the path escapes, hits a light or is absorbed, up to limit
+    fn path_length(&self, mut ray: Ray, world: &HittableList, limit: i32) -> i32 {
+        let mut depth = 0;
+        while depth < limit {
+            depth += 1;
+            let Some(rec) = get_world_hit(&ray, 0.0, f64::INFINITY, world) else {break};
+            let Some(scattered) = rec.mat.scatter(&ray, &rec) else {break};
+            if scattered.emissive {break};
+            ray = scattered;
+        }
+        depth
+    }
+}
+
+impl Integrator for PathTracer {
+    // ray.color is the path throughput, scattered rays already carry the product
+    fn li(&self, mut ray: Ray, world: &HittableList) -> DVec3 {
+        let mut l = DVec3::zero();
+        for depth in 0..self.max_depth {
+            let Some(rec) = get_world_hit(&ray, 0.0, f64::INFINITY, world) else {
+                return l + ray.color * environment_light(ray);
+            };
+            // punctual lights can't be hit, so they are sampled at every bounce
+            l += ray.color * punctual_light(world, &self.punctual, &ray, &rec);
+            // glowing surfaces that aren't lights, e.g. principled emission
+            if !rec.mat.is_emissive() {
+                l += ray.color * rec.mat.emitted(&ray, &rec);
+            }
+            let Some(scattered) = rec.mat.scatter(&ray, &rec) else {return l};
+            if scattered.emissive {return l + ray.color * scattered.color};
+            ray = scattered;
+
+            let survival = survival_probability(ray.color, depth);
+            if fastrand::f64() >= survival {return l};
+            ray.color /= survival;
+        }
+        l
+    }
+
+    // pixels whose paths end within a few bounces get fewer samples
+    fn sample_count(&self, r: Ray, world: &HittableList, samples: i32) -> i32 {
+        let limit = self.max_depth.min(ADAPTIVE_DEPTH);
+        let depth = self.path_length(r, world, limit) as f64;
+        (samples as f64 * (depth / limit as f64)).round().max(1.0) as i32
+    }
+
+    fn is_spectral(&self) -> bool {
+        true
+    }
+}
+
+// direct light from the emitters only, mirrors and glass are followed
+pub struct Whitted {
+    pub max_depth: i32,
+    pub lights: Vec<usize>,
+    pub punctual: Vec<usize>,
+}
+
+impl Integrator for Whitted {
+    fn li(&self, mut r: Ray, world: &HittableList) -> DVec3 {
+        for _ in 0..self.max_depth {
+            let Some(rec) = get_world_hit(&r, 0.0, f64::INFINITY, world) else {
+                return r.color * environment_light(r);
+            };
+            if rec.mat.is_emissive() {return r.color * rec.mat.emitted(&r, &rec)};
+            if !rec.mat.is_delta() {
+                return r.color * (direct_light(world, &self.lights, &r, &rec) + punctual_light(world, &self.punctual, &r, &rec));
+            }
+            let Some(scattered) = rec.mat.scatter(&r, &rec) else {break};
+            r = scattered;
+        }
+        DVec3::zero()
+    }
+
+    fn is_spectral(&self) -> bool {
+        true
+    }
+}
+
+pub struct Normals {}
+
+impl Integrator for Normals {
+    fn li(&self, r: Ray, world: &HittableList) -> DVec3 {
+        match get_world_hit(&r, 0.0, f64::INFINITY, world) {
+            Some(rec) => 0.5 * (rec.normal + DVec3::one()),
+            None => DVec3::zero()
+        }
+    }
+}
+
+pub struct Albedo {}
+
+impl Integrator for Albedo {
+    fn li(&self, r: Ray, world: &HittableList) -> DVec3 {
+        match get_world_hit(&r, 0.0, f64::INFINITY, world) {
+            Some(rec) => rec.mat.albedo(),
+            None => DVec3::zero()
+        }
+    }
+}
+
+// distance to the first hit, white up close fading to black
+pub struct Depth {}
+
+impl Integrator for Depth {
+    fn li(&self, r: Ray, world: &HittableList) -> DVec3 {
+        match get_world_hit(&r, 0.0, f64::INFINITY, world) {
+            Some(rec) => DVec3::one() * (-rec.hit_time * r.direction.mag() / DEPTH_SCALE).exp(),
+            None => DVec3::zero()
+        }
+    }
+}
+
+// one cosine distributed occlusion ray per sample
+pub struct AmbientOcclusion {}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, r: Ray, world: &HittableList) -> DVec3 {
+        let Some(rec) = get_world_hit(&r, 0.0, f64::INFINITY, world) else {return DVec3::one()};
+        let dir = (rec.normal + unit_samp()).normalized();
+        let occlusion = Ray::new(rec.spawn_point(dir), dir, DVec3::one());
+        if get_world_hit(&occlusion, 0.0, AO_DISTANCE, world).is_some() {DVec3::zero()} else {DVec3::one()}
+    }
+}
+
+// how many times the path scattered before escaping, hitting a light or being absorbed
+pub struct Bounces {
+    pub max_depth: i32,
+}
+
+impl Integrator for Bounces {
+    fn li(&self, mut r: Ray, world: &HittableList) -> DVec3 {
+        let mut bounces = 0;
+        while bounces < self.max_depth {
+            let Some(rec) = get_world_hit(&r, 0.0, f64::INFINITY, world) else {break};
+            if rec.mat.is_emissive() {break};
+            let Some(scattered) = rec.mat.scatter(&r, &rec) else {break};
+            r = scattered;
+            bounces += 1;
+        }
+        heat_map(bounces as f64 / self.max_depth.max(1) as f64)
+    }
+}
+
+// bounding box and primitive tests spent on the camera ray, on a log scale
+pub struct Cost {}
+
+impl Integrator for Cost {
+    fn li(&self, r: Ray, world: &HittableList) -> DVec3 {
+        let before = intersection_tests();
+        get_world_hit(&r, 0.0, f64::INFINITY, world);
+        let tests = (intersection_tests() - before) as f64;
+        heat_map((1.0 + tests).ln() / (1.0 + MAX_COST).ln())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+    use crate::lights::PointLight;
+    use crate::materials::Lambertian;
+    use crate::raytracing::Sphere;
+
+    // mean of n path tracer samples along rays from origin, in directions picked by dir
+    fn mean_li(world: &HittableList, origin: DVec3, dir: impl Fn() -> DVec3, n: usize) -> DVec3 {
+        let pt = PathTracer{max_depth: 100, punctual: punctual_lights(world)};
+        let mut sum = DVec3::zero();
+        for _ in 0..n {
+            sum += pt.li(Ray::new(origin, dir(), DVec3::one()), world);
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn sky_lit_ball_matches_the_closed_form() {
+        // the sky is a + b y, a lambertian ball's top sees a pi + b 2 pi / 3 of irradiance
+        let (bottom, top) = (DVec3::new(0.68, 0.98, 1.0), DVec3::new(0.5, 0.66, 1.0));
+        let (a, b) = ((bottom + top) / 2.0, (top - bottom) / 2.0);
+        let world: HittableList = vec![Box::new(Sphere{center: DVec3::zero(), radius: 1.0, mat: Box::new(Lambertian{albedo: DVec3::broadcast(0.5)})})];
+        let l = mean_li(&world, DVec3::new(0.0, 5.0, 0.0), || -DVec3::unit_y(), 20000);
+        let expected = 0.5 * (a + b * (2.0 / 3.0));
+        assert!((l - expected).abs().component_max() < 5e-3, "{:?} {:?}", l, expected);
+    }
+
+    #[test]
+    fn roulette_only_thins_out_dim_paths() {
+        assert_eq!(survival_probability(DVec3::broadcast(0.1), ROULETTE_DEPTH - 1), 1.0);
+        assert_eq!(survival_probability(DVec3::new(0.2, 1.5, 0.1), ROULETTE_DEPTH), 1.0);
+        assert!((survival_probability(DVec3::new(0.3, 0.1, 0.2), ROULETTE_DEPTH) - 0.3).abs() < 1e-12);
+        // nearly white paths still get killed now and then
+        assert!((survival_probability(DVec3::broadcast(0.99), ROULETTE_DEPTH + 5) - 0.95).abs() < 1e-12);
+    }
+
+    #[test]
+    fn point_light_in_a_diffuse_sphere_matches_the_furnace() {
+        // every point of the sphere gets I / r^2 straight from the center and reflects albedo of what
+        // arrives, so the radiance is albedo / (1 - albedo) I / (pi r^2) after all bounces
+        let (radius, intensity, albedo) = (2.0, 3.0, 0.5);
+        let world: HittableList = vec![
+            Box::new(Sphere{center: DVec3::zero(), radius, mat: Box::new(Lambertian{albedo: DVec3::broadcast(albedo)})}),
+            Box::new(PointLight{position: DVec3::zero(), radius: 0.0, color: DVec3::one(), intensity}),
+        ];
+        let l = mean_li(&world, DVec3::new(0.3, -0.2, 0.1), unit_samp, 100000);
+        let expected = albedo / (1.0 - albedo) * intensity / (PI * radius * radius);
+        assert!((l - DVec3::broadcast(expected)).abs().component_max() < 0.01 * expected, "{:?} {}", l, expected);
+    }
+}
@@ -0,0 +1,444 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+use ultraviolet::DVec3;
+use crate::color::luminance;
+use crate::materials::Material;
+use crate::raytracing::{Hittable, Ray, RayHit, unit_samp};
+use serde::{Deserialize, Serialize};
+
+// render radiance is in W/(sr m^2) at 555nm, so one unit is 683 nits
+pub const LUMINOUS_EFFICACY: f64 = 683.0;
+
+// how bright a light is, converted to radiance using its area and emission profile
+#[derive(Clone, Copy)]
+pub enum Intensity {
+    // peak luminance of the surface in cd/m^2
+    Nits(f64),
+    // total radiant power
+    Watts(f64),
+    // total luminous power
+    Lumens(f64),
+}
+
+// orthonormal tangents for a unit normal
+pub fn tangent_frame(n: DVec3) -> (DVec3, DVec3) {
+    let a = if n.x.abs() > 0.9 {DVec3::unit_y()} else {DVec3::unit_x()};
+    let t = a.cross(n).normalized();
+    (t, n.cross(t))
+}
+
+fn smoothstep(lo: f64, hi: f64, x: f64) -> f64 {
+    if lo >= hi {return if x >= hi {1.0} else {0.0}};
+    let t = ((x - lo) / (hi - lo)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// cone falloff, angles in degrees from the axis
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Spot {
+    pub inner: f64,
+    pub outer: f64,
+}
+
+impl Spot {
+    pub fn falloff(&self, cos: f64) -> f64 {
+        smoothstep(self.outer.to_radians().cos(), self.inner.to_radians().cos(), cos)
+    }
+}
+
+// IESNA LM-63 photometric profile, normalized so the brightest direction is 1
+#[derive(Serialize, Deserialize)]
+pub struct IesProfile {
+    // degrees, vertical 0 points along the emitting normal
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    // horizontal major, candela / max candela
+    candela: Vec<f64>,
+}
+
+fn lerp_index(angles: &[f64], x: f64) -> (usize, usize, f64) {
+    if angles.len() == 1 || x <= angles[0] {return (0, 0, 0.0)};
+    let i = angles.partition_point(|a| *a <= x);
+    if i >= angles.len() {return (angles.len() - 1, angles.len() - 1, 0.0)};
+    let (a, b) = (angles[i - 1], angles[i]);
+    (i - 1, i, if b > a {(x - a) / (b - a)} else {0.0})
+}
+
+impl IesProfile {
+    pub fn load(filename: &str) -> Result<IesProfile, String> {
+        let text = std::fs::read_to_string(filename).map_err(|e| format!("couldn't read {}: {}", filename, e))?;
+        IesProfile::parse(&text).map_err(|e| format!("couldn't load {}: {}", filename, e))
+    }
+
+    pub fn parse(text: &str) -> Result<IesProfile, String> {
+        let tilt = text.find("TILT=").ok_or("missing TILT line")?;
+        let mut lines = text[tilt..].lines();
+        let tilt_line = lines.next().unwrap_or_default();
+        let mut numbers = lines.flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ',')).filter(|s| !s.is_empty())
+            .map(|s| s.parse::<f64>().map_err(|_| format!("bad number {}", s)));
+        let mut next = || numbers.next().unwrap_or(Err("unexpected end of file".to_string()));
+        if tilt_line.trim() == "TILT=INCLUDE" {
+            // lamp to luminaire geometry, then tilt angles and factors
+            next()?;
+            let count = next()? as usize;
+            for _ in 0..2 * count {
+                next()?;
+            }
+        }
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_count = next()? as usize;
+        let horizontal_count = next()? as usize;
+        // photometric type, units, width, length, height, ballast factor, future use, input watts
+        for _ in 0..8 {
+            next()?;
+        }
+        let vertical = (0..vertical_count).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+        let horizontal = (0..horizontal_count).map(|_| next()).collect::<Result<Vec<_>, _>>()?;
+        let candela = (0..vertical_count * horizontal_count).map(|_| next().map(|c| c * multiplier)).collect::<Result<Vec<_>, _>>()?;
+        if vertical.is_empty() || horizontal.is_empty() {return Err("no angles".to_string())};
+        let max = candela.iter().cloned().fold(0.0, f64::max);
+        if max <= 0.0 {return Err("no candela values".to_string())};
+        Ok(IesProfile{vertical, horizontal, candela: candela.iter().map(|c| c / max).collect()})
+    }
+
+    // relative intensity, angles in degrees
+    pub fn value(&self, vertical: f64, horizontal: f64) -> f64 {
+        // fold the horizontal angle into the range the file covers, matching its symmetry
+        let last = *self.horizontal.last().unwrap();
+        let h = horizontal.rem_euclid(360.0);
+        let h = if last <= 0.0 {0.0}
+            else if last <= 90.0 {let h = h % 180.0; if h > 90.0 {180.0 - h} else {h}}
+            else if last <= 180.0 {if h > 180.0 {360.0 - h} else {h}}
+            else {h};
+        let (v0, v1, fv) = lerp_index(&self.vertical, vertical);
+        let (h0, h1, fh) = lerp_index(&self.horizontal, h);
+        let at = |hi: usize, vi: usize| self.candela[hi * self.vertical.len() + vi];
+        let a = at(h0, v0) * (1.0 - fv) + at(h0, v1) * fv;
+        let b = at(h1, v0) * (1.0 - fv) + at(h1, v1) * fv;
+        a * (1.0 - fh) + b * fh
+    }
+}
+
+// emissive material for any surface, radiance depends on the side and the angle from the normal
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AreaLight {
+    // unit luminance
+    pub color: DVec3,
+    // peak radiance
+    pub radiance: f64,
+    pub two_sided: bool,
+    pub spot: Option<Spot>,
+    pub ies: Option<Arc<IesProfile>>,
+}
+
+impl AreaLight {
+    // area is the total surface area of the shape, unused for Nits
+    pub fn new(color: DVec3, intensity: Intensity, area: f64, two_sided: bool, spot: Option<Spot>, ies: Option<Arc<IesProfile>>) -> AreaLight {
+        let mut light = AreaLight{color: color / luminance(color).max(1e-6), radiance: 0.0, two_sided, spot, ies};
+        let sides = if two_sided {2.0} else {1.0};
+        let flux_per_radiance = || area * sides * light.projected_profile();
+        light.radiance = match intensity {
+            Intensity::Nits(nits) => nits / LUMINOUS_EFFICACY,
+            Intensity::Watts(watts) => watts / flux_per_radiance(),
+            Intensity::Lumens(lumens) => lumens / LUMINOUS_EFFICACY / flux_per_radiance(),
+        };
+        light
+    }
+
+    // falloff of the spot cone and ies profile, cos and the tangent frame are relative to the emitting side
+    fn profile(&self, cos: f64, dir: DVec3, n: DVec3) -> f64 {
+        let mut f = 1.0;
+        if let Some(spot) = &self.spot {
+            f *= spot.falloff(cos);
+        }
+        if let Some(ies) = &self.ies {
+            let (t, b) = tangent_frame(n);
+            let horizontal = dir.dot(b).atan2(dir.dot(t)).to_degrees();
+            f *= ies.value(cos.clamp(-1.0, 1.0).acos().to_degrees(), horizontal);
+        }
+        f
+    }
+
+    // cosine weighted integral of the profile over the hemisphere, pi for a plain diffuse emitter
+    fn projected_profile(&self) -> f64 {
+        if self.spot.is_none() && self.ies.is_none() {return PI};
+        let (steps_theta, steps_phi) = (128, 64);
+        let n = DVec3::unit_z();
+        let mut sum = 0.0;
+        for i in 0..steps_theta {
+            let theta = (i as f64 + 0.5) / steps_theta as f64 * PI / 2.0;
+            let (sin, cos) = theta.sin_cos();
+            for j in 0..steps_phi {
+                let phi = (j as f64 + 0.5) / steps_phi as f64 * 2.0 * PI;
+                let dir = DVec3::new(sin * phi.cos(), sin * phi.sin(), cos);
+                sum += self.profile(cos, dir, n) * cos * sin;
+            }
+        }
+        sum * (PI / 2.0 / steps_theta as f64) * (2.0 * PI / steps_phi as f64)
+    }
+}
+
+#[typetag::serde]
+impl Material for AreaLight {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let mut scattered = r_in.scatter_from(rec, rec.normal, self.emitted(r_in, rec));
+        scattered.emissive = true;
+        Some(scattered)
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn emits_two_sided(&self) -> bool {
+        self.two_sided
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.color
+    }
+
+    fn emitted(&self, r_in: &Ray, rec: &RayHit) -> DVec3 {
+        if !rec.front && !self.two_sided {return DVec3::zero()};
+        let dir = -r_in.direction.normalized();
+        let f = self.profile(dir.dot(rec.normal), dir, rec.normal);
+        if f <= 0.0 {return DVec3::zero()};
+        r_in.illuminant(self.color) * self.radiance * f
+    }
+}
+
+// lights without geometry, rays can't hit them so they are only found by sampling
+pub trait PunctualLight {
+    // picks a point on the light as seen from p. returns the unit direction to it, its distance
+    // and the radiance arriving at p divided by the sampling density
+    fn sample_li(&self, p: DVec3, r_in: &Ray) -> Option<(DVec3, f64, DVec3)>;
+}
+
+// radiant intensity in W/sr of a light spreading power over solid_angle, nits are taken as the
+// luminance of a sphere of the given radius
+fn radiant_intensity(intensity: Intensity, solid_angle: f64, radius: f64) -> f64 {
+    match intensity {
+        Intensity::Nits(nits) => nits / LUMINOUS_EFFICACY * PI * radius * radius,
+        Intensity::Watts(watts) => watts / solid_angle,
+        Intensity::Lumens(lumens) => lumens / LUMINOUS_EFFICACY / solid_angle,
+    }
+}
+
+// a point on a sphere of the given radius around center, for soft shadows
+fn sample_sphere(center: DVec3, radius: f64) -> DVec3 {
+    if radius <= 0.0 {return center};
+    center + unit_samp() * radius
+}
+
+// isotropic point light, a radius above zero gives soft shadows
+#[derive(Serialize, Deserialize)]
+pub struct PointLight {
+    pub position: DVec3,
+    pub radius: f64,
+    // unit luminance
+    pub color: DVec3,
+    // W/sr
+    pub intensity: f64,
+}
+
+impl PointLight {
+    pub fn new(position: DVec3, radius: f64, color: DVec3, intensity: Intensity) -> PointLight {
+        let color = color / luminance(color).max(1e-6);
+        PointLight{position, radius, color, intensity: radiant_intensity(intensity, 4.0 * PI, radius)}
+    }
+}
+
+impl PunctualLight for PointLight {
+    fn sample_li(&self, p: DVec3, r_in: &Ray) -> Option<(DVec3, f64, DVec3)> {
+        let d = sample_sphere(self.position, self.radius) - p;
+        let dist = d.mag();
+        if dist <= 0.0 {return None};
+        Some((d / dist, dist, r_in.illuminant(self.color) * self.intensity / (dist * dist)))
+    }
+}
+
+// point light limited to a cone around direction, full intensity inside inner and none outside outer
+#[derive(Serialize, Deserialize)]
+pub struct SpotLight {
+    pub position: DVec3,
+    pub direction: DVec3,
+    pub radius: f64,
+    pub cone: Spot,
+    // unit luminance
+    pub color: DVec3,
+    // W/sr along the axis
+    pub intensity: f64,
+}
+
+impl SpotLight {
+    pub fn new(position: DVec3, direction: DVec3, radius: f64, cone: Spot, color: DVec3, intensity: Intensity) -> SpotLight {
+        let color = color / luminance(color).max(1e-6);
+        // the smoothstep falloff covers exactly half of the band between the cones, as in pbrt
+        let (cos_inner, cos_outer) = (cone.inner.to_radians().cos(), cone.outer.to_radians().cos());
+        let intensity = radiant_intensity(intensity, 2.0 * PI * (1.0 - (cos_inner + cos_outer) / 2.0), radius);
+        SpotLight{position, direction: direction.normalized(), radius, cone, color, intensity}
+    }
+}
+
+impl PunctualLight for SpotLight {
+    fn sample_li(&self, p: DVec3, r_in: &Ray) -> Option<(DVec3, f64, DVec3)> {
+        let d = sample_sphere(self.position, self.radius) - p;
+        let dist = d.mag();
+        if dist <= 0.0 {return None};
+        let wi = d / dist;
+        let falloff = self.cone.falloff(self.direction.dot(-wi));
+        if falloff <= 0.0 {return None};
+        Some((wi, dist, r_in.illuminant(self.color) * self.intensity * falloff / (dist * dist)))
+    }
+}
+
+// distant light like the sun, the angular diameter in degrees softens its shadows
+#[derive(Serialize, Deserialize)]
+pub struct DirectionalLight {
+    // towards the light
+    pub direction: DVec3,
+    pub angular_diameter: f64,
+    // unit luminance
+    pub color: DVec3,
+    // W/m^2 on a surface facing the light
+    pub irradiance: f64,
+}
+
+impl DirectionalLight {
+    // watts and lumens are per square metre, nits are the luminance of the disc
+    pub fn new(direction: DVec3, angular_diameter: f64, color: DVec3, intensity: Intensity) -> DirectionalLight {
+        let color = color / luminance(color).max(1e-6);
+        let half = (angular_diameter / 2.0).to_radians();
+        let irradiance = match intensity {
+            Intensity::Nits(nits) => nits / LUMINOUS_EFFICACY * 2.0 * PI * (1.0 - half.cos()),
+            Intensity::Watts(watts) => watts,
+            Intensity::Lumens(lux) => lux / LUMINOUS_EFFICACY,
+        };
+        DirectionalLight{direction: direction.normalized(), angular_diameter, color, irradiance}
+    }
+}
+
+impl PunctualLight for DirectionalLight {
+    fn sample_li(&self, _p: DVec3, r_in: &Ray) -> Option<(DVec3, f64, DVec3)> {
+        // uniform direction inside the cone the disc covers
+        let cos_max = (self.angular_diameter / 2.0).to_radians().cos();
+        let cos = 1.0 - fastrand::f64() * (1.0 - cos_max);
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * fastrand::f64();
+        let (t, b) = tangent_frame(self.direction);
+        let wi = (self.direction * cos + t * (sin * phi.cos()) + b * (sin * phi.sin())).normalized();
+        Some((wi, f64::INFINITY, r_in.illuminant(self.color) * self.irradiance))
+    }
+}
+
+#[typetag::serde]
+impl Hittable for PointLight {
+    fn hit(&self, _r: &Ray, _ray_tmin: f64, _ray_tmax: f64) -> Option<RayHit> {
+        None
+    }
+
+    fn bounding_box_hit(&self, _r: &Ray, _ray_tmin: f64, _ray_tmax: f64) -> bool {
+        false
+    }
+
+    fn primitive_count(&self) -> usize {
+        0
+    }
+
+    fn punctual(&self) -> Option<&(dyn PunctualLight + Sync + Send)> {
+        Some(self)
+    }
+}
+
+#[typetag::serde]
+impl Hittable for SpotLight {
+    fn hit(&self, _r: &Ray, _ray_tmin: f64, _ray_tmax: f64) -> Option<RayHit> {
+        None
+    }
+
+    fn bounding_box_hit(&self, _r: &Ray, _ray_tmin: f64, _ray_tmax: f64) -> bool {
+        false
+    }
+
+    fn primitive_count(&self) -> usize {
+        0
+    }
+
+    fn punctual(&self) -> Option<&(dyn PunctualLight + Sync + Send)> {
+        Some(self)
+    }
+}
+
+#[typetag::serde]
+impl Hittable for DirectionalLight {
+    fn hit(&self, _r: &Ray, _ray_tmin: f64, _ray_tmax: f64) -> Option<RayHit> {
+        None
+    }
+
+    fn bounding_box_hit(&self, _r: &Ray, _ray_tmin: f64, _ray_tmax: f64) -> bool {
+        false
+    }
+
+    fn primitive_count(&self) -> usize {
+        0
+    }
+
+    fn punctual(&self) -> Option<&(dyn PunctualLight + Sync + Send)> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // type C profile with quarter symmetry, brightest straight down and dark sideways
+    const IES: &str = "IESNA:LM-63-2002\n[TEST] test\nTILT=NONE\n1 1000 2 3 2 1 2 0 0 0\n1.0 1.0 50\n\
+        0 45 90\n0 90\n100 50 0\n100, 25, 0\n";
+
+    #[test]
+    fn ies_profile_parses_and_interpolates() {
+        let ies = IesProfile::parse(IES).unwrap();
+        assert_eq!(ies.vertical, vec![0.0, 45.0, 90.0]);
+        assert_eq!(ies.horizontal, vec![0.0, 90.0]);
+        assert_eq!(ies.value(0.0, 0.0), 1.0);
+        assert_eq!(ies.value(90.0, 0.0), 0.0);
+        assert!((ies.value(22.5, 0.0) - 0.75).abs() < 1e-12);
+        assert!((ies.value(45.0, 45.0) - 0.375).abs() < 1e-12);
+        // folded by the quarter symmetry
+        assert_eq!(ies.value(45.0, 270.0), ies.value(45.0, 90.0));
+        assert_eq!(ies.value(45.0, 180.0), ies.value(45.0, 0.0));
+    }
+
+    #[test]
+    fn ies_profile_rejects_truncated_files() {
+        assert!(IesProfile::parse("TILT=NONE\n1 1000 1 3 2 1 2 0 0 0\n1.0 1.0 50\n0 45").is_err());
+        assert!(IesProfile::parse("1 1000 1 3 2").is_err());
+    }
+
+    #[test]
+    fn spot_light_spreads_its_power_over_the_cone() {
+        let cone = Spot{inner: 20.0, outer: 40.0};
+        let light = SpotLight::new(DVec3::zero(), DVec3::unit_y(), 0.0, cone, DVec3::one(), Intensity::Watts(10.0));
+        // integrate the falloff over the sphere, it only depends on the angle from the axis
+        let steps = 100000;
+        let mut power = 0.0;
+        for i in 0..steps {
+            let cos = -1.0 + 2.0 * (i as f64 + 0.5) / steps as f64;
+            power += light.intensity * cone.falloff(cos) * 2.0 * PI * 2.0 / steps as f64;
+        }
+        assert!((power - 10.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn area_light_power_matches_its_rating() {
+        let profile = Spot{inner: 30.0, outer: 60.0};
+        let light = AreaLight::new(DVec3::one(), Intensity::Lumens(1000.0), 2.0, false, Some(profile), None);
+        let watts = light.radiance * 2.0 * light.projected_profile();
+        assert!((watts - 1000.0 / LUMINOUS_EFFICACY).abs() < 1e-9);
+        let diffuse = AreaLight::new(DVec3::one(), Intensity::Watts(PI), 1.0, true, None, None);
+        assert!((diffuse.radiance - 0.5).abs() < 1e-12);
+    }
+}
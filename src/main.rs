@@ -1,27 +1,144 @@
 use std::f64::consts::PI;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use ndarray::Array3;
 use fltk::{app, prelude::*, window::Window, image::RgbImage as FltkRgbImage, frame::Frame};
+use fltk::app::MouseButton;
+use fltk::enums::{Event, Key, Shortcut};
+use fltk::dialog::{self, NativeFileChooser, NativeFileChooserOptions, NativeFileChooserType};
 //extern crate oidn;
 
 mod camera;
 mod materials;
 mod raytracing;
 mod obj_loader;
+mod navigation;
+mod controls;
+mod color;
+mod spectral;
+mod bdpt;
+mod photon;
+mod integrator;
+mod lights;
+mod principled;
+mod specialty;
+mod texture;
+mod bvh;
+mod sdf;
+mod hair;
+mod subdivision;
+mod ply_loader;
+mod stl_loader;
+mod point_cloud;
+mod scene;
+mod farm;
+mod gltf_loader;
 
 use camera::Camera;
-use obj_loader::load_mesh;
-use raytracing::{HittableList, Sphere, unit_samp, Mesh};
+use integrator::IntegratorKind;
+use gltf_loader::load_gltf;
+use hair::{load_hair, CurveType, Curves, Hair};
+use ply_loader::load_ply;
+use point_cloud::{PointCloud, PointKind};
+use stl_loader::load_stl;
+use obj_loader::{load_mesh, load_mesh_subdivided, merge_groups, MeshGroup};
+use lights::{AreaLight, DirectionalLight, IesProfile, Intensity, PointLight, Spot, SpotLight, LUMINOUS_EFFICACY};
+use navigation::ViewState;
+use photon::Sppm;
+use principled::Principled;
+use scene::{load_scene, save_scene};
+use spectral::Dispersion;
+use color::{CubeLut, InputSpace, OutputTransform};
+use controls::{ControlPanel, Message, RenderSettings, PANEL_WIDTH, PANEL_HEIGHT};
+use raytracing::{BoundingBox, Disc, HittableList, Sphere, unit_samp, Mesh};
+use sdf::{Sdf, SdfShape};
 use ultraviolet::{DVec3, DRotor3};
 
 const WIDTH: i32 = 640;
 const HEIGHT: i32 = 480;
+const PREVIEW_SCALE: i32 = 4;
+const IDLE_WAIT: Duration = Duration::from_millis(50);
 
-fn main() {
+// options for the windowed mode, a bare argument is a saved scene to open
+struct Options {
+    scene: Option<String>,
+    material_space: InputSpace,
+    // photometric profile for the ceiling light
+    ies: Option<String>,
+    // mesh file to stand in front of the big spheres, how often to subdivide it and the radius
+    // to draw it as points with instead, for scans without faces
+    model: Option<String>,
+    subdivide: usize,
+    points: Option<f64>,
+    // .hair file to stand beside the model, melanin colored unless a color is given
+    hair: Option<String>,
+    hair_color: Option<DVec3>,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options{scene: None, material_space: InputSpace::Linear, ies: None, model: None, subdivide: 0, points: None, hair: None, hair_color: None};
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            // material colors as picked in an image editor
+            "--srgb-materials" => options.material_space = InputSpace::Srgb,
+            "--ies" => options.ies = Some(value()?),
+            "--model" => options.model = Some(value()?),
+            "--subdivide" => options.subdivide = value()?.parse().map_err(|_| "--subdivide needs a number of levels".to_string())?,
+            "--points" => options.points = Some(value()?.parse().map_err(|_| "--points needs a radius".to_string())?),
+            "--hair" => options.hair = Some(value()?),
+            "--hair-color" => options.hair_color = Some(parse_color(&value()?)?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            file => options.scene = Some(file.to_string()),
+        }
+    }
+    Ok(options)
+}
+
+// "r,g,b"
+fn parse_color(text: &str) -> Result<DVec3, String> {
+    let c: Vec<f64> = text.split(',').map(|c| c.trim().parse()).collect::<Result<_, _>>()
+        .map_err(|_| format!("{} isn't a color", text))?;
+    match c[..] {
+        [r, g, b] => Ok(DVec3::new(r, g, b)),
+        _ => Err(format!("{} isn't an r,g,b color", text)),
+    }
+}
+
+// uniform scale and offset, p * scale + offset, that make the points two units across and stand them
+// on the ground at spot
+fn fit_on_ground(points: impl Iterator<Item = DVec3>, spot: DVec3) -> (f64, DVec3) {
+    let (lo, hi) = points.fold((DVec3::broadcast(f64::INFINITY), DVec3::broadcast(f64::NEG_INFINITY)), |(lo, hi), p| {
+        (lo.min_by_component(p), hi.max_by_component(p))
+    });
+    let scale = 2.0 / (hi - lo).component_max().max(1e-9);
+    let base = DVec3::new((lo.x + hi.x) / 2.0, lo.y, (lo.z + hi.z) / 2.0);
+    (scale, spot - base * scale)
+}
+
+// the groups of an .obj or a gltf scene with their own materials, ply and stl files are one group
+// in the default material. only .obj files can be subdivided
+fn load_model(filename: &str, subdivide: usize) -> Result<Vec<MeshGroup>, String> {
+    let extension = Path::new(filename).extension().and_then(|e| e.to_str()).map(str::to_lowercase);
+    match extension.as_deref() {
+        Some("obj") if subdivide > 0 => load_mesh_subdivided(filename, subdivide),
+        Some("obj") => load_mesh(filename),
+        Some(_) if subdivide > 0 => Err("only .obj models can be subdivided".to_string()),
+        Some("gltf" | "glb") => load_gltf(filename),
+        Some("ply") => Ok(vec![MeshGroup::new(load_ply(filename)?, Principled::default())]),
+        Some("stl") => Ok(vec![MeshGroup::new(load_stl(filename)?, Principled::default())]),
+        _ => Err(format!("{} isn't an .obj, .gltf, .glb, .ply or .stl file", filename)),
+    }
+}
 
+// the random sphere grid with a few showcase objects
+fn random_scene(options: &Options) -> Result<HittableList, String> {
+    let space = options.material_space;
     let mut world = HittableList::new();
 
-    let mat_ground = materials::Lambertian{albedo: DVec3::new(0.5, 0.5, 0.5)};
+    let mat_ground = materials::Lambertian{albedo: space.to_linear(DVec3::new(0.5, 0.5, 0.5))};
     world.push(Box::new(Sphere{center: DVec3::new(0.0, -1000.0, 0.0), radius: 1000.0, mat:Box::new(mat_ground)}));
 
     for a in -11..11 {
@@ -30,20 +147,22 @@ fn main() {
             let center = DVec3::new(a as f64 + 0.9 * fastrand::f64(), 0.2, b as f64 + 0.9 * fastrand::f64());
             if (center - DVec3::new(4.0, 0.2, 0.0)).mag() > 0.9 {
                 if choose_mat < 0.8 {
-                    let albedo = unit_samp();
+                    let albedo = space.to_linear(unit_samp());
                     let sphere_mat = materials::Lambertian{albedo};
                     world.push(
                         Box::new(Sphere{center, radius:0.2, mat:Box::new(sphere_mat)})
                     );
-                } else if choose_mat < 0.8 {
-                    let albedo = unit_samp();
+                } else if choose_mat < 0.95 {
+                    let albedo = space.to_linear(unit_samp());
                     let fuzz = fastrand::f64() * 0.5;
                     let sphere_mat = materials::Metal{albedo, fuzz};
                     world.push(
                         Box::new(Sphere{center, radius:0.2, mat:Box::new(sphere_mat)})
                     );
                 } else {
-                    let sphere_mat = materials::Dielectric{ior:1.5};
+                    // crown glass or a more dispersive flint
+                    let dispersion = if fastrand::bool() {Dispersion::bk7()} else {Dispersion::from_abbe(1.5, 40.0)};
+                    let sphere_mat = materials::Dielectric{ior:1.5, dispersion};
                     world.push(
                         Box::new(Sphere{center, radius:0.2, mat:Box::new(sphere_mat)})
                     );
@@ -52,83 +171,413 @@ fn main() {
         }
     }
 
-    let mat1 = materials::Emissive{color:DVec3::new(1.0, 1.0, 1.0),strength:20.0};
+    let light_color = space.to_linear(DVec3::new(1.0, 1.0, 1.0));
+    let mat1 = AreaLight::new(light_color, Intensity::Nits(20.0 * LUMINOUS_EFFICACY), 4.0 * PI, false, None, None);
     world.push(
         Box::new(Sphere{center:DVec3::new(2.0, 3.0, -1.0), radius:1.0, mat:Box::new(mat1)})
     );
+    world.push(
+        Box::new(DirectionalLight::new(DVec3::new(0.5, 0.5, 0.0), 0.53, light_color, Intensity::Watts(2.0)))
+    );
+    // a warm bulb rated like a household one, aimed at the principled sphere
+    world.push(Box::new(SpotLight::new(
+        DVec3::new(4.0, 4.0, 2.0), DVec3::new(0.0, -3.0, -2.0), 0.05, Spot{inner: 10.0, outer: 20.0},
+        space.to_linear(DVec3::new(1.0, 0.8, 0.6)), Intensity::Lumens(800.0)
+    )));
+    world.push(Box::new(PointLight::new(DVec3::new(-4.0, 2.5, 1.5), 0.1, light_color, Intensity::Watts(10.0))));
+    if let Some(filename) = &options.ies {
+        let profile = IesProfile::load(filename)?;
+        let radius = 0.3;
+        let mat = AreaLight::new(light_color, Intensity::Lumens(3000.0), PI * radius * radius, false, None, Some(Arc::new(profile)));
+        world.push(Box::new(Disc{center: DVec3::new(0.0, 4.0, 0.0), normal: DVec3::new(0.0, -1.0, 0.0), radius, mat: Box::new(mat)}));
+    }
+
+    let mat3 = Principled{
+        base_color: space.to_linear(DVec3::new(0.6, 0.05, 0.05)), roughness: 0.4, clearcoat: 1.0, sheen: 0.3,
+        ..Principled::default()
+    };
+    world.push(
+        Box::new(Sphere{center:DVec3::new(4.0, 1.0, 0.0), radius:1.0, mat:Box::new(mat3)})
+    );
 
-    let mat2 = materials::Metal{albedo:DVec3::new(0.4, 0.2, 0.1), fuzz:1.0};
+    let mat4 = materials::Subsurface{
+        albedo: space.to_linear(DVec3::new(0.9, 0.75, 0.6)), radius: DVec3::new(0.3, 0.15, 0.08), ior: 1.4
+    };
+    world.push(
+        Box::new(Sphere{center:DVec3::new(-4.0, 1.0, 0.0), radius:1.0, mat:Box::new(mat4)})
+    );
+
+    let mat2 = materials::Metal{albedo:space.to_linear(DVec3::new(0.4, 0.2, 0.1)), fuzz:1.0};
+    // clear varnish over the rough metal
+    let coated = materials::Layer{
+        coat: Box::new(materials::Metal{albedo: DVec3::one(), fuzz: 0.0}), base: Box::new(mat2),
+        weight: materials::Weight::Constant(1.0), ior: 1.5
+    };
     /*world.push(
         Box::new(Sphere{center:DVec3::new(-4.0, 1.0, 0.0), radius:1.0, mat:Box::new(mat2)})
     );*/
     let mut suzanne = Mesh::new(
-        load_mesh("C:\\Users\\joshu\\Documents\\rust_projects\\rust_raytracing\\suzanne.obj"), Box::new(mat2)
+        merge_groups(load_mesh_subdivided("C:\\Users\\joshu\\Documents\\rust_projects\\rust_raytracing\\suzanne.obj", 2)?), Box::new(coated)
     );
     suzanne.transform(DVec3::new(1.0, 0.5, -1.0), DRotor3::from_euler_angles(0.0, -PI / 4.0, -PI / 4.0));
     world.push(Box::new(suzanne));
 
+    // gold mandelbulb half a unit across, the box keeps the marching off the rest of the scene
+    let bulb = Sdf::Translate{
+        offset: DVec3::new(2.5, 0.62, 2.0),
+        shape: Box::new(Sdf::Scale{factor: 0.5, shape: Box::new(Sdf::Mandelbulb{power: 8.0, iterations: 12})})
+    };
+    let gold = materials::Metal{albedo: space.to_linear(DVec3::new(1.0, 0.78, 0.34)), fuzz: 0.2};
+    let bounds = BoundingBox{min: DVec3::new(1.85, -0.03, 1.35), max: DVec3::new(3.15, 1.27, 2.65)};
+    world.push(Box::new(SdfShape::new(bulb, bounds, Box::new(gold))));
+
+    if let Some(model) = &options.model {
+        let groups = load_model(model, options.subdivide)?;
+        // in front of the big spheres
+        let points = groups.iter().flat_map(|g| (0..g.data.vertex_count() as u32).map(|i| g.data.position(i)));
+        let (scale, offset) = fit_on_ground(points, DVec3::new(0.0, 0.0, 2.5));
+        if let Some(radius) = options.points {
+            let mut data = merge_groups(groups);
+            data.map_positions(|p| p * scale + offset);
+            let mat = Box::new(Principled::default());
+            world.push(Box::new(PointCloud::from_mesh_data(&data, radius, PointKind::Disc, mat)));
+        } else {
+            if groups.iter().all(|g| g.data.indices.is_empty()) {
+                return Err(format!("{} has no faces, give --points <radius> to draw its vertices", model));
+            }
+            for group in groups.into_iter().filter(|g| !g.data.indices.is_empty()) {
+                let mut data = group.data;
+                data.map_positions(|p| p * scale + offset);
+                let mesh = Mesh::new(data, Box::new(group.mat));
+                world.push(Box::new(Mesh{
+                    normal_map: group.normal_map, bump_map: group.bump_map, bump_scale: group.bump_scale * scale, ..mesh
+                }));
+            }
+        }
+    }
+
+    if let Some(hair) = &options.hair {
+        let mut curves = load_hair(hair, 1.0)?;
+        let (scale, offset) = fit_on_ground(curves.iter().flat_map(|c| c.points), DVec3::new(-2.5, 0.0, 2.0));
+        for curve in &mut curves {
+            curve.points = curve.points.map(|p| p * scale + offset);
+            curve.width = curve.width.map(|w| w * scale);
+        }
+        // brown with a little red unless told otherwise
+        let mat = match options.hair_color {
+            Some(color) => Hair::from_color(space.to_linear(color), 0.3),
+            None => Hair::from_melanin(1.3, 0.2),
+        };
+        world.push(Box::new(Curves::new(curves, CurveType::Cylinder, Box::new(mat))));
+    }
+
     /*let mat3 = materials::Metal{albedo:DVec3::new(0.7, 0.6, 0.5), fuzz: 0.0};
     world.push(
         Box::new(Sphere{center:DVec3::new(4.0, 1.0, 0.0), radius:1.0, mat:Box::new(mat3)})
     );*/
 
-    let lookfrom = DVec3::new(13.0, 2.0, 3.0);
-    let lookat = DVec3::new(0.0, 0.5, -1.0);
-    let vup = DVec3::new(0.0, 1.0, 0.0);
+    Ok(world)
+}
 
-    let camera = Camera{width: WIDTH, height:HEIGHT, samples:1, max_depth:2, vfov:20.0};
-    let config = Arc::new(camera.get_config(lookfrom, lookat, vup, 0.1, 10.0));
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    // render farm modes run without a window
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [_, "--coordinator", scene_file, address, output] => {
+            farm::coordinate(scene_file, address, output).expect("render farm failed");
+            return;
+        }
+        [_, "--worker", address] => {
+            farm::work(address).expect("worker failed");
+            return;
+        }
+        _ => {}
+    }
+
+    let options = parse_options(&args[1..]).expect("bad arguments");
+    // a saved scene given on the command line replaces the random one
+    let (world, view, settings) = match &options.scene {
+        Some(filename) => {
+            let scene = load_scene(filename).expect("couldn't load scene");
+            (scene.world, scene.view, scene.settings)
+        }
+        None => {
+            let view = ViewState {
+                lookfrom: DVec3::new(13.0, 2.0, 3.0),
+                lookat: DVec3::new(0.0, 0.5, -1.0),
+                vup: DVec3::new(0.0, 1.0, 0.0),
+                vfov: 20.0,
+                defocus_angle: 0.1,
+                focus_dist: 10.0,
+                interacting: false,
+            };
+            let settings = RenderSettings {
+                width: WIDTH,
+                height: HEIGHT,
+                samples: 1,
+                max_depth: 64,
+                spectral: false,
+                integrator: IntegratorKind::PathTracer,
+                target_passes: 256,
+                output: OutputTransform::default(),
+                paused: false,
+                generation: 0,
+            };
+            (random_scene(&options).expect("couldn't build the scene"), view, settings)
+        }
+    };
+    let (width, height) = (settings.width, settings.height);
+
+    let view = Arc::new(Mutex::new(view));
+    let settings = Arc::new(Mutex::new(settings));
     let world = Arc::new(world);
 
-    let accum_img = Arc::new(Mutex::new(Array3::<f64>::zeros((HEIGHT as usize, WIDTH as usize, 3))));
+    let accum_img = Arc::new(Mutex::new(Array3::<f64>::zeros((height as usize, width as usize, 3))));
     let pass_count = Arc::new(Mutex::new(0));
 
     let app = app::App::default();
-    let mut wind = Window::new(100, 100, WIDTH, HEIGHT, "Ray Tracing Progress");
-    let mut frame = Frame::default().with_size(WIDTH, HEIGHT).center_of(&wind);
+    let mut wind = Window::new(100, 100, width + PANEL_WIDTH, height.max(PANEL_HEIGHT), "Ray Tracing Progress");
+    let mut frame = Frame::new(0, 0, width, height, None);
+
+    let (s, r) = app::channel::<Message>();
+    let mut panel = ControlPanel::new(width, &settings.lock().unwrap(), s);
+
+    wind.end();
+    wind.make_resizable(false);
     wind.show();
 
-    let (s, r) = app::channel::<()>();
+    let view_ui = Arc::clone(&view);
+    let settings_ui = Arc::clone(&settings);
+    let world_ui = Arc::clone(&world);
+    let mut last_mouse = (0, 0);
+    frame.handle(move |f, ev| {
+        match ev {
+            Event::Push => {
+                let _ = f.take_focus();
+                last_mouse = app::event_coords();
+                true
+            }
+            Event::Drag => {
+                let (x, y) = app::event_coords();
+                let (dx, dy) = ((x - last_mouse.0) as f64, (y - last_mouse.1) as f64);
+                last_mouse = (x, y);
+                let mut view = view_ui.lock().unwrap();
+                view.interacting = true;
+                match app::event_mouse_button() {
+                    MouseButton::Left if app::event_state().contains(Shortcut::Shift) => view.pan(dx, dy, f.height()),
+                    MouseButton::Left if app::event_state().contains(Shortcut::Ctrl) => view.look(dx, dy),
+                    MouseButton::Left => view.orbit(dx, dy),
+                    MouseButton::Middle => view.pan(dx, dy, f.height()),
+                    MouseButton::Right => view.dolly(-dy),
+                    _ => {}
+                }
+                true
+            }
+            Event::Released => {
+                let mut view = view_ui.lock().unwrap();
+                if app::event_is_click() && app::event_mouse_button() == MouseButton::Left {
+                    // click to focus on the surface under the cursor
+                    let (x, y) = app::event_coords();
+                    let camera = settings_ui.lock().unwrap().camera(view.vfov);
+                    let config = camera.get_config(view.lookfrom, view.lookat, view.vup, view.defocus_angle, view.focus_dist);
+                    if let Some(p) = camera.surface_at(&world_ui, &config, x - f.x(), y - f.y()) {
+                        view.focus_on(p);
+                    }
+                }
+                view.interacting = false;
+                true
+            }
+            Event::MouseWheel => {
+                view_ui.lock().unwrap().zoom_fov(app::event_dy_value() as f64);
+                true
+            }
+            Event::Focus | Event::Unfocus => true,
+            Event::KeyDown => {
+                let mut view = view_ui.lock().unwrap();
+                match app::event_key() {
+                    Key::Up => view.turn(0.0, -1.0),
+                    Key::Down => view.turn(0.0, 1.0),
+                    Key::Left => view.turn(-1.0, 0.0),
+                    Key::Right => view.turn(1.0, 0.0),
+                    _ => match app::event_text().to_lowercase().as_str() {
+                        "w" => view.fly(1.0, 0.0, 0.0),
+                        "s" => view.fly(-1.0, 0.0, 0.0),
+                        "a" => view.fly(0.0, -1.0, 0.0),
+                        "d" => view.fly(0.0, 1.0, 0.0),
+                        "e" => view.fly(0.0, 0.0, 1.0),
+                        "q" => view.fly(0.0, 0.0, -1.0),
+                        _ => return false,
+                    }
+                }
+                view.interacting = true;
+                true
+            }
+            Event::KeyUp => {
+                view_ui.lock().unwrap().interacting = false;
+                true
+            }
+            _ => false,
+        }
+    });
 
     let accum_img_render = Arc::clone(&accum_img);
     let pass_count_render = Arc::clone(&pass_count);
     let world_render = Arc::clone(&world);
-    let config_render = Arc::clone(&config);
+    let view_render = Arc::clone(&view);
+    let settings_render = Arc::clone(&settings);
 
     std::thread::spawn(move || {
+        let mut last_view: Option<ViewState> = None;
+        let mut last_generation = None;
+        // photon mapping refines its estimate across passes, this holds its per pixel state
+        let mut sppm: Option<Sppm> = None;
         loop {
-            let pass_img = camera.render_pass(&world_render, &config_render);
+            let view = view_render.lock().unwrap().clone();
+            let settings = settings_render.lock().unwrap().clone();
+            let changed = last_view.as_ref() != Some(&view) || last_generation != Some(settings.generation);
+            let done = *pass_count_render.lock().unwrap() >= settings.target_passes;
+            if settings.paused || (done && !changed && !view.interacting) {
+                std::thread::sleep(IDLE_WAIT);
+                continue;
+            }
+
+            // render a cheap low resolution pass while the user is moving the camera
+            let full_camera = settings.camera(view.vfov);
+            let pass_camera = if view.interacting {
+                Camera{width: (settings.width / PREVIEW_SCALE).max(1), height: (settings.height / PREVIEW_SCALE).max(1), samples: 1, ..full_camera}
+            } else {
+                full_camera
+            };
+            let config = Arc::new(pass_camera.get_config(view.lookfrom, view.lookat, view.vup, view.defocus_angle, view.focus_dist));
+            let start = Instant::now();
+            if changed || view.interacting {sppm = None};
+            let pass_img = pass_camera.render_pass(&world_render, &config, &mut sppm);
+            let elapsed = start.elapsed().as_secs_f64();
+
+            // camera or settings changed while this pass was rendering
+            if *view_render.lock().unwrap() != view || settings_render.lock().unwrap().generation != settings.generation {continue};
+
             let mut accum = accum_img_render.lock().unwrap();
             let mut count = pass_count_render.lock().unwrap();
+            if view.interacting || changed {
+                let dim = (settings.height as usize, settings.width as usize, 3);
+                if accum.dim() != dim {
+                    *accum = Array3::zeros(dim);
+                } else {
+                    accum.fill(0.0);
+                }
+                *count = 0;
+            }
             *count += 1;
-            
-            for ((y, x, c), val) in accum.indexed_iter_mut() {
-                *val += pass_img[(y, x, c)];
+
+            if view.interacting {
+                let (preview_h, preview_w, _) = pass_img.dim();
+                for ((y, x, c), val) in accum.indexed_iter_mut() {
+                    let py = (y / PREVIEW_SCALE as usize).min(preview_h - 1);
+                    let px = (x / PREVIEW_SCALE as usize).min(preview_w - 1);
+                    *val = pass_img[(py, px, c)];
+                }
+            } else {
+                for ((y, x, c), val) in accum.indexed_iter_mut() {
+                    *val += pass_img[(y, x, c)];
+                }
             }
-            s.send(());
+            last_view = Some(view);
+            last_generation = Some(settings.generation);
+
+            let samples = pass_camera.width as f64 * pass_camera.height as f64 * pass_camera.samples as f64;
+            s.send(Message::PassDone{samples_per_sec: samples / elapsed.max(1e-9)});
         }
     });
 
+    let mut samples_per_sec = 0.0;
     while app.wait() {
-        if let Some(_) = r.recv() {
-            let accum = accum_img.lock().unwrap();
-            let count = *pass_count.lock().unwrap();
-            
-            println!("{} passes completed", count);
-            
-            let mut buffer = vec![0u8; (WIDTH * HEIGHT * 3) as usize];
-            for y in 0..HEIGHT as usize {
-                for x in 0..WIDTH as usize {
-                    for c in 0..3 {
-                        let val = accum[(y, x, c)] / count as f64;
-                        let color = (val.sqrt().clamp(0.0, 1.0) * 255.0) as u8;
-                        buffer[(y * WIDTH as usize + x) * 3 + c] = color;
-                    }
+        let Some(msg) = r.recv() else {continue};
+        let mut settings = settings.lock().unwrap();
+        match msg {
+            Message::PassDone{samples_per_sec: sps} => samples_per_sec = sps,
+            Message::Pause => settings.paused = true,
+            Message::Resume => settings.paused = false,
+            Message::Restart => settings.generation += 1,
+            Message::Save => {
+                let accum = accum_img.lock().unwrap();
+                let count = *pass_count.lock().unwrap();
+                save_image(&accum, count, &settings.output);
+            }
+            Message::SaveScene => save_scene_as(&world, &view.lock().unwrap(), &settings),
+            Message::LoadLut => {
+                if let Some(lut) = load_lut() {
+                    settings.output.lut = Some(Arc::new(lut));
                 }
             }
-            let fltk_img = FltkRgbImage::new(&buffer, WIDTH, HEIGHT, fltk::enums::ColorDepth::Rgb8).unwrap();
+            Message::ClearLut => settings.output.lut = None,
+            Message::SettingsChanged => {
+                if panel.apply(&mut settings) {
+                    frame.resize(0, 0, settings.width, settings.height);
+                    panel.group.resize(settings.width, 0, PANEL_WIDTH, PANEL_HEIGHT);
+                    wind.set_size(settings.width + PANEL_WIDTH, settings.height.max(PANEL_HEIGHT));
+                }
+            }
+        }
+
+        let accum = accum_img.lock().unwrap();
+        let count = *pass_count.lock().unwrap();
+        panel.update_stats(count, &settings, samples_per_sec);
+        if count > 0 {
+            let (height, width, _) = accum.dim();
+            let buffer = settings.output.to_bytes(&accum, count);
+            let fltk_img = FltkRgbImage::new(&buffer, width as i32, height as i32, fltk::enums::ColorDepth::Rgb8).unwrap();
             frame.set_image(Some(fltk_img));
-            wind.redraw();
         }
+        wind.redraw();
+    }
+}
+
+fn load_lut() -> Option<CubeLut> {
+    let mut dialog = NativeFileChooser::new(NativeFileChooserType::BrowseFile);
+    dialog.set_title("Load LUT");
+    dialog.set_filter("*.cube");
+    dialog.show();
+    let path = dialog.filename();
+    if path.as_os_str().is_empty() {return None};
+
+    match CubeLut::load(&path.to_string_lossy()) {
+        Ok(lut) => Some(lut),
+        Err(e) => {
+            dialog::alert_default(&format!("Couldn't load LUT: {}", e));
+            None
+        }
+    }
+}
+
+fn save_scene_as(world: &HittableList, view: &ViewState, settings: &RenderSettings) {
+    let mut dialog = NativeFileChooser::new(NativeFileChooserType::BrowseSaveFile);
+    dialog.set_title("Save scene");
+    dialog.set_filter("*.json");
+    dialog.set_preset_file("scene.json");
+    dialog.set_option(NativeFileChooserOptions::SaveAsConfirm);
+    dialog.show();
+    let path = dialog.filename();
+    if path.as_os_str().is_empty() {return};
+
+    if let Err(e) = save_scene(&path.to_string_lossy(), world, view, settings) {
+        dialog::alert_default(&e);
+    }
+}
+
+fn save_image(accum: &Array3<f64>, count: i32, output: &OutputTransform) {
+    let mut dialog = NativeFileChooser::new(NativeFileChooserType::BrowseSaveFile);
+    dialog.set_title("Save image");
+    dialog.set_filter("*.png\t*.jpg\t*.bmp\t*.tga");
+    dialog.set_preset_file("render.png");
+    dialog.set_option(NativeFileChooserOptions::SaveAsConfirm);
+    dialog.show();
+    let path = dialog.filename();
+    if path.as_os_str().is_empty() {return};
+
+    let (height, width, _) = accum.dim();
+    let buffer = output.to_bytes(accum, count.max(1));
+    if let Err(e) = image::save_buffer(&path, &buffer, width as u32, height as u32, image::ColorType::Rgb8) {
+        dialog::alert_default(&format!("Couldn't save {}: {}", path.display(), e));
     }
 }
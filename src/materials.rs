@@ -1,11 +1,16 @@
 
+use std::f64::consts::PI;
+use std::sync::Arc;
 use crate::raytracing::{Ray, RayHit, unit_samp};
+use crate::spectral::Dispersion;
+use crate::texture::Texture;
 use ultraviolet::*;
 use dyn_clone::DynClone;
+use serde::{Deserialize, Serialize};
 
 fn near_zero(v: DVec3) -> bool {
     let eps: f64 = 1e-8;
-    v.x < eps && v.y < eps && v.z < eps
+    v.x.abs() < eps && v.y.abs() < eps && v.z.abs() < eps
 }
 
 fn reflect(v:DVec3, normal:DVec3) -> DVec3 {
@@ -25,17 +30,74 @@ fn reflectance(cos: f64, ref_idx: f64) -> f64 {
     r0 + (1.0-r0) * (1.0-cos).powi(5)
 }
 
-pub trait Material: DynClone {
+// reflects or refracts at a smooth boundary to a medium of the given ior, picked by fresnel
+fn boundary_direction(unit_direction: DVec3, rec: &RayHit, ior: f64) -> DVec3 {
+    let refr_ratio = if rec.front {1.0/ior} else {ior};
+
+    let cos_theta = rec.normal.dot(-unit_direction).min(1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    let cannot_refract = refr_ratio * sin_theta > 1.0;
+
+    if cannot_refract || (reflectance(cos_theta, refr_ratio) > fastrand::f64()) {
+        reflect(unit_direction, rec.normal)
+    } else {
+        refract(unit_direction, rec.normal, refr_ratio)
+    }
+}
+
+#[typetag::serde(tag = "type")]
+pub trait Material: DynClone + Send + Sync {
     fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray>;
+
+    // bsdf for light arriving from wi and leaving back along r_in, without the cosine term
+    fn eval(&self, _r_in: &Ray, _rec: &RayHit, _wi: DVec3) -> DVec3 {
+        DVec3::zero()
+    }
+
+    // solid angle density of scatter picking wi
+    fn pdf(&self, _r_in: &Ray, _rec: &RayHit, _wi: DVec3) -> f64 {
+        0.0
+    }
+
+    // true when scatter can't be described by eval and pdf, e.g. mirrors and glass
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    // for emitters, whether the back side emits too
+    fn emits_two_sided(&self) -> bool {
+        false
+    }
+
+    // rgb surface color, for the albedo view
+    fn albedo(&self) -> DVec3 {
+        DVec3::one()
+    }
+
+    // radiance leaving the surface back along r_in
+    fn emitted(&self, _r_in: &Ray, _rec: &RayHit) -> DVec3 {
+        DVec3::zero()
+    }
+
+    // one sided surfaces can't be hit from behind, open meshes then vanish from the back
+    fn two_sided(&self) -> bool {
+        true
+    }
 }
 
 dyn_clone::clone_trait_object!(Material);
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Lambertian{
     pub albedo: DVec3
 }
 
+#[typetag::serde]
 impl Material for Lambertian {
     fn scatter(&self, r_in: &Ray, rec:&RayHit) -> Option<Ray> {
         let mut scatter_direction = rec.normal + unit_samp();
@@ -43,66 +105,367 @@ impl Material for Lambertian {
         if near_zero(scatter_direction) {
             scatter_direction = rec.normal;
         }
+        // a bent shading normal can send the ray through the actual surface
+        if scatter_direction.dot(rec.geometric_normal) <= 0.0 {
+            return None;
+        }
 
-        let scatter_ray = Ray::new(rec.hit_point, scatter_direction, self.albedo * r_in.color);
+        let scatter_ray = r_in.scatter_from(rec, scatter_direction, r_in.reflectance(self.albedo * rec.color) * r_in.color);
         Some(scatter_ray)
     }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        if wi.dot(rec.normal) <= 0.0 {return DVec3::zero()};
+        r_in.reflectance(self.albedo * rec.color) / PI
+    }
+
+    // normal + unit_samp() is cosine distributed
+    fn pdf(&self, _r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        wi.normalized().dot(rec.normal).max(0.0) / PI
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.albedo
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Metal{
     pub albedo: DVec3,
     pub fuzz: f64
 }
 
+// solid angle density of the direction of m + fuzz * unit_samp() for a unit vector m, at a
+// direction whose cosine with m is cos. the offset lands uniformly on a sphere of radius fuzz
+// around m, a direction gets the area of every crossing of that sphere in front of the origin
+fn fuzz_pdf(cos: f64, fuzz: f64) -> f64 {
+    let disc = fuzz * fuzz - (1.0 - cos * cos);
+    if disc <= 0.0 {return 0.0};
+    let root = disc.sqrt();
+    let area: f64 = [cos - root, cos + root].iter().filter(|t| **t > 0.0).map(|t| t * t).sum();
+    area / (4.0 * PI * fuzz * root)
+}
+
+#[typetag::serde]
 impl Material for Metal {
     fn scatter(&self, r_in: &Ray, rec:&RayHit) -> Option<Ray> {
         let reflected = reflect(r_in.direction.normalized(), rec.normal);
-        let color = r_in.color * self.albedo;
-        let scattered = Ray::new(rec.hit_point, reflected + self.fuzz * unit_samp(), color);
-        if scattered.direction.dot(rec.normal) > 0.0 {Some(scattered)} else {None}
+        let color = r_in.color * r_in.reflectance(self.albedo);
+        let scattered = r_in.scatter_from(rec, reflected + self.fuzz * unit_samp(), color);
+        if scattered.direction.dot(rec.normal) > 0.0 && scattered.direction.dot(rec.geometric_normal) > 0.0 {Some(scattered)} else {None}
+    }
+
+    // scatter keeps the albedo for every direction it doesn't absorb, so f * cos = albedo * pdf
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let cos = wi.normalized().dot(rec.normal);
+        if cos <= 0.0 {return DVec3::zero()};
+        r_in.reflectance(self.albedo) * self.pdf(r_in, rec, wi) / cos
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        let wi = wi.normalized();
+        if self.fuzz <= 0.0 || wi.dot(rec.normal) <= 0.0 || wi.dot(rec.geometric_normal) <= 0.0 {return 0.0};
+        let reflected = reflect(r_in.direction.normalized(), rec.normal);
+        fuzz_pdf(wi.dot(reflected), self.fuzz)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.fuzz == 0.0
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.albedo
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Dielectric {
-    pub ior: f64
+    pub ior: f64,
+    // only used in spectral mode, ior is used otherwise
+    pub dispersion: Dispersion
 }
 
+#[typetag::serde]
 impl Material for Dielectric {
     fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
-        let attenuation = DVec3::one();
-        let refr_ratio = if rec.front {1.0/self.ior} else {self.ior};
+        let mut attenuation = DVec3::one();
+        let mut wavelengths = r_in.wavelengths;
+        let ior = match &mut wavelengths {
+            Some(w) if self.dispersion.is_dispersive() => {
+                // each wavelength bends differently, so only the hero can carry on
+                attenuation = w.terminate_secondary();
+                self.dispersion.ior(self.ior, w.hero())
+            }
+            _ => self.ior
+        };
+        let direction = boundary_direction(r_in.direction.normalized(), rec, ior);
+        let mut scattered = r_in.scatter_from(rec, direction, r_in.color * attenuation);
+        scattered.wavelengths = wavelengths;
+
+        Some(scattered)
+    }
+}
+
+// random walk subsurface scattering inside a closed surface with a smooth dielectric boundary.
+// the walk happens one boundary hit at a time: a ray inside the medium either scatters before
+// it reaches the boundary it hit, or leaves through it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Subsurface {
+    // color after many scattering events, i.e. what the surface looks like from afar
+    pub albedo: DVec3,
+    // mean free path per rgb channel, in scene units
+    pub radius: DVec3,
+    pub ior: f64
+}
+
+// albedo of a single scattering event that gives the multiple scattering albedo a, van de hulst's fit
+fn single_scatter_albedo(a: f64) -> f64 {
+    let a = a.clamp(0.0, 0.999);
+    let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+    1.0 - s * s
+}
+
+impl Subsurface {
+    // extinction and scattering coefficient per channel, one channel per wavelength in spectral mode
+    fn coefficients(&self, r_in: &Ray) -> (DVec3, DVec3) {
+        let max = self.radius.component_max().max(1e-9);
+        let radius = (r_in.reflectance(self.radius / max) * max).max_by_component(DVec3::broadcast(1e-9));
+        let sigma_t = DVec3::one() / radius;
+        let albedo = r_in.reflectance(self.albedo);
+        let ss = DVec3::new(single_scatter_albedo(albedo.x), single_scatter_albedo(albedo.y), single_scatter_albedo(albedo.z));
+        (sigma_t, sigma_t * ss)
+    }
+}
+
+fn exp(v: DVec3) -> DVec3 {
+    DVec3::new(v.x.exp(), v.y.exp(), v.z.exp())
+}
+
+#[typetag::serde]
+impl Material for Subsurface {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
         let unit_direction = r_in.direction.normalized();
+        if rec.front {
+            return Some(r_in.scatter_from(rec, boundary_direction(unit_direction, rec, self.ior), r_in.color));
+        }
 
-        let cos_theta = rec.normal.dot(-unit_direction).min(1.0);
-        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        // inside, sample a free flight with one channel's extinction and weight by the average pdf
+        let (sigma_t, sigma_s) = self.coefficients(r_in);
+        let dist = rec.hit_time * r_in.direction.mag();
+        let channel = fastrand::usize(..3);
+        let flight = -(1.0 - fastrand::f64()).ln() / sigma_t[channel];
+        if flight < dist {
+            let transmittance = exp(-sigma_t * flight);
+            let pdf = (sigma_t * transmittance).dot(DVec3::one()) / 3.0;
+            // isotropic phase function, its value cancels with its pdf
+            let p = r_in.origin + unit_direction * flight;
+            return Some(r_in.spawn(p, unit_samp(), r_in.color * sigma_s * transmittance / pdf));
+        }
+        let transmittance = exp(-sigma_t * dist);
+        let pdf = transmittance.dot(DVec3::one()) / 3.0;
+        let direction = boundary_direction(unit_direction, rec, self.ior);
+        Some(r_in.scatter_from(rec, direction, r_in.color * transmittance / pdf))
+    }
 
-        let cannot_refract = refr_ratio * sin_theta > 1.0;
+    fn albedo(&self) -> DVec3 {
+        self.albedo
+    }
+}
 
-        let direction = if cannot_refract || (reflectance(cos_theta, refr_ratio) > fastrand::f64()) {
-            reflect(unit_direction, rec.normal)
-        } else {
-            refract(unit_direction, rec.normal, refr_ratio)
-        };
+// blend factor of the combinators, textures are read as grey values at the hit's uv
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Weight {
+    Constant(f64),
+    Texture(Arc<Texture>),
+}
+
+impl Weight {
+    pub fn value(&self, rec: &RayHit) -> f64 {
+        match self {
+            Weight::Constant(w) => *w,
+            Weight::Texture(t) => t.height_at(rec.uv),
+        }.clamp(0.0, 1.0)
+    }
+
+    fn average(&self) -> f64 {
+        match self {
+            Weight::Constant(w) => *w,
+            Weight::Texture(t) => t.average.dot(DVec3::one()) / 3.0,
+        }.clamp(0.0, 1.0)
+    }
+}
+
+// linear blend, weight 0 is all a and 1 all b. scatter picks one of the two with the weight as probability
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Mix {
+    pub a: Box<dyn Material + Sync + Send>,
+    pub b: Box<dyn Material + Sync + Send>,
+    pub weight: Weight
+}
+
+#[typetag::serde]
+impl Material for Mix {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        if fastrand::f64() < self.weight.value(rec) {self.b.scatter(r_in, rec)} else {self.a.scatter(r_in, rec)}
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let w = self.weight.value(rec);
+        self.a.eval(r_in, rec, wi) * (1.0 - w) + self.b.eval(r_in, rec, wi) * w
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        let w = self.weight.value(rec);
+        self.a.pdf(r_in, rec, wi) * (1.0 - w) + self.b.pdf(r_in, rec, wi) * w
+    }
+
+    fn is_delta(&self) -> bool {
+        self.a.is_delta() && self.b.is_delta()
+    }
+
+    fn albedo(&self) -> DVec3 {
+        let w = self.weight.average();
+        self.a.albedo() * (1.0 - w) + self.b.albedo() * w
+    }
+
+    fn emitted(&self, r_in: &Ray, rec: &RayHit) -> DVec3 {
+        let w = self.weight.value(rec);
+        self.a.emitted(r_in, rec) * (1.0 - w) + self.b.emitted(r_in, rec) * w
+    }
+
+    fn two_sided(&self) -> bool {
+        self.a.two_sided() || self.b.two_sided()
+    }
+}
+
+// a coating over a base material. the coat reflects the fresnel fraction of the light for its ior,
+// scaled by weight (e.g. a coverage mask), so coat should not darken at grazing angles itself,
+// like a white Metal. the rest passes through to the base and loses the fresnel fraction again on the way out
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Layer {
+    pub coat: Box<dyn Material + Sync + Send>,
+    pub base: Box<dyn Material + Sync + Send>,
+    pub weight: Weight,
+    pub ior: f64
+}
+
+impl Layer {
+    fn coat_reflectance(&self, rec: &RayHit, dir: DVec3) -> f64 {
+        let cos = dir.normalized().dot(rec.normal).abs().min(1.0);
+        self.weight.value(rec) * reflectance(cos, self.ior)
+    }
+}
 
-        let scattered = Ray::new(rec.hit_point, direction, r_in.color * attenuation);
-        
+#[typetag::serde]
+impl Material for Layer {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        if fastrand::f64() < self.coat_reflectance(rec, -r_in.direction) {
+            return self.coat.scatter(r_in, rec);
+        }
+        let mut scattered = self.base.scatter(r_in, rec)?;
+        if !scattered.emissive {
+            scattered.color *= 1.0 - self.coat_reflectance(rec, scattered.direction);
+        }
         Some(scattered)
     }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let f_out = self.coat_reflectance(rec, -r_in.direction);
+        let f_in = self.coat_reflectance(rec, wi);
+        self.coat.eval(r_in, rec, wi) * f_out + self.base.eval(r_in, rec, wi) * (1.0 - f_out) * (1.0 - f_in)
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        let f_out = self.coat_reflectance(rec, -r_in.direction);
+        self.coat.pdf(r_in, rec, wi) * f_out + self.base.pdf(r_in, rec, wi) * (1.0 - f_out)
+    }
+
+    fn is_delta(&self) -> bool {
+        self.coat.is_delta() && self.base.is_delta()
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.base.albedo()
+    }
+
+    // emission from the base shines through the coat
+    fn emitted(&self, r_in: &Ray, rec: &RayHit) -> DVec3 {
+        self.base.emitted(r_in, rec) * (1.0 - self.coat_reflectance(rec, -r_in.direction))
+    }
+
+    fn two_sided(&self) -> bool {
+        self.base.two_sided()
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Emissive {
     pub strength: f64,
     pub color: DVec3
 }
 
+#[typetag::serde]
 impl Material for Emissive {
     fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
-        let color = self.color * self.strength;
-        let mut scattered = Ray::new(rec.hit_point, rec.normal, color);
+        let mut scattered = r_in.scatter_from(rec, rec.normal, self.emitted(r_in, rec));
         scattered.emissive = true;
         Some(scattered)
     }
-}
\ No newline at end of file
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.color
+    }
+
+    // one sided, only the outward facing side emits
+    fn emitted(&self, r_in: &Ray, rec: &RayHit) -> DVec3 {
+        if !rec.front {return DVec3::zero()};
+        r_in.illuminant(self.color) * self.strength
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(mat: Box<dyn Material + Sync>) -> RayHit {
+        RayHit{
+            hit_point: DVec3::zero(), normal: DVec3::unit_y(), geometric_normal: DVec3::unit_y(), mat, hit_time: 1.0,
+            front: true, uv: DVec2::zero(), tangent: DVec3::unit_x(), color: DVec3::one(), error: DVec3::zero()
+        }
+    }
+
+    #[test]
+    fn fuzz_pdf_integrates_to_one() {
+        for fuzz in [0.3, 1.0, 1.7] {
+            let steps = 1000000;
+            let total: f64 = (0..steps).map(|i| {
+                let cos = -1.0 + 2.0 * (i as f64 + 0.5) / steps as f64;
+                fuzz_pdf(cos, fuzz) * 2.0 * PI * 2.0 / steps as f64
+            }).sum();
+            assert!((total - 1.0).abs() < 1e-2, "fuzz {} integrates to {}", fuzz, total);
+        }
+    }
+
+    #[test]
+    fn fully_fuzzed_metal_at_normal_incidence_is_lambertian() {
+        let metal = Metal{albedo: DVec3::broadcast(0.5), fuzz: 1.0};
+        let rec = hit(Box::new(metal.clone()));
+        let r_in = Ray::new(DVec3::unit_y(), -DVec3::unit_y(), DVec3::one());
+        for wi in [DVec3::unit_y(), DVec3::new(1.0, 1.0, 0.0), DVec3::new(0.3, 0.1, -2.0)] {
+            let cos = wi.normalized().y.max(0.0);
+            assert!((metal.pdf(&r_in, &rec, wi) - cos / PI).abs() < 1e-12);
+            let f = metal.eval(&r_in, &rec, wi);
+            let expected = if cos > 0.0 {0.5 / PI} else {0.0};
+            assert!((f.x - expected).abs() < 1e-12);
+        }
+        assert!(!metal.is_delta());
+        assert!(Metal{albedo: DVec3::one(), fuzz: 0.0}.is_delta());
+    }
+}
@@ -0,0 +1,112 @@
+use std::f64::consts::PI;
+use ultraviolet::DVec3;
+use serde::{Deserialize, Serialize};
+
+const ORBIT_SPEED: f64 = 0.01;
+const LOOK_SPEED: f64 = 0.005;
+const DOLLY_SPEED: f64 = 0.01;
+const FLY_STEP: f64 = 0.25;
+const TURN_STEP: f64 = 0.05;
+const FOV_STEP: f64 = 1.1;
+const MIN_DISTANCE: f64 = 0.01;
+// keeps orbiting from flipping over the poles
+const MIN_POLAR: f64 = 0.01;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct ViewState {
+    pub lookfrom: DVec3,
+    pub lookat: DVec3,
+    pub vup: DVec3,
+    pub vfov: f64,
+    pub defocus_angle: f64,
+    pub focus_dist: f64,
+    #[serde(skip)]
+    pub interacting: bool,
+}
+
+// rodrigues' rotation of v around a unit axis, right handed
+fn rotate_about(v: DVec3, axis: DVec3, angle: f64) -> DVec3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + axis.cross(v) * sin + axis * axis.dot(v) * (1.0 - cos)
+}
+
+fn clamp_polar(offset: DVec3, up: DVec3, rotated: DVec3) -> DVec3 {
+    let polar = (rotated.normalized().dot(up)).clamp(-1.0, 1.0).acos();
+    if (MIN_POLAR..=PI - MIN_POLAR).contains(&polar) {rotated} else {offset}
+}
+
+impl ViewState {
+    // (u, v, w) basis matching Camera::get_config, w points away from the scene
+    pub fn basis(&self) -> (DVec3, DVec3, DVec3) {
+        let w = (self.lookfrom - self.lookat).normalized();
+        let u = self.vup.cross(w).normalized();
+        let v = w.cross(u);
+        (u, v, w)
+    }
+
+    pub fn distance(&self) -> f64 {
+        (self.lookfrom - self.lookat).mag()
+    }
+
+    // rotates the camera around lookat, dx/dy in pixels
+    pub fn orbit(&mut self, dx: f64, dy: f64) {
+        let (u, _, _) = self.basis();
+        let up = self.vup.normalized();
+        let offset = self.lookfrom - self.lookat;
+        let yawed = rotate_about(offset, up, -dx * ORBIT_SPEED);
+        let pitched = clamp_polar(yawed, up, rotate_about(yawed, u, -dy * ORBIT_SPEED));
+        self.lookfrom = self.lookat + pitched;
+    }
+
+    fn rotate_target(&mut self, yaw: f64, pitch: f64) {
+        let (u, _, _) = self.basis();
+        let up = self.vup.normalized();
+        let offset = self.lookat - self.lookfrom;
+        let yawed = rotate_about(offset, up, yaw);
+        let pitched = clamp_polar(yawed, up, rotate_about(yawed, u, pitch));
+        self.lookat = self.lookfrom + pitched;
+    }
+
+    // rotates lookat around the camera, used for mouse look while flying
+    pub fn look(&mut self, dx: f64, dy: f64) {
+        self.rotate_target(-dx * LOOK_SPEED, -dy * LOOK_SPEED);
+    }
+
+    // moves camera and target in the view plane so the point under the cursor follows it
+    pub fn pan(&mut self, dx: f64, dy: f64, image_height: i32) {
+        let (u, v, _) = self.basis();
+        let h = (self.vfov.to_radians() / 2.0).tan();
+        let units_per_pixel = 2.0 * h * self.distance() / image_height as f64;
+        let offset = (-dx * u + dy * v) * units_per_pixel;
+        self.lookfrom += offset;
+        self.lookat += offset;
+    }
+
+    // moves the camera towards (positive) or away from lookat
+    pub fn dolly(&mut self, amount: f64) {
+        let offset = self.lookfrom - self.lookat;
+        let dist = (offset.mag() * (-amount * DOLLY_SPEED).exp()).max(MIN_DISTANCE);
+        self.lookfrom = self.lookat + offset.normalized() * dist;
+    }
+
+    pub fn fly(&mut self, forward: f64, right: f64, up: f64) {
+        let (u, v, w) = self.basis();
+        let offset = (-forward * w + right * u + up * v) * FLY_STEP;
+        self.lookfrom += offset;
+        self.lookat += offset;
+    }
+
+    pub fn turn(&mut self, yaw: f64, pitch: f64) {
+        self.rotate_target(-yaw * TURN_STEP, -pitch * TURN_STEP);
+    }
+
+    pub fn zoom_fov(&mut self, steps: f64) {
+        self.vfov = (self.vfov * FOV_STEP.powf(steps)).clamp(1.0, 120.0);
+    }
+
+    // sets the focus plane to pass through a world space point
+    pub fn focus_on(&mut self, point: DVec3) {
+        let (_, _, w) = self.basis();
+        self.focus_dist = (point - self.lookfrom).dot(-w).max(MIN_DISTANCE);
+    }
+}
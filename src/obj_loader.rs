@@ -1,33 +1,523 @@
 use std::fs::File;
+use std::collections::HashMap;
 use std::io::BufReader;
-use obj::{load_obj, Obj};
-use ultraviolet::DVec3;
-
-#[derive(Clone, Debug)]
-pub struct MeshTriangle {
-    pub pos1: DVec3,
-    pub pos2: DVec3,
-    pub pos3: DVec3,
-    pub norm1: DVec3,
-    pub norm2: DVec3,
-    pub norm3: DVec3,
-}
-
-pub fn load_mesh(filename: &str) -> Vec<MeshTriangle> {
-    let file = BufReader::new(File::open(filename).expect("Couldn't open file"));
-    let object: Obj = load_obj(file).expect("couldn't load an object from this file");
-
-    let mut tri_list: Vec<MeshTriangle> = vec![];
-    for i in (0..object.indices.len()).step_by(3) {
-        let tri = MeshTriangle {
-            pos1: DVec3::from(object.vertices[object.indices[i] as usize].position.map(|e| e as f64)),
-            pos2: DVec3::from(object.vertices[object.indices[i+1] as usize].position.map(|e| e as f64)),
-            pos3: DVec3::from(object.vertices[object.indices[i+2] as usize].position.map(|e| e as f64)),
-            norm1: DVec3::from(object.vertices[object.indices[i] as usize].normal.map(|e| e as f64)),
-            norm2: DVec3::from(object.vertices[object.indices[i+1] as usize].normal.map(|e| e as f64)),
-            norm3: DVec3::from(object.vertices[object.indices[i+2] as usize].normal.map(|e| e as f64))
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use obj::raw::{parse_mtl, parse_obj};
+use obj::raw::material::Material as MtlMaterial;
+use obj::raw::object::{Polygon, RawObj};
+use ultraviolet::{DVec2, DVec3, Vec2, Vec3, Vec4};
+use crate::color::InputSpace;
+use crate::lights::tangent_frame;
+use crate::principled::Principled;
+use crate::subdivision::Cage;
+use crate::texture::Texture;
+use serde::{Deserialize, Serialize};
+
+// displaced triangles are split into this many rows of smaller ones
+const DISPLACEMENT_LEVEL: usize = 4;
+
+fn widen(v: Vec3) -> DVec3 {
+    DVec3::new(v.x as f64, v.y as f64, v.z as f64)
+}
+
+fn narrow(v: DVec3) -> Vec3 {
+    Vec3::new(v.x as f32, v.y as f32, v.z as f32)
+}
+
+// vertex positions, single precision halves the memory of big scans
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Positions {
+    Single(Vec<Vec3>),
+    Double(Vec<DVec3>),
+}
+
+impl Positions {
+    pub fn len(&self) -> usize {
+        match self {
+            Positions::Single(v) => v.len(),
+            Positions::Double(v) => v.len(),
+        }
+    }
+
+    pub fn get(&self, i: usize) -> DVec3 {
+        match self {
+            Positions::Single(v) => widen(v[i]),
+            Positions::Double(v) => v[i],
+        }
+    }
+
+    pub fn push(&mut self, p: DVec3) {
+        match self {
+            Positions::Single(v) => v.push(narrow(p)),
+            Positions::Double(v) => v.push(p),
+        }
+    }
+}
+
+// indexed triangle mesh, every vertex is stored once and shared by the triangles around it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MeshData {
+    pub positions: Positions,
+    // one per vertex
+    pub normals: Vec<Vec3>,
+    // one per vertex, or empty for meshes without a uv layout
+    pub uvs: Vec<Vec2>,
+    // along increasing u with the bitangent sign in w, empty until generate_tangents
+    pub tangents: Vec<Vec4>,
+    // linear rgb per vertex, empty for meshes without vertex colors
+    pub colors: Vec<Vec3>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl MeshData {
+    pub fn new(single_precision: bool) -> MeshData {
+        MeshData {
+            positions: if single_precision {Positions::Single(vec![])} else {Positions::Double(vec![])},
+            normals: vec![],
+            uvs: vec![],
+            tangents: vec![],
+            colors: vec![],
+            indices: vec![]
+        }
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    // uv has to be given for every vertex or for none
+    pub fn push_vertex(&mut self, p: DVec3, n: DVec3, uv: Option<DVec2>) -> u32 {
+        self.positions.push(p);
+        self.normals.push(narrow(n));
+        if let Some(uv) = uv {
+            self.uvs.push(Vec2::new(uv.x as f32, uv.y as f32));
+        }
+        (self.positions.len() - 1) as u32
+    }
+
+    pub fn position(&self, i: u32) -> DVec3 {
+        self.positions.get(i as usize)
+    }
+
+    pub fn normal(&self, i: u32) -> DVec3 {
+        widen(self.normals[i as usize])
+    }
+
+    pub fn uv(&self, i: u32) -> DVec2 {
+        self.uvs.get(i as usize).map_or(DVec2::zero(), |uv| DVec2::new(uv.x as f64, uv.y as f64))
+    }
+
+    pub fn color(&self, i: u32) -> DVec3 {
+        self.colors.get(i as usize).map_or(DVec3::one(), |c| widen(*c))
+    }
+
+    // tangent and bitangent sign
+    pub fn tangent(&self, i: u32) -> Option<(DVec3, f64)> {
+        let t = self.tangents.get(i as usize)?;
+        Some((DVec3::new(t.x as f64, t.y as f64, t.z as f64), t.w as f64))
+    }
+
+    pub fn triangle(&self, t: usize) -> [DVec3; 3] {
+        self.indices[t].map(|i| self.position(i))
+    }
+
+    pub fn face_normal(&self, t: usize) -> DVec3 {
+        let [p0, p1, p2] = self.triangle(t);
+        (p1 - p0).cross(p2 - p0)
+    }
+
+    // moves every vertex, normals are kept so only rigid moves and uniform scales are right
+    pub fn map_positions(&mut self, f: impl Fn(DVec3) -> DVec3) {
+        match &mut self.positions {
+            Positions::Single(v) => v.iter_mut().for_each(|p| *p = narrow(f(widen(*p)))),
+            Positions::Double(v) => v.iter_mut().for_each(|p| *p = f(*p)),
+        }
+    }
+
+    pub fn append(&mut self, other: &MeshData) {
+        let offset = self.vertex_count() as u32;
+        for i in 0..other.vertex_count() as u32 {
+            self.push_vertex(other.position(i), other.normal(i), (!other.uvs.is_empty()).then(|| other.uv(i)));
+        }
+        self.tangents.extend(&other.tangents);
+        if self.tangents.len() != self.vertex_count() {
+            self.tangents.clear();
+        }
+        self.colors.extend(&other.colors);
+        if self.colors.len() != self.vertex_count() {
+            self.colors.clear();
+        }
+        self.indices.extend(other.indices.iter().map(|tri| tri.map(|i| i + offset)));
+    }
+}
+
+// one usemtl group of a file or one primitive of a gltf scene, with its material mapped onto
+// the principled bsdf
+pub struct MeshGroup {
+    pub data: MeshData,
+    pub mat: Principled,
+    // the maps and bump height the mesh made from the group should get
+    pub normal_map: Option<Arc<Texture>>,
+    pub bump_map: Option<Arc<Texture>>,
+    pub bump_scale: f64,
+}
+
+impl MeshGroup {
+    // a mesh without maps
+    pub fn new(data: MeshData, mat: Principled) -> MeshGroup {
+        MeshGroup{data, mat, normal_map: None, bump_map: None, bump_scale: 1.0}
+    }
+}
+
+// every group in one mesh, for when the file's materials are replaced
+pub fn merge_groups(groups: Vec<MeshGroup>) -> MeshData {
+    let mut groups = groups.into_iter();
+    let Some(first) = groups.next() else {return MeshData::new(true)};
+    let mut data = first.data;
+    for group in groups {
+        data.append(&group.data);
+    }
+    data
+}
+
+// the map statements obj-rs doesn't read, with the -bm multiplier of the bump and displacement maps
+#[derive(Default)]
+struct MtlMaps {
+    bump: Option<(String, f64)>,
+    normal: Option<String>,
+    displacement: Option<(String, f64)>,
+}
+
+// takes the bump, normal and displacement statements out of an mtl file, obj-rs fails on their
+// options and on norm and disp. the file name is the last argument
+fn split_maps(text: &str) -> (String, HashMap<String, MtlMaps>) {
+    let mut rest = String::new();
+    let mut maps: HashMap<String, MtlMaps> = HashMap::new();
+    let mut name = String::new();
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let scale = words.iter().position(|&w| w == "-bm")
+            .and_then(|i| words.get(i + 1)?.parse().ok())
+            .unwrap_or(1.0);
+        match (words.first(), words.last()) {
+            (Some(&"newmtl"), Some(mtl)) => name = mtl.to_string(),
+            (Some(&("map_Bump" | "map_bump" | "bump")), Some(file)) if words.len() > 1 => {
+                maps.entry(name.clone()).or_default().bump = Some((file.to_string(), scale));
+                continue;
+            }
+            (Some(&"norm"), Some(file)) if words.len() > 1 => {
+                maps.entry(name.clone()).or_default().normal = Some(file.to_string());
+                continue;
+            }
+            (Some(&"disp"), Some(file)) if words.len() > 1 => {
+                maps.entry(name.clone()).or_default().displacement = Some((file.to_string(), scale));
+                continue;
+            }
+            _ => {}
+        }
+        rest.push_str(line);
+        rest.push('\n');
+    }
+    (rest, maps)
+}
+
+// the materials of an obj's mtl libraries, maps are found next to the obj
+struct MtlLibrary {
+    materials: HashMap<String, MtlMaterial>,
+    maps: HashMap<String, MtlMaps>,
+    dir: PathBuf,
+}
+
+impl MtlLibrary {
+    fn texture(&self, file: &str) -> Result<Arc<Texture>, String> {
+        Ok(Arc::new(Texture::load(&self.dir.join(file).to_string_lossy(), InputSpace::Linear)?))
+    }
+
+    // the group's material and maps, displacement maps are applied to the mesh right away
+    fn group(&self, name: &str, data: MeshData) -> Result<MeshGroup, String> {
+        let mat = self.materials.get(name).map_or(Principled::default(), Principled::from_mtl);
+        let Some(maps) = self.maps.get(name) else {
+            return Ok(MeshGroup::new(data, mat));
+        };
+        let data = match &maps.displacement {
+            Some((file, scale)) => displace(&data, &*self.texture(file)?, *scale, DISPLACEMENT_LEVEL),
+            None => data,
         };
-        tri_list.push(tri);
+        let normal_map = maps.normal.as_deref().map(|file| self.texture(file)).transpose()?;
+        let bump_map = maps.bump.as_ref().map(|(file, _)| self.texture(file)).transpose()?;
+        let bump_scale = maps.bump.as_ref().map_or(1.0, |(_, scale)| *scale);
+        Ok(MeshGroup{data, mat, normal_map, bump_map, bump_scale})
+    }
+}
+
+// the parsed file and the mtl libraries it names, missing libraries are skipped
+fn read_obj(filename: &str) -> Result<(RawObj, MtlLibrary), String> {
+    let file = File::open(filename).map_err(|e| format!("couldn't open {}: {}", filename, e))?;
+    let raw = parse_obj(BufReader::new(file)).map_err(|e| format!("couldn't load {}: {}", filename, e))?;
+
+    let dir = Path::new(filename).parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut library = MtlLibrary{materials: HashMap::new(), maps: HashMap::new(), dir};
+    for lib in &raw.material_libraries {
+        let Ok(text) = std::fs::read_to_string(library.dir.join(lib)) else {continue};
+        let (text, maps) = split_maps(&text);
+        let mtl = parse_mtl(text.as_bytes()).map_err(|e| format!("couldn't load {}: {}", lib, e))?;
+        library.materials.extend(mtl.materials);
+        library.maps.extend(maps);
     }
-    tri_list
-}
\ No newline at end of file
+    Ok((raw, library))
+}
+
+// usemtl groups sorted by name with the polygons in them, faces before the first usemtl have no name
+fn polygon_groups(raw: &RawObj) -> Vec<(&String, Vec<usize>)> {
+    let mut groups: Vec<(&String, Vec<usize>)> = raw.meshes.iter()
+        .map(|(name, group)| (name, group.polygons.iter().flat_map(|range| range.start..range.end).collect()))
+        .collect();
+    groups.sort_by_key(|(name, _)| *name);
+    groups
+}
+
+fn corners(polygon: &Polygon) -> Vec<(usize, Option<usize>, Option<usize>)> {
+    match polygon {
+        Polygon::P(v) => v.iter().map(|&p| (p, None, None)).collect(),
+        Polygon::PT(v) => v.iter().map(|&(p, t)| (p, Some(t), None)).collect(),
+        Polygon::PN(v) => v.iter().map(|&(p, n)| (p, None, Some(n))).collect(),
+        Polygon::PTN(v) => v.iter().map(|&(p, t, n)| (p, Some(t), Some(n))).collect(),
+    }
+}
+
+// one mesh per usemtl group, each with its mtl material. polygons are fan triangulated and faces
+// without normals get their flat normal
+pub fn load_mesh(filename: &str) -> Result<Vec<MeshGroup>, String> {
+    let (raw, library) = read_obj(filename)?;
+
+    let position = |i: usize| {
+        let p = raw.positions[i];
+        DVec3::new(p.0 as f64, p.1 as f64, p.2 as f64)
+    };
+    let normal = |i: usize| {
+        let n = raw.normals[i];
+        DVec3::new(n.0 as f64, n.1 as f64, n.2 as f64)
+    };
+    let tex_coord = |i: usize| {
+        let t = raw.tex_coords[i];
+        DVec2::new(t.0 as f64, t.1 as f64)
+    };
+    let has_uvs = !raw.tex_coords.is_empty();
+
+    let mut meshes = vec![];
+    for (name, polygons) in polygon_groups(&raw) {
+        let mut data = MeshData::new(true);
+        // corners without a normal are keyed by their polygon so flat faces don't share vertices
+        let mut vertices = HashMap::new();
+        for face in polygons {
+            let corners = corners(&raw.polygons[face]);
+            if corners.len() < 3 {continue};
+            let flat = (1..corners.len() - 1).fold(DVec3::zero(), |sum, k| {
+                let a = position(corners[0].0);
+                sum + (position(corners[k].0) - a).cross(position(corners[k + 1].0) - a)
+            }).normalized();
+            let index: Vec<u32> = corners.iter().map(|&(p, t, n)| {
+                let key = (p, t, n.ok_or(face));
+                *vertices.entry(key).or_insert_with(|| data.push_vertex(
+                    position(p),
+                    n.map_or(flat, normal),
+                    has_uvs.then(|| t.map_or(DVec2::zero(), tex_coord))
+                ))
+            }).collect();
+            for k in 1..index.len() - 1 {
+                data.indices.push([index[0], index[k], index[k + 1]]);
+            }
+        }
+        if data.indices.is_empty() {continue};
+        generate_tangents(&mut data);
+        meshes.push(library.group(name, data)?);
+    }
+    Ok(meshes)
+}
+
+// every face of the file untriangulated, for subdivision, tagged with its place in
+// polygon_groups. edges where the file splits the normals become hard creases
+fn load_cage(raw: &RawObj) -> Cage {
+    let mut cage = Cage{
+        positions: raw.positions.iter().map(|p| DVec3::new(p.0 as f64, p.1 as f64, p.2 as f64)).collect(),
+        ..Cage::default()
+    };
+    let has_uvs = !raw.tex_coords.is_empty();
+    // normal indices at both ends of every edge, as the first face using it saw them
+    let mut edge_normals: HashMap<(u32, u32), (usize, usize)> = HashMap::new();
+    for (group, (_, polygons)) in polygon_groups(raw).into_iter().enumerate() {
+        for face in polygons {
+            let corners = corners(&raw.polygons[face]);
+            if corners.len() < 3 {continue};
+            for (i, &(a, _, na)) in corners.iter().enumerate() {
+                let (b, _, nb) = corners[(i + 1) % corners.len()];
+                let (Some(na), Some(nb)) = (na, nb) else {continue};
+                let (key, normals) = if a < b {((a as u32, b as u32), (na, nb))} else {((b as u32, a as u32), (nb, na))};
+                match edge_normals.get(&key) {
+                    Some(&seen) if seen != normals => cage.crease(key.0, key.1, f64::INFINITY),
+                    Some(_) => {}
+                    None => {edge_normals.insert(key, normals);}
+                }
+            }
+            cage.faces.push(corners.iter().map(|c| c.0 as u32).collect());
+            cage.groups.push(group as u32);
+            if has_uvs {
+                cage.uvs.push(corners.iter().map(|c| c.1.map_or(DVec2::zero(), |t| {
+                    let t = raw.tex_coords[t];
+                    DVec2::new(t.0 as f64, t.1 as f64)
+                })).collect());
+            }
+        }
+    }
+    cage
+}
+
+// the whole file subdivided levels times, catmull-clark for quad meshes and loop for triangle
+// meshes, then split into its usemtl groups
+pub fn load_mesh_subdivided(filename: &str, levels: usize) -> Result<Vec<MeshGroup>, String> {
+    let (raw, library) = read_obj(filename)?;
+    let meshes = load_cage(&raw).subdivide(levels);
+    meshes.into_iter().zip(polygon_groups(&raw))
+        .filter(|(data, _)| !data.indices.is_empty())
+        .map(|(data, (name, _))| library.group(name, data))
+        .collect()
+}
+
+// per vertex tangents from the uv layout, averaged over the triangles sharing the vertex and made
+// orthogonal to its normal. meshes without uvs get none
+pub fn generate_tangents(data: &mut MeshData) {
+    if data.uvs.is_empty() {
+        data.tangents.clear();
+        return;
+    }
+    let mut tangents = vec![DVec3::zero(); data.vertex_count()];
+    let mut bitangents = vec![DVec3::zero(); data.vertex_count()];
+    for tri in &data.indices {
+        let [p0, p1, p2] = tri.map(|i| data.position(i));
+        let [uv0, uv1, uv2] = tri.map(|i| data.uv(i));
+        let (dp1, dp2) = (p1 - p0, p2 - p0);
+        let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+        let det = duv1.x * duv2.y - duv1.y * duv2.x;
+        // without a usable uv layout any tangent will do
+        if det.abs() < 1e-12 {continue};
+        let tangent = (dp1 * duv2.y - dp2 * duv1.y) / det;
+        let bitangent = (dp2 * duv1.x - dp1 * duv2.x) / det;
+        for &i in tri {
+            tangents[i as usize] += tangent;
+            bitangents[i as usize] += bitangent;
+        }
+    }
+
+    data.tangents = (0..data.vertex_count()).map(|i| {
+        let n = data.normal(i as u32);
+        let t = tangents[i] - n * n.dot(tangents[i]);
+        let t = if t.mag_sq() > 1e-20 {t.normalized()} else {tangent_frame(n).0};
+        let sign = if n.cross(t).dot(bitangents[i]) < 0.0 {-1.0} else {1.0};
+        Vec4::new(t.x as f32, t.y as f32, t.z as f32, sign)
+    }).collect();
+}
+
+pub fn position_key(p: DVec3) -> [u64; 3] {
+    [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]
+}
+
+// area weighted average of the face normals around every position, vertices split along uv
+// seams share the same sum
+pub fn smooth_normals(data: &mut MeshData) {
+    let mut sums: HashMap<[u64; 3], DVec3> = HashMap::new();
+    for t in 0..data.triangle_count() {
+        let face = data.face_normal(t);
+        for i in data.indices[t] {
+            *sums.entry(position_key(data.position(i))).or_insert(DVec3::zero()) += face;
+        }
+    }
+    for i in 0..data.vertex_count() {
+        let n = sums.get(&position_key(data.position(i as u32))).copied().unwrap_or(DVec3::zero());
+        data.normals[i] = if n.mag_sq() > 0.0 {narrow(n.normalized())} else {Vec3::unit_z()};
+    }
+}
+
+// splits every triangle into level * level smaller ones and moves the new vertices along the
+// interpolated normal by scale times the map's height. points on shared edges are shared too,
+// cracks only open where hard edges or uv seams split the original vertices
+pub fn displace(data: &MeshData, map: &Texture, scale: f64, level: usize) -> MeshData {
+    let level = level.max(1);
+    let mut out = MeshData::new(matches!(data.positions, Positions::Single(_)));
+    // a new vertex is named by the original vertices it blends and their weights in 1/level steps
+    let mut vertices: HashMap<[(u32, usize); 3], u32> = HashMap::new();
+    for tri in &data.indices {
+        let mut vertex = |i: usize, j: usize| {
+            let mut key = [(tri[0], level - i - j), (tri[1], i), (tri[2], j)].map(|(v, w)| if w == 0 {(u32::MAX, 0)} else {(v, w)});
+            key.sort();
+            *vertices.entry(key).or_insert_with(|| {
+                let (b1, b2) = (i as f64 / level as f64, j as f64 / level as f64);
+                let b0 = 1.0 - b1 - b2;
+                let uv = data.uv(tri[0]) * b0 + data.uv(tri[1]) * b1 + data.uv(tri[2]) * b2;
+                let n = (data.normal(tri[0]) * b0 + data.normal(tri[1]) * b1 + data.normal(tri[2]) * b2).normalized();
+                let p = data.position(tri[0]) * b0 + data.position(tri[1]) * b1 + data.position(tri[2]) * b2;
+                if !data.colors.is_empty() {
+                    out.colors.push(narrow(data.color(tri[0]) * b0 + data.color(tri[1]) * b1 + data.color(tri[2]) * b2));
+                }
+                out.push_vertex(p + n * scale * map.height_at(uv), n, (!data.uvs.is_empty()).then_some(uv))
+            })
+        };
+        let mut new_tris = vec![];
+        for i in 0..level {
+            for j in 0..level - i {
+                new_tris.push([vertex(i, j), vertex(i + 1, j), vertex(i, j + 1)]);
+                if i + j + 1 < level {
+                    new_tris.push([vertex(i + 1, j), vertex(i + 1, j + 1), vertex(i, j + 1)]);
+                }
+            }
+        }
+        out.indices.extend(new_tris);
+    }
+    smooth_normals(&mut out);
+    generate_tangents(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_statements_are_split_from_the_mtl() {
+        let text = "newmtl skin\nKd 0.8 0.6 0.5\nmap_Bump -bm 0.02 pores.png\nnorm skin_n.png\n\
+            newmtl rock\ndisp -mm 0 1 -bm 0.1 rock_h.png\nbump rock_b.png\n";
+        let (rest, maps) = split_maps(text);
+        assert_eq!(rest, "newmtl skin\nKd 0.8 0.6 0.5\nnewmtl rock\n");
+        let skin = &maps["skin"];
+        assert_eq!(skin.bump, Some(("pores.png".to_string(), 0.02)));
+        assert_eq!(skin.normal.as_deref(), Some("skin_n.png"));
+        assert!(skin.displacement.is_none());
+        let rock = &maps["rock"];
+        assert_eq!(rock.displacement, Some(("rock_h.png".to_string(), 0.1)));
+        assert_eq!(rock.bump, Some(("rock_b.png".to_string(), 1.0)));
+        // what's left has to get through obj-rs
+        let mtl = parse_mtl(rest.as_bytes()).unwrap();
+        assert_eq!(mtl.materials.len(), 2);
+    }
+
+    #[test]
+    fn displacement_moves_vertices_along_the_normal() {
+        let mut data = MeshData::new(false);
+        for (p, uv) in [(DVec3::zero(), DVec2::zero()), (DVec3::unit_x(), DVec2::unit_x()), (DVec3::unit_y(), DVec2::unit_y())] {
+            data.push_vertex(p, DVec3::unit_z(), Some(uv));
+        }
+        data.indices.push([0, 1, 2]);
+        let flat = Texture{width: 1, height: 1, data: vec![DVec3::one()], average: DVec3::one()};
+        let out = displace(&data, &flat, 0.5, 3);
+        assert_eq!(out.triangle_count(), 9);
+        assert_eq!(out.vertex_count(), 10);
+        for i in 0..out.vertex_count() as u32 {
+            assert!((out.position(i).z - 0.5).abs() < 1e-6);
+            assert!((out.normal(i) - DVec3::unit_z()).mag() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::f64::consts::PI;
+use std::thread;
+
+use ndarray::Array3;
+use ultraviolet::DVec3;
+use crate::camera::{Camera, CameraConfig, THREAD_COUNT, unit_disk_samp};
+use crate::raytracing::{HittableList, Ray, RayHit, direct_light, environment_light, emitters, get_world_hit, punctual_light, punctual_lights, sample_emitter, square_samp};
+
+// fraction of new photons kept each pass, controls how fast the radius shrinks
+const ALPHA: f64 = 2.0 / 3.0;
+// starting gather radius in pixel footprints at the visible point
+const INITIAL_RADIUS_PIXELS: f64 = 4.0;
+
+#[derive(Clone, Copy)]
+struct Photon {
+    p: DVec3,
+    // towards where the photon came from
+    wi: DVec3,
+    beta: DVec3,
+}
+
+// balanced kd-tree stored in place, the median of every slice is its node
+struct KdTree {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,
+}
+
+fn build_node(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.len() <= 1 {return};
+    let mut min = DVec3::broadcast(f64::INFINITY);
+    let mut max = DVec3::broadcast(-f64::INFINITY);
+    for photon in photons.iter() {
+        min = min.min_by_component(photon.p);
+        max = max.max_by_component(photon.p);
+    }
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z {0} else if extent.y >= extent.z {1} else {2};
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.p[axis].total_cmp(&b.p[axis]));
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build_node(left, left_axes);
+    build_node(&mut right[1..], &mut right_axes[1..]);
+}
+
+impl KdTree {
+    fn new(mut photons: Vec<Photon>) -> KdTree {
+        let mut axes = vec![0; photons.len()];
+        build_node(&mut photons, &mut axes);
+        KdTree{photons, axes}
+    }
+
+    fn for_each_within(&self, p: DVec3, radius: f64, f: &mut impl FnMut(&Photon)) {
+        self.search(0, self.photons.len(), p, radius, f);
+    }
+
+    fn search(&self, lo: usize, hi: usize, p: DVec3, radius: f64, f: &mut impl FnMut(&Photon)) {
+        if lo >= hi {return};
+        let mid = lo + (hi - lo) / 2;
+        let photon = &self.photons[mid];
+        if (photon.p - p).mag_sq() <= radius * radius {
+            f(photon);
+        }
+        if hi - lo == 1 {return};
+        let axis = self.axes[mid];
+        let d = p[axis] - photon.p[axis];
+        if d <= radius {
+            self.search(lo, mid, p, radius, f);
+        }
+        if d >= -radius {
+            self.search(mid + 1, hi, p, radius, f);
+        }
+    }
+}
+
+// progressive state of one pixel
+#[derive(Clone, Copy, Default)]
+struct PixelState {
+    radius: f64,
+    photon_count: f64,
+    // accumulated flux inside the radius
+    tau: DVec3,
+    // sum of the radiance not carried by photons: emitters seen directly, direct light and sky
+    direct: DVec3,
+    estimate: DVec3,
+}
+
+impl PixelState {
+    // adds the photons within the radius and shrinks it so that only ALPHA of them are kept
+    fn gather(&mut self, tree: &KdTree, vp: &VisiblePoint) {
+        let mut phi = DVec3::zero();
+        let mut found = 0.0;
+        tree.for_each_within(vp.rec.hit_point, self.radius, &mut |photon| {
+            if photon.wi.dot(vp.rec.normal) <= 0.0 {return};
+            phi += photon.beta * vp.rec.mat.eval(&vp.ray, &vp.rec, photon.wi);
+            found += 1.0;
+        });
+        if found == 0.0 {return};
+        let count = self.photon_count + ALPHA * found;
+        let radius = self.radius * (count / (self.photon_count + found)).sqrt();
+        self.tau = (self.tau + vp.ray.color * phi) * (radius * radius) / (self.radius * self.radius);
+        self.photon_count = count;
+        self.radius = radius;
+    }
+}
+
+// first non specular surface a camera ray lands on
+struct VisiblePoint {
+    ray: Ray,
+    rec: RayHit,
+}
+
+// stochastic progressive photon mapping. photons carry light from emissive objects after at least one bounce,
+// direct light is sampled and sky light is path traced from the visible points since the sky emits no photons.
+// punctual lights only add direct light. traced in rgb, the spectral setting only applies to the path tracers
+pub struct Sppm {
+    width: usize,
+    height: usize,
+    passes: usize,
+    photons_emitted: f64,
+    pixels: Vec<PixelState>,
+}
+
+// light from the sky reaching the visible point over any number of bounces, emitters are left to the photons
+fn sky_light(world: &HittableList, vp: &VisiblePoint, max_depth: usize) -> DVec3 {
+    let Some(mut ray) = vp.rec.mat.scatter(&vp.ray, &vp.rec) else {return DVec3::zero()};
+    for _ in 0..max_depth {
+        let Some(rec) = get_world_hit(&ray, 0.0, f64::INFINITY, world) else {
+            return ray.color * environment_light(ray);
+        };
+        if rec.mat.is_emissive() {break};
+        let Some(scattered) = rec.mat.scatter(&ray, &rec) else {break};
+        ray = scattered;
+    }
+    DVec3::zero()
+}
+
+// follows specular bounces from the camera until a surface photons can be gathered on,
+// returns it with the radiance found on the way
+fn trace_camera(world: &HittableList, lights: &[usize], punctual: &[usize], mut ray: Ray, max_depth: usize) -> (Option<VisiblePoint>, DVec3) {
+    let mut l = DVec3::zero();
+    for _ in 0..=max_depth {
+        let Some(rec) = get_world_hit(&ray, 0.0, f64::INFINITY, world) else {
+            return (None, l + ray.color * environment_light(ray));
+        };
+        if rec.mat.is_emissive() {
+            return (None, l + ray.color * rec.mat.emitted(&ray, &rec));
+        }
+        if !rec.mat.is_delta() {
+            let vp = VisiblePoint{ray, rec};
+            let direct = direct_light(world, lights, &vp.ray, &vp.rec) + punctual_light(world, punctual, &vp.ray, &vp.rec);
+            l += vp.ray.color * direct + sky_light(world, &vp, max_depth);
+            return (Some(vp), l);
+        }
+        let Some(scattered) = rec.mat.scatter(&ray, &rec) else {break};
+        ray = scattered;
+    }
+    (None, l)
+}
+
+fn trace_photons(world: &HittableList, lights: &[usize], count: usize, max_depth: usize) -> Vec<Photon> {
+    let mut photons = Vec::new();
+    for _ in 0..count {
+        let Some(light) = sample_emitter(world, lights) else {break};
+        let (dir, pdf_dir) = light.sample_direction();
+        if pdf_dir <= 0.0 {continue};
+        let le = light.emitted(dir, None);
+        if le == DVec3::zero() {continue};
+        let mut ray = Ray::new(light.spawn_point(dir), dir, le * dir.dot(light.n).abs() / (light.pdf_pos * pdf_dir));
+        for depth in 0..max_depth {
+            let Some(rec) = get_world_hit(&ray, 0.0, f64::INFINITY, world) else {break};
+            if rec.mat.is_emissive() {break};
+            // direct light is sampled explicitly, so only bounced photons are stored
+            if depth > 0 && !rec.mat.is_delta() {
+                photons.push(Photon{p: rec.hit_point, wi: -ray.direction.normalized(), beta: ray.color});
+            }
+            let Some(scattered) = rec.mat.scatter(&ray, &rec) else {break};
+            ray = scattered;
+        }
+    }
+    photons
+}
+
+impl Sppm {
+    pub fn new(camera: &Camera) -> Sppm {
+        let (width, height) = (camera.width as usize, camera.height as usize);
+        Sppm{width, height, passes: 0, photons_emitted: 0.0, pixels: vec![PixelState::default(); width * height]}
+    }
+
+    pub fn matches(&self, camera: &Camera) -> bool {
+        self.width == camera.width as usize && self.height == camera.height as usize
+    }
+
+    // runs one camera pass and one photon pass. returns what has to be added to the running sum
+    // of passes so that sum / passes is the current estimate
+    pub fn render_pass(&mut self, camera: &Camera, world: &HittableList, config: &CameraConfig) -> Array3<f64> {
+        let width = self.width;
+        let max_depth = camera.max_depth.max(1) as usize;
+        let photon_count = width * self.height * camera.samples.max(1) as usize;
+        let lights = emitters(world);
+        let punctual = punctual_lights(world);
+        let pixel_size = config.pixel_delta_u.mag();
+        let focus_dist = (config.pixel_zero_loc - config.camera_center).mag();
+
+        // photon pass
+        let per_thread = photon_count.div_ceil(THREAD_COUNT as usize);
+        let photons = thread::scope(|scope| {
+            let handles: Vec<_> = (0..THREAD_COUNT as usize).map(|thread_no| {
+                let lights = &lights;
+                scope.spawn(move || {
+                    fastrand::seed(thread_no as u64 + fastrand::u64(..));
+                    trace_photons(world, lights, per_thread, max_depth)
+                })
+            }).collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect::<Vec<_>>()
+        });
+        let tree = KdTree::new(photons);
+        self.photons_emitted += (per_thread * THREAD_COUNT as usize) as f64;
+        self.passes += 1;
+        let passes = self.passes as f64;
+        let photons_emitted = self.photons_emitted;
+
+        // camera pass, gathering photons around each visible point and shrinking its radius
+        let chunk_len = (self.height.div_ceil(THREAD_COUNT as usize) * width).max(1);
+        let added: Vec<DVec3> = thread::scope(|scope| {
+            let handles: Vec<_> = self.pixels.chunks_mut(chunk_len).enumerate().map(|(chunk_idx, chunk)| {
+                let (lights, punctual, tree) = (&lights, &punctual, &tree);
+                scope.spawn(move || {
+                    fastrand::seed(chunk_idx as u64 + fastrand::u64(..));
+                    chunk.iter_mut().enumerate().map(|(k, pixel)| {
+                        let idx = chunk_idx * chunk_len + k;
+                        let (i, j) = (idx % width, idx / width);
+                        let pixel_center = config.pixel_zero_loc + (i as f64 * config.pixel_delta_u) + (j as f64 * config.pixel_delta_v);
+                        let p = unit_disk_samp();
+                        let disk_sample = config.camera_center + (p.x * config.defocus_disk_u) + (p.y * config.defocus_disk_v);
+                        let ray_origin = if config.defocus_angle <= 0.0 {config.camera_center} else {disk_sample};
+                        let r = Ray::new(ray_origin, pixel_center + square_samp(config.pixel_delta_u, config.pixel_delta_v) - ray_origin, DVec3::one());
+
+                        let (vp, l) = trace_camera(world, lights, punctual, r, max_depth);
+                        pixel.direct += l;
+                        if let Some(vp) = vp {
+                            if pixel.radius == 0.0 {
+                                let dist = (vp.rec.hit_point - config.camera_center).mag();
+                                pixel.radius = INITIAL_RADIUS_PIXELS * pixel_size * dist / focus_dist;
+                            }
+                            pixel.gather(tree, &vp);
+                        }
+
+                        let mut estimate = pixel.direct / passes;
+                        if pixel.radius > 0.0 {
+                            estimate += pixel.tau / (photons_emitted * PI * pixel.radius * pixel.radius);
+                        }
+                        let added = estimate * passes - pixel.estimate * (passes - 1.0);
+                        pixel.estimate = estimate;
+                        added
+                    }).collect::<Vec<_>>()
+                })
+            }).collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+
+        let mut img: Array3<f64> = Array3::zeros((self.height, width, 3));
+        for (idx, c) in added.iter().enumerate() {
+            let (i, j) = (idx % width, idx / width);
+            img[(j, i, 0)] = c.x;
+            img[(j, i, 1)] = c.y;
+            img[(j, i, 2)] = c.z;
+        }
+        img
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Lambertian;
+    use ultraviolet::DVec2;
+
+    fn random_point(scale: f64) -> DVec3 {
+        DVec3::new(fastrand::f64() - 0.5, fastrand::f64() - 0.5, fastrand::f64() - 0.5) * scale
+    }
+
+    // photons numbered through their beta
+    fn numbered(points: &[DVec3]) -> Vec<Photon> {
+        points.iter().enumerate().map(|(i, p)| Photon{p: *p, wi: DVec3::unit_y(), beta: DVec3::broadcast(i as f64)}).collect()
+    }
+
+    #[test]
+    fn radius_query_matches_brute_force() {
+        fastrand::seed(5);
+        let mut points: Vec<DVec3> = (0..500).map(|_| random_point(10.0)).collect();
+        // stacked photons and ones on a plane, like photons landing on a floor
+        points.extend(std::iter::repeat_n(DVec3::new(1.0, 2.0, 3.0), 20));
+        points.extend((0..100).map(|_| random_point(10.0) * DVec3::new(1.0, 0.0, 1.0)));
+        let tree = KdTree::new(numbered(&points));
+        for k in 0..200 {
+            let p = if k % 10 == 0 {DVec3::new(1.0, 2.0, 3.0)} else {random_point(12.0)};
+            let radius = fastrand::f64() * 3.0;
+            let mut found = vec![];
+            tree.for_each_within(p, radius, &mut |photon| found.push(photon.beta.x as usize));
+            found.sort();
+            let expected: Vec<usize> = (0..points.len()).filter(|i| (points[*i] - p).mag_sq() <= radius * radius).collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn gather_keeps_alpha_of_the_new_photons() {
+        let rec = RayHit{
+            hit_point: DVec3::zero(), normal: DVec3::unit_y(), geometric_normal: DVec3::unit_y(),
+            mat: Box::new(Lambertian{albedo: DVec3::one()}), hit_time: 1.0, front: true,
+            uv: DVec2::zero(), tangent: DVec3::unit_x(), color: DVec3::one(), error: DVec3::zero()
+        };
+        let vp = VisiblePoint{ray: Ray::new(DVec3::unit_y(), -DVec3::unit_y(), DVec3::one()), rec};
+        let mut points: Vec<DVec3> = (0..30).map(|_| random_point(0.5) * DVec3::new(1.0, 0.0, 1.0)).collect();
+        // outside the radius
+        points.push(DVec3::new(2.0, 0.0, 0.0));
+        let tree = KdTree::new(numbered(&points));
+        let mut state = PixelState{radius: 1.0, photon_count: 10.0, ..PixelState::default()};
+        state.gather(&tree, &vp);
+        assert!((state.photon_count - (10.0 + ALPHA * 30.0)).abs() < 1e-9);
+        let shrink = (10.0 + ALPHA * 30.0) / 40.0;
+        assert!((state.radius * state.radius - shrink).abs() < 1e-9);
+        // the flux found so far is scaled down with the area
+        let flux = (0..30).map(|i| i as f64).sum::<f64>() / PI;
+        assert!((state.tau - DVec3::broadcast(flux * shrink)).mag() < 1e-9);
+        // nothing found leaves the radius alone
+        let empty = KdTree::new(vec![]);
+        state.gather(&empty, &vp);
+        assert!((state.radius * state.radius - shrink).abs() < 1e-9);
+    }
+}
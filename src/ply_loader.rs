@@ -0,0 +1,288 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use ultraviolet::{DVec2, DVec3, Vec3};
+use crate::obj_loader::{MeshData, generate_tangents, smooth_normals};
+
+#[derive(Clone, Copy)]
+enum Scalar {
+    I8, U8, I16, U16, I32, U32, F32, F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar, String> {
+        Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(format!("unknown ply type {}", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        }
+    }
+
+    // full scale of integer color channels
+    fn color_scale(self) -> f64 {
+        match self {
+            Scalar::U8 | Scalar::I8 => 255.0,
+            Scalar::U16 | Scalar::I16 => 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+enum Property {
+    Value(String, Scalar),
+    // count type, item type
+    List(String, Scalar, Scalar),
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+enum Format {
+    Ascii,
+    Binary{big_endian: bool},
+}
+
+// pulls numbers out of the body in whichever encoding the header announced
+struct Body<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: Format,
+}
+
+impl Body<'_> {
+    fn read(&mut self, ty: Scalar) -> Result<f64, String> {
+        match self.format {
+            Format::Ascii => {
+                while self.pos < self.data.len() && self.data[self.pos].is_ascii_whitespace() {
+                    self.pos += 1;
+                }
+                let start = self.pos;
+                while self.pos < self.data.len() && !self.data[self.pos].is_ascii_whitespace() {
+                    self.pos += 1;
+                }
+                let token = std::str::from_utf8(&self.data[start..self.pos]).map_err(|_| "bad ply number".to_string())?;
+                token.parse::<f64>().map_err(|_| format!("bad ply number {:?}", token))
+            }
+            Format::Binary{big_endian} => {
+                let size = ty.size();
+                let Some(bytes) = self.data.get(self.pos..self.pos + size) else {return Err("ply file is truncated".to_string())};
+                self.pos += size;
+                let mut b = [0u8; 8];
+                b[..size].copy_from_slice(bytes);
+                if big_endian {b[..size].reverse()};
+                Ok(match ty {
+                    Scalar::I8 => b[0] as i8 as f64,
+                    Scalar::U8 => b[0] as f64,
+                    Scalar::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    Scalar::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    Scalar::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    Scalar::F64 => f64::from_le_bytes(b),
+                })
+            }
+        }
+    }
+}
+
+// ascii and binary ply. positions, normals, colors and uvs are read from the vertex element and
+// faces are fan triangulated, files without faces give a mesh with no indices for point clouds.
+// missing normals are averaged from the faces
+pub fn load_ply(filename: &str) -> Result<MeshData, String> {
+    let mut data = vec![];
+    BufReader::new(File::open(filename).map_err(|e| format!("couldn't open {}: {}", filename, e))?)
+        .read_to_end(&mut data).map_err(|e| format!("couldn't read {}: {}", filename, e))?;
+    parse_ply(&data, filename)
+}
+
+// the file's bytes, filename is only for the errors
+pub fn parse_ply(data: &[u8], filename: &str) -> Result<MeshData, String> {
+
+    let end = b"end_header";
+    let header_end = data.windows(end.len()).position(|w| w == end).ok_or(format!("{} has no ply header", filename))?;
+    let mut body_start = header_end + end.len();
+    // the header ends with one line break, \r\n on some writers
+    if data.get(body_start) == Some(&b'\r') {body_start += 1};
+    if data.get(body_start) == Some(&b'\n') {body_start += 1};
+    let header = String::from_utf8_lossy(&data[..header_end]);
+
+    let mut lines = header.lines();
+    if lines.next().map(str::trim) != Some("ply") {return Err(format!("{} isn't a ply file", filename))};
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    for line in lines {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["format", "ascii", ..] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", ..] => format = Some(Format::Binary{big_endian: false}),
+            ["format", "binary_big_endian", ..] => format = Some(Format::Binary{big_endian: true}),
+            ["element", name, count] => elements.push(Element{
+                name: name.to_string(),
+                count: count.parse().map_err(|_| format!("bad element count in {}", filename))?,
+                properties: vec![]
+            }),
+            ["property", "list", count, item, name] => {
+                let element = elements.last_mut().ok_or(format!("property before element in {}", filename))?;
+                element.properties.push(Property::List(name.to_string(), Scalar::parse(count)?, Scalar::parse(item)?));
+            }
+            ["property", ty, name] => {
+                let element = elements.last_mut().ok_or(format!("property before element in {}", filename))?;
+                element.properties.push(Property::Value(name.to_string(), Scalar::parse(ty)?));
+            }
+            _ => {}
+        }
+    }
+    let format = format.ok_or(format!("{} has no ply format line", filename))?;
+    let mut body = Body{data: &data[body_start..], pos: 0, format};
+
+    let mut mesh = MeshData::new(true);
+    let mut has_normals = false;
+    for element in &elements {
+        let find = |names: &[&str]| element.properties.iter().position(|p| matches!(p, Property::Value(n, _) if names.contains(&n.as_str())));
+        let (x, y, z) = (find(&["x"]), find(&["y"]), find(&["z"]));
+        let (nx, ny, nz) = (find(&["nx"]), find(&["ny"]), find(&["nz"]));
+        let (red, green, blue) = (find(&["red", "r"]), find(&["green", "g"]), find(&["blue", "b"]));
+        let (u, v) = (find(&["u", "s", "texture_u", "texture_s"]), find(&["v", "t", "texture_v", "texture_t"]));
+        let color_scale = red.map_or(1.0, |i| match element.properties[i] {
+            Property::Value(_, ty) => ty.color_scale(),
+            Property::List(..) => 1.0,
+        });
+        has_normals |= element.name == "vertex" && nx.is_some();
+
+        let mut values = vec![0.0; element.properties.len()];
+        let mut list = vec![];
+        for _ in 0..element.count {
+            for (k, property) in element.properties.iter().enumerate() {
+                match property {
+                    Property::Value(_, ty) => values[k] = body.read(*ty)?,
+                    Property::List(name, count_ty, item_ty) => {
+                        let count = body.read(*count_ty)? as usize;
+                        let items: Result<Vec<f64>, String> = (0..count).map(|_| body.read(*item_ty)).collect();
+                        if name == "vertex_indices" || name == "vertex_index" {list = items?} else {items?;}
+                    }
+                }
+            }
+            let get = |i: Option<usize>| i.map_or(0.0, |i| values[i]);
+            match element.name.as_str() {
+                "vertex" => {
+                    let p = DVec3::new(get(x), get(y), get(z));
+                    let n = DVec3::new(get(nx), get(ny), get(nz));
+                    let uv = (u.is_some() && v.is_some()).then(|| DVec2::new(get(u), get(v)));
+                    mesh.push_vertex(p, n, uv);
+                    if red.is_some() {
+                        let c = DVec3::new(get(red), get(green), get(blue)) / color_scale;
+                        mesh.colors.push(Vec3::new(c.x as f32, c.y as f32, c.z as f32));
+                    }
+                }
+                "face" => {
+                    for k in 1..list.len().saturating_sub(1) {
+                        mesh.indices.push([list[0] as u32, list[k] as u32, list[k + 1] as u32]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let vertices = mesh.vertex_count() as u32;
+    if mesh.indices.iter().any(|tri| tri.iter().any(|&i| i >= vertices)) {
+        return Err(format!("{} has faces with missing vertices", filename));
+    }
+    // points without normals keep zero ones so discs can face the camera
+    if !has_normals && !mesh.indices.is_empty() {smooth_normals(&mut mesh)};
+    generate_tangents(&mut mesh);
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "element vertex 4\nproperty float x\nproperty float y\nproperty float z\n\
+        property uchar red\nproperty uchar green\nproperty uchar blue\n\
+        element face 1\nproperty list uchar int vertex_indices\nend_header\n";
+
+    fn check_quad(mesh: &MeshData) {
+        assert_eq!(mesh.vertex_count(), 4);
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+        assert!((mesh.position(2) - DVec3::new(1.0, 1.0, 0.0)).mag() < 1e-6);
+        assert!((mesh.color(1) - DVec3::new(1.0, 0.0, 0.0)).mag() < 1e-6);
+        // normals come from the faces when the file has none
+        assert!((mesh.normal(3) - DVec3::unit_z()).mag() < 1e-6);
+    }
+
+    const CORNERS: [([f32; 3], [u8; 3]); 4] = [
+        ([0.0, 0.0, 0.0], [0, 0, 0]), ([1.0, 0.0, 0.0], [255, 0, 0]), ([1.0, 1.0, 0.0], [0, 255, 0]), ([0.0, 1.0, 0.0], [0, 0, 255])
+    ];
+
+    #[test]
+    fn ascii_ply() {
+        let mut text = format!("ply\r\nformat ascii 1.0\ncomment made by hand\n{}", HEADER);
+        for (p, c) in CORNERS {
+            text += &format!("{} {} {} {} {} {}\n", p[0], p[1], p[2], c[0], c[1], c[2]);
+        }
+        text += "4 0 1 2 3\n";
+        check_quad(&parse_ply(text.as_bytes(), "quad.ply").unwrap());
+    }
+
+    #[test]
+    fn binary_ply() {
+        for big_endian in [false, true] {
+            let order = if big_endian {"big"} else {"little"};
+            let mut data = format!("ply\nformat binary_{}_endian 1.0\n{}", order, HEADER).into_bytes();
+            let word = |x: [u8; 4]| if big_endian {[x[3], x[2], x[1], x[0]]} else {x};
+            for (p, c) in CORNERS {
+                for x in p {
+                    data.extend(word(x.to_le_bytes()));
+                }
+                data.extend(c);
+            }
+            data.push(4);
+            for i in 0..4i32 {
+                data.extend(word(i.to_le_bytes()));
+            }
+            check_quad(&parse_ply(&data, "quad.ply").unwrap());
+        }
+    }
+
+    #[test]
+    fn points_without_faces() {
+        let text = "ply\nformat ascii 1.0\nelement vertex 2\nproperty double x\nproperty double y\nproperty double z\n\
+            property float nx\nproperty float ny\nproperty float nz\nend_header\n0 0 0 0 1 0\n1 2 3 0 0 1\n";
+        let mesh = parse_ply(text.as_bytes(), "points.ply").unwrap();
+        assert_eq!(mesh.vertex_count(), 2);
+        assert!(mesh.indices.is_empty() && mesh.colors.is_empty());
+        assert!((mesh.position(1) - DVec3::new(1.0, 2.0, 3.0)).mag() < 1e-6);
+        assert!((mesh.normal(0) - DVec3::unit_y()).mag() < 1e-6);
+    }
+
+    #[test]
+    fn broken_ply_files() {
+        assert!(parse_ply(b"not a ply", "x.ply").is_err());
+        assert!(parse_ply(b"ply\nelement vertex 1\nproperty float x\nend_header\n0\n", "x.ply").is_err());
+        // face pointing past the vertices
+        let text = "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\nproperty float z\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n3 0 1 2\n";
+        assert!(parse_ply(text.as_bytes(), "x.ply").is_err());
+        // body cut short
+        let text = "ply\nformat binary_little_endian 1.0\nelement vertex 2\nproperty float x\nend_header\n\0\0\0\0";
+        assert!(parse_ply(text.as_bytes(), "x.ply").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use ultraviolet::{DVec2, DVec3, Vec3};
+use crate::bvh::Bvh;
+use crate::materials::Material;
+use crate::obj_loader::MeshData;
+use crate::raytracing::{BoundingBox, Hittable, Ray, RayHit, gamma};
+use serde::{Deserialize, Serialize};
+
+// spheres look the same from everywhere, discs lie flat along the point normal and turn
+// towards the ray where a point has none
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PointKind {
+    Sphere,
+    Disc,
+}
+
+// every point is drawn as a small shape in its own color, which materials multiply into their base color
+#[derive(Serialize, Deserialize)]
+#[serde(from = "PointCloudRecord")]
+pub struct PointCloud {
+    // kept in the order of the bvh leaves
+    pub points: Vec<Vec3>,
+    // empty for white points
+    pub colors: Vec<Vec3>,
+    // empty, or zero for single points, to face the ray
+    pub normals: Vec<Vec3>,
+    pub radius: f64,
+    pub kind: PointKind,
+    pub mat: Box<dyn Material + Sync + Send>,
+    #[serde(skip_serializing)]
+    pub bvh: Bvh,
+    #[serde(skip_serializing)]
+    pub bounding_box: BoundingBox,
+}
+
+// saved points, the hierarchy is rebuilt on load
+#[derive(Deserialize)]
+struct PointCloudRecord {
+    points: Vec<Vec3>,
+    colors: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    radius: f64,
+    kind: PointKind,
+    mat: Box<dyn Material + Sync + Send>,
+}
+
+impl From<PointCloudRecord> for PointCloud {
+    fn from(record: PointCloudRecord) -> PointCloud {
+        PointCloud::new(record.points, record.colors, record.normals, record.radius, record.kind, record.mat)
+    }
+}
+
+fn widen(v: Vec3) -> DVec3 {
+    DVec3::new(v.x as f64, v.y as f64, v.z as f64)
+}
+
+impl PointCloud {
+    pub fn new(points: Vec<Vec3>, colors: Vec<Vec3>, normals: Vec<Vec3>, radius: f64, kind: PointKind, mat: Box<dyn Material + Sync + Send>) -> PointCloud {
+        let pad = DVec3::broadcast(radius);
+        let bounds = |p: Vec3| BoundingBox{min: widen(p) - pad, max: widen(p) + pad};
+        let (bvh, order) = Bvh::build(points.len(), |i| bounds(points[i]));
+        let reorder = |v: &Vec<Vec3>| if v.len() == points.len() {order.iter().map(|&i| v[i as usize]).collect()} else {vec![]};
+        let (colors, normals) = (reorder(&colors), reorder(&normals));
+        let points: Vec<Vec3> = order.iter().map(|&i| points[i as usize]).collect();
+        let bounding_box = points.iter().fold(BoundingBox::empty(), |b, &p| b.union(&bounds(p)));
+        PointCloud{points, colors, normals, radius, kind, mat, bvh, bounding_box}
+    }
+
+    // the vertices of a loaded mesh, its faces are ignored
+    pub fn from_mesh_data(data: &MeshData, radius: f64, kind: PointKind, mat: Box<dyn Material + Sync + Send>) -> PointCloud {
+        let points = (0..data.vertex_count() as u32).map(|i| {
+            let p = data.position(i);
+            Vec3::new(p.x as f32, p.y as f32, p.z as f32)
+        }).collect();
+        PointCloud::new(points, data.colors.clone(), data.normals.clone(), radius, kind, mat)
+    }
+
+    fn normal(&self, i: usize) -> Option<DVec3> {
+        let n = widen(*self.normals.get(i)?);
+        (n.mag_sq() > 0.0).then(|| n.normalized())
+    }
+
+    // distance along the ray and outward normal
+    fn intersect(&self, i: usize, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<(f64, DVec3)> {
+        let center = widen(self.points[i]);
+        let oc = r.origin - center;
+        match self.kind {
+            PointKind::Sphere => {
+                let a = r.direction.mag_sq();
+                let half_b = oc.dot(r.direction);
+                let c = oc.mag_sq() - self.radius * self.radius;
+                let disc = half_b * half_b - a * c;
+                if disc < 0.0 {return None};
+                let q = -(half_b + disc.sqrt().copysign(half_b));
+                if q == 0.0 {return None};
+                let (t0, t1) = (q / a, c / q);
+                let (near, far) = if t0 < t1 {(t0, t1)} else {(t1, t0)};
+                let t = if ray_tmin < near && near < ray_tmax {near} else if ray_tmin < far && far < ray_tmax {far} else {return None};
+                Some((t, (oc + r.direction * t).normalized()))
+            }
+            PointKind::Disc => {
+                let n = self.normal(i).unwrap_or(-r.direction.normalized());
+                let denom = n.dot(r.direction);
+                if denom.abs() < 1e-12 {return None};
+                let t = -n.dot(oc) / denom;
+                if t <= ray_tmin || ray_tmax <= t {return None};
+                if (oc + r.direction * t).mag_sq() > self.radius * self.radius {return None};
+                Some((t, n))
+            }
+        }
+    }
+}
+
+#[typetag::serde]
+impl Hittable for PointCloud {
+    fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit> {
+        let mut closest: Option<(usize, f64, DVec3)> = None;
+        self.bvh.traverse(r, ray_tmax, |start, count, max_dst| {
+            let mut found = None;
+            for i in start..start + count {
+                let max_dst = found.map_or(max_dst, |(_, t, _)| t);
+                if let Some((t, n)) = self.intersect(i, r, ray_tmin, max_dst) {found = Some((i, t, n))};
+            }
+            let (i, t, n) = found?;
+            closest = Some((i, t, n));
+            Some(t)
+        });
+        let (i, t, outward_normal) = closest?;
+
+        let center = widen(self.points[i]);
+        let p = match self.kind {
+            // reprojected onto the sphere, and back onto the disc's plane
+            PointKind::Sphere => center + outward_normal * self.radius,
+            PointKind::Disc => {
+                let p = r.clone().at(t);
+                p - outward_normal * outward_normal.dot(p - center)
+            }
+        };
+        let mut rec = RayHit{
+            hit_point: p,
+            mat: self.mat.clone(),
+            normal: outward_normal,
+            geometric_normal: outward_normal,
+            hit_time: t,
+            front: true,
+            uv: DVec2::zero(),
+            tangent: DVec3::zero(),
+            color: self.colors.get(i).map_or(DVec3::one(), |&c| widen(c)),
+            error: (p.abs() + center.abs()) * gamma(5) + DVec3::broadcast(self.radius) * gamma(5)
+        };
+        rec.set_face_normal(r, outward_normal);
+        if self.kind == PointKind::Sphere && !rec.front && !self.mat.two_sided() {return None};
+        Some(rec)
+    }
+
+    fn bounding_box_hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> bool {
+        self.bounding_box.hit(r, ray_tmin, ray_tmax).is_some()
+    }
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        Some(self.mat.as_ref())
+    }
+}
@@ -0,0 +1,420 @@
+use std::f64::consts::PI;
+use obj::raw::material::{Material as MtlMaterial, MtlColor};
+use ultraviolet::DVec3;
+use crate::color::{luminance, xyz_to_srgb};
+use crate::lights::tangent_frame;
+use crate::materials::Material;
+use crate::raytracing::{Ray, RayHit};
+use serde::{Deserialize, Serialize};
+
+// disney style layered material, every parameter but ior and emission is in [0, 1]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Principled {
+    pub base_color: DVec3,
+    pub metallic: f64,
+    pub roughness: f64,
+    // dielectric reflectance, 0.5 is an f0 of 4%
+    pub specular: f64,
+    // tints the dielectric reflection towards the base color
+    pub specular_tint: f64,
+    pub sheen: f64,
+    pub sheen_tint: f64,
+    pub clearcoat: f64,
+    pub clearcoat_gloss: f64,
+    pub transmission: f64,
+    pub ior: f64,
+    pub emission: DVec3,
+    // stretches the highlight along the tangent
+    pub anisotropic: f64,
+    // false for cards that should disappear from behind
+    pub two_sided: bool,
+}
+
+impl Default for Principled {
+    fn default() -> Principled {
+        Principled{
+            base_color: DVec3::broadcast(0.8), metallic: 0.0, roughness: 0.5, specular: 0.5, specular_tint: 0.0,
+            sheen: 0.0, sheen_tint: 0.5, clearcoat: 0.0, clearcoat_gloss: 1.0, transmission: 0.0, ior: 1.5,
+            emission: DVec3::zero(), anisotropic: 0.0, two_sided: true,
+        }
+    }
+}
+
+// spectral curves would need the .rfl file, they fall back to a flat grey
+fn mtl_color(c: &MtlColor) -> DVec3 {
+    match c {
+        MtlColor::Rgb(r, g, b) => DVec3::new(*r as f64, *g as f64, *b as f64),
+        MtlColor::Xyz(x, y, z) => xyz_to_srgb() * DVec3::new(*x as f64, *y as f64, *z as f64),
+        MtlColor::Spectral(_, m) => DVec3::broadcast(*m as f64),
+    }
+}
+
+pub fn schlick_weight(cos: f64) -> f64 {
+    (1.0 - cos).clamp(0.0, 1.0).powi(5)
+}
+
+fn lerp(a: DVec3, b: DVec3, t: f64) -> DVec3 {
+    a * (1.0 - t) + b * t
+}
+
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(-1.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {return 1.0};
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let cos_i = cos_i.abs();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+// anisotropic ggx, directions in the shading frame with z along the normal
+pub fn ggx_d(h: DVec3, ax: f64, ay: f64) -> f64 {
+    if h.z <= 0.0 {return 0.0};
+    let e = (h.x / ax).powi(2) + (h.y / ay).powi(2) + h.z * h.z;
+    1.0 / (PI * ax * ay * e * e)
+}
+
+fn ggx_lambda(w: DVec3, ax: f64, ay: f64) -> f64 {
+    if w.z == 0.0 {return 0.0};
+    let tan2_alpha2 = ((w.x * ax).powi(2) + (w.y * ay).powi(2)) / (w.z * w.z);
+    ((1.0 + tan2_alpha2).sqrt() - 1.0) / 2.0
+}
+
+pub fn ggx_g(wo: DVec3, wi: DVec3, ax: f64, ay: f64) -> f64 {
+    1.0 / (1.0 + ggx_lambda(wo, ax, ay) + ggx_lambda(wi, ax, ay))
+}
+
+// microfacet normal with density D(h) cos(h)
+pub fn ggx_sample(ax: f64, ay: f64) -> DVec3 {
+    let u = fastrand::f64();
+    let phi = 2.0 * PI * fastrand::f64();
+    let slope = (u / (1.0 - u)).sqrt();
+    DVec3::new(ax * slope * phi.cos(), ay * slope * phi.sin(), 1.0).normalized()
+}
+
+// burley's gtr1 distribution for the clearcoat
+fn gtr1_d(cos_h: f64, a: f64) -> f64 {
+    let a2 = a * a;
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * cos_h * cos_h))
+}
+
+fn gtr1_sample(a: f64) -> DVec3 {
+    let a2 = a * a;
+    let cos = ((1.0 - a2.powf(1.0 - fastrand::f64())) / (1.0 - a2)).max(0.0).sqrt();
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * fastrand::f64();
+    DVec3::new(sin * phi.cos(), sin * phi.sin(), cos)
+}
+
+// cosine distributed direction in the local hemisphere
+pub fn cosine_sample() -> DVec3 {
+    let phi = 2.0 * PI * fastrand::f64();
+    let r = fastrand::f64().sqrt();
+    DVec3::new(r * phi.cos(), r * phi.sin(), (1.0 - r * r).max(0.0).sqrt())
+}
+
+pub fn reflect(wo: DVec3, h: DVec3) -> DVec3 {
+    2.0 * wo.dot(h) * h - wo
+}
+
+// wo refracted through a microfacet h, eta is the ior behind the surface over the one in front
+fn refract(wo: DVec3, h: DVec3, eta: f64) -> Option<DVec3> {
+    let cos_i = wo.dot(h);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {return None};
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-wo / eta + (cos_i / eta - cos_t) * h)
+}
+
+// one lobe choice per scatter, the probabilities roughly follow each lobe's contribution
+struct Lobes {
+    diffuse: f64,
+    specular: f64,
+    clearcoat: f64,
+    transmission: f64,
+}
+
+// orthonormal shading frame, local directions have z along the normal
+pub struct Frame {
+    pub t: DVec3,
+    pub b: DVec3,
+    pub n: DVec3,
+}
+
+impl Frame {
+    pub fn new(n: DVec3) -> Frame {
+        let (t, b) = tangent_frame(n);
+        Frame{t, b, n}
+    }
+
+    // t follows the given direction projected onto the surface
+    pub fn with_tangent(n: DVec3, tangent: DVec3) -> Frame {
+        let t = tangent - n * n.dot(tangent);
+        if t.mag_sq() < 1e-12 {return Frame::new(n)};
+        let t = t.normalized();
+        Frame{t, b: n.cross(t), n}
+    }
+
+    pub fn to_local(&self, v: DVec3) -> DVec3 {
+        DVec3::new(v.dot(self.t), v.dot(self.b), v.dot(self.n))
+    }
+
+    pub fn to_world(&self, v: DVec3) -> DVec3 {
+        self.t * v.x + self.b * v.y + self.n * v.z
+    }
+}
+
+impl Principled {
+    // maps the glTF metallic roughness model and its transmission, ior and emissive strength extensions
+    pub fn from_gltf(base_color: DVec3, metallic: f64, roughness: f64, emissive: DVec3, transmission: f64, ior: f64) -> Principled {
+        Principled{base_color, metallic, roughness, emission: emissive, transmission, ior, ..Principled::default()}
+    }
+
+    // wavefront materials: Kd is the base color, Ns the phong exponent, d the opacity and Ni the ior
+    pub fn from_mtl(mtl: &MtlMaterial) -> Principled {
+        let default = Principled::default();
+        let base_color = mtl.diffuse.as_ref().map_or(default.base_color, mtl_color);
+        let specular = mtl.specular.as_ref().map_or(default.specular, |c| luminance(mtl_color(c)).clamp(0.0, 1.0));
+        let roughness = mtl.specular_exponent.map_or(default.roughness, |ns| (2.0 / (ns as f64 + 2.0)).sqrt());
+        let transmission = mtl.dissolve.map_or(0.0, |d| 1.0 - d as f64);
+        let ior = mtl.optical_density.map_or(default.ior, |ni| if ni > 0.0 {ni as f64} else {default.ior});
+        let emission = mtl.emissive.as_ref().map_or(DVec3::zero(), mtl_color);
+        Principled{base_color, specular, roughness, transmission, ior, emission, ..default}
+    }
+
+    fn alphas(&self) -> (f64, f64) {
+        let aspect = (1.0 - 0.9 * self.anisotropic).sqrt();
+        let a = self.roughness * self.roughness;
+        ((a / aspect).max(1e-3), (a * aspect).max(1e-3))
+    }
+
+    fn clearcoat_alpha(&self) -> f64 {
+        0.1 * (1.0 - self.clearcoat_gloss) + 0.001 * self.clearcoat_gloss
+    }
+
+    fn tint(&self) -> DVec3 {
+        let lum = luminance(self.base_color);
+        if lum > 0.0 {self.base_color / lum} else {DVec3::one()}
+    }
+
+    fn lobes(&self) -> Lobes {
+        let dielectric = 1.0 - self.metallic;
+        let diffuse = dielectric * (1.0 - self.transmission) * luminance(self.base_color);
+        let specular = 1.0;
+        let clearcoat = 0.25 * self.clearcoat;
+        let transmission = dielectric * self.transmission;
+        let total = diffuse + specular + clearcoat + transmission;
+        Lobes{diffuse: diffuse / total, specular: specular / total, clearcoat: clearcoat / total, transmission: transmission / total}
+    }
+
+    fn frame(&self, rec: &RayHit) -> Frame {
+        Frame::with_tangent(rec.normal, rec.tangent)
+    }
+
+    // pbrt's jacobian from the refraction half vector to wi, for eta behind over eta in front
+    fn dwh_dwi(eta: f64, dot_o: f64, dot_i: f64) -> f64 {
+        let denom = dot_o + eta * dot_i;
+        eta * eta * dot_i.abs() / (denom * denom)
+    }
+
+    // ior behind the surface over the ior in front of it, as seen from the side that was hit
+    fn eta(&self, rec: &RayHit) -> f64 {
+        if rec.front {self.ior} else {1.0 / self.ior}
+    }
+
+    // rgb bsdf, wo and wi in the shading frame
+    fn f(&self, r_in: &Ray, rec: &RayHit, wo: DVec3, wi: DVec3) -> DVec3 {
+        let base = r_in.reflectance(self.base_color * rec.color);
+        let (ax, ay) = self.alphas();
+        let dielectric = 1.0 - self.metallic;
+
+        if wi.z <= 0.0 {
+            // rough refraction
+            if self.transmission <= 0.0 || dielectric <= 0.0 || wi.z == 0.0 {return DVec3::zero()};
+            let eta = self.eta(rec);
+            let mut h = (wo + wi * eta).normalized();
+            if h.z < 0.0 {h = -h};
+            let (dot_o, dot_i) = (wo.dot(h), wi.dot(h));
+            if dot_o * dot_i >= 0.0 {return DVec3::zero()};
+            let f = fresnel_dielectric(dot_o, eta);
+            let ft = ggx_d(h, ax, ay) * ggx_g(wo, wi, ax, ay) * (1.0 - f) * dot_o.abs() * Principled::dwh_dwi(eta, dot_o, dot_i)
+                / (wi.z * wo.z).abs();
+            // radiance is compressed into the denser medium
+            return base * (dielectric * self.transmission * ft / (eta * eta));
+        }
+
+        let h = (wo + wi).normalized();
+        let cos_d = wi.dot(h);
+        let (fo, fi) = (schlick_weight(wo.z), schlick_weight(wi.z));
+
+        // diffuse with retro reflection and sheen
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let diffuse = base / PI * (1.0 + (fd90 - 1.0) * fo) * (1.0 + (fd90 - 1.0) * fi);
+        let sheen_color = lerp(DVec3::one(), r_in.reflectance(self.tint()), self.sheen_tint);
+        let sheen = sheen_color * self.sheen * schlick_weight(cos_d);
+        let mut f = (diffuse + sheen) * (dielectric * (1.0 - self.transmission));
+
+        // specular reflection
+        let spec_color = lerp(DVec3::one(), r_in.reflectance(self.tint()), self.specular_tint);
+        let f0 = lerp(spec_color * 0.08 * self.specular, base, self.metallic);
+        let fresnel = lerp(f0, DVec3::one(), schlick_weight(cos_d));
+        f += fresnel * (ggx_d(h, ax, ay) * ggx_g(wo, wi, ax, ay) / (4.0 * wo.z * wi.z));
+
+        // clearcoat, a fixed ior 1.5 layer on top
+        if self.clearcoat > 0.0 {
+            let fc = 0.04 + 0.96 * schlick_weight(cos_d);
+            let gc = ggx_g(wo, wi, 0.25, 0.25);
+            f += DVec3::one() * (0.25 * self.clearcoat * gtr1_d(h.z, self.clearcoat_alpha()) * fc * gc / (4.0 * wo.z * wi.z));
+        }
+        f
+    }
+
+    fn pdf_local(&self, rec: &RayHit, wo: DVec3, wi: DVec3) -> f64 {
+        let lobes = self.lobes();
+        let (ax, ay) = self.alphas();
+        if wi.z <= 0.0 {
+            if lobes.transmission <= 0.0 || wi.z == 0.0 {return 0.0};
+            let eta = self.eta(rec);
+            let mut h = (wo + wi * eta).normalized();
+            if h.z < 0.0 {h = -h};
+            let (dot_o, dot_i) = (wo.dot(h), wi.dot(h));
+            if dot_o * dot_i >= 0.0 {return 0.0};
+            return lobes.transmission * ggx_d(h, ax, ay) * h.z * Principled::dwh_dwi(eta, dot_o, dot_i);
+        }
+        let h = (wo + wi).normalized();
+        let dot_o = wo.dot(h).abs().max(1e-9);
+        let mut pdf = lobes.diffuse * wi.z / PI;
+        pdf += lobes.specular * ggx_d(h, ax, ay) * h.z / (4.0 * dot_o);
+        if lobes.clearcoat > 0.0 {
+            pdf += lobes.clearcoat * gtr1_d(h.z, self.clearcoat_alpha()) * h.z / (4.0 * dot_o);
+        }
+        pdf
+    }
+}
+
+#[typetag::serde]
+impl Material for Principled {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let frame = self.frame(rec);
+        let wo = frame.to_local(-r_in.direction.normalized());
+        if wo.z <= 0.0 {return None};
+        let lobes = self.lobes();
+        let (ax, ay) = self.alphas();
+
+        let u = fastrand::f64();
+        let wi = if u < lobes.diffuse {
+            cosine_sample()
+        } else if u < lobes.diffuse + lobes.specular {
+            reflect(wo, ggx_sample(ax, ay))
+        } else if u < lobes.diffuse + lobes.specular + lobes.clearcoat {
+            reflect(wo, gtr1_sample(self.clearcoat_alpha()))
+        } else {
+            refract(wo, ggx_sample(ax, ay), self.eta(rec))?
+        };
+
+        let pdf = self.pdf_local(rec, wo, wi);
+        if pdf <= 0.0 {return None};
+        let weight = self.f(r_in, rec, wo, wi) * wi.z.abs() / pdf;
+        Some(r_in.scatter_from(rec, frame.to_world(wi), r_in.color * weight))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let frame = self.frame(rec);
+        let wo = frame.to_local(-r_in.direction.normalized());
+        if wo.z <= 0.0 {return DVec3::zero()};
+        self.f(r_in, rec, wo, frame.to_local(wi.normalized()))
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        let frame = self.frame(rec);
+        let wo = frame.to_local(-r_in.direction.normalized());
+        if wo.z <= 0.0 {return 0.0};
+        self.pdf_local(rec, wo, frame.to_local(wi.normalized()))
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.base_color
+    }
+
+    fn two_sided(&self) -> bool {
+        self.two_sided
+    }
+
+    // glows without being sampled as a light
+    fn emitted(&self, r_in: &Ray, rec: &RayHit) -> DVec3 {
+        if !rec.front {return DVec3::zero()};
+        r_in.illuminant(self.emission)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(mat: &Principled, front: bool) -> RayHit {
+        RayHit{
+            hit_point: DVec3::zero(), normal: DVec3::unit_z(), geometric_normal: DVec3::unit_z(), mat: Box::new(mat.clone()),
+            hit_time: 1.0, front, uv: ultraviolet::DVec2::zero(), tangent: DVec3::unit_x(), color: DVec3::one(), error: DVec3::zero()
+        }
+    }
+
+    // midpoint rule over the sphere in (cos theta, phi)
+    fn integrate(steps: usize, f: impl Fn(DVec3) -> f64) -> f64 {
+        let mut sum = 0.0;
+        for i in 0..steps {
+            let cos = -1.0 + 2.0 * (i as f64 + 0.5) / steps as f64;
+            let sin = (1.0 - cos * cos).sqrt();
+            for j in 0..2 * steps {
+                let phi = PI * (j as f64 + 0.5) / steps as f64;
+                sum += f(DVec3::new(sin * phi.cos(), sin * phi.sin(), cos));
+            }
+        }
+        sum * (2.0 / steps as f64) * (PI / steps as f64)
+    }
+
+    #[test]
+    fn pdf_matches_scatter() {
+        // glass on both sides of the boundary, and a rough plastic
+        let glass = Principled{transmission: 1.0, roughness: 0.4, ior: 1.5, ..Principled::default()};
+        let plastic = Principled{roughness: 0.6, clearcoat: 1.0, ..Principled::default()};
+        for (mat, front) in [(&glass, true), (&glass, false), (&plastic, true)] {
+            let rec = hit(mat, front);
+            let r_in = Ray::new(DVec3::new(-0.3, 0.2, 1.0), DVec3::new(0.3, -0.2, -1.0), DVec3::one());
+            // fraction of scatter calls that return a ray, against the mass of the pdf
+            let n = 200000;
+            let kept = (0..n).filter(|_| mat.scatter(&r_in, &rec).is_some()).count() as f64 / n as f64;
+            let mass = integrate(400, |wi| mat.pdf(&r_in, &rec, wi));
+            assert!((kept - mass).abs() < 0.02, "front {} kept {} mass {}", front, kept, mass);
+        }
+    }
+
+    #[test]
+    fn scatter_weight_is_f_cos_over_pdf() {
+        let mat = Principled{transmission: 1.0, roughness: 0.3, ior: 1.5, anisotropic: 0.5, ..Principled::default()};
+        let rec = hit(&mat, true);
+        let r_in = Ray::new(DVec3::new(0.0, -0.5, 1.0), DVec3::new(0.0, 0.5, -1.0), DVec3::one());
+        for _ in 0..1000 {
+            let Some(scattered) = mat.scatter(&r_in, &rec) else {continue};
+            let wi = scattered.direction.normalized();
+            let expected = mat.eval(&r_in, &rec, wi) * wi.z.abs() / mat.pdf(&r_in, &rec, wi);
+            assert!((scattered.color - expected).mag() <= 1e-6 * expected.mag().max(1.0));
+        }
+    }
+
+    #[test]
+    fn anisotropy_follows_the_tangent() {
+        let mat = Principled{roughness: 0.5, anisotropic: 1.0, ..Principled::default()};
+        let mut rec = hit(&mat, true);
+        let r_in = Ray::new(DVec3::unit_z(), -DVec3::unit_z(), DVec3::one());
+        let along_x = mat.eval(&r_in, &rec, DVec3::new(0.6, 0.0, 1.0));
+        let along_y = mat.eval(&r_in, &rec, DVec3::new(0.0, 0.6, 1.0));
+        assert!(along_x.x > along_y.x);
+        rec.tangent = DVec3::unit_y();
+        assert!((mat.eval(&r_in, &rec, DVec3::new(0.0, 0.6, 1.0)) - along_x).mag() < 1e-12);
+    }
+}
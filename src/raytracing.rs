@@ -1,19 +1,60 @@
+use std::cell::Cell;
+use std::f64::consts::PI;
+use std::sync::Arc;
 use ultraviolet::*;
+use wide::{CmpGe, CmpGt, CmpLe, CmpLt};
+use crate::bvh::Bvh;
+use crate::camera::unit_disk_samp;
+use crate::lights::{PunctualLight, tangent_frame};
 use crate::materials::Material;
-use crate::obj_loader::MeshTriangle;
+use crate::obj_loader::MeshData;
+use crate::scene::{deserialize_rotor, serialize_rotor};
+use crate::spectral::Wavelengths;
+use crate::texture::Texture;
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone)]
 pub struct Ray {
     pub origin: DVec3,
     pub direction: DVec3,
     pub color: DVec3,
-    pub emissive: bool
+    pub emissive: bool,
+    // set in spectral mode, color then holds one throughput per wavelength
+    pub wavelengths: Option<Wavelengths>
 }
 
 impl Ray {
     pub fn new(origin: DVec3, direction: DVec3, color:DVec3) -> Ray{
         Ray {
-            origin, direction, color, emissive:false
+            origin, direction, color, emissive:false, wavelengths:None
+        }
+    }
+
+    // a new ray continuing this path, keeps the wavelengths
+    pub fn spawn(&self, origin: DVec3, direction: DVec3, color:DVec3) -> Ray {
+        Ray {
+            origin, direction, color, emissive:false, wavelengths:self.wavelengths
+        }
+    }
+
+    // a new ray continuing this path from the surface of rec
+    pub fn scatter_from(&self, rec: &RayHit, direction: DVec3, color: DVec3) -> Ray {
+        self.spawn(rec.spawn_point(direction), direction, color)
+    }
+
+    // rgb albedo as seen by this ray
+    pub fn reflectance(&self, rgb: DVec3) -> DVec3 {
+        match &self.wavelengths {
+            Some(w) => w.reflectance(rgb),
+            None => rgb
+        }
+    }
+
+    // rgb emission as seen by this ray
+    pub fn illuminant(&self, rgb: DVec3) -> DVec3 {
+        match &self.wavelengths {
+            Some(w) => w.illuminant(rgb),
+            None => rgb
         }
     }
 
@@ -25,46 +66,244 @@ impl Ray {
 #[derive(Clone)]
 pub struct RayHit {
     pub hit_point: DVec3,
+    // shading normal, interpolated or bent by normal maps, on the same side as geometric_normal
     pub normal: DVec3,
+    // true surface normal, facing the side the ray came from
+    pub geometric_normal: DVec3,
     pub mat: Box<dyn Material + Sync>,
     pub hit_time: f64,
-    pub front: bool
+    pub front: bool,
+    // surface parameterization for textures, zero where a shape has none
+    pub uv: DVec2,
+    // unit direction of increasing u, zero where a shape has none
+    pub tangent: DVec3,
+    // vertex color, multiplied into the base color of materials that support it. white where a shape has none
+    pub color: DVec3,
+    // bound on the floating point error of hit_point, per axis
+    pub error: DVec3
+}
+
+// shadow rays stop this fraction short of their target
+pub const SHADOW_EPSILON: f64 = 1e-4;
+
+// bound on the relative error of n chained floating point operations
+pub fn gamma(n: i32) -> f64 {
+    let eps = f64::EPSILON * 0.5;
+    n as f64 * eps / (1.0 - n as f64 * eps)
+}
+
+// error bound for a point on a surface whose error wasn't tracked, e.g. sampled light positions
+pub fn point_error(p: DVec3) -> DVec3 {
+    p.abs() * gamma(7)
+}
+
+// moves p just far enough along n, towards the side w leaves on, that a ray from it
+// can't hit the surface p is on again
+pub fn offset_ray_origin(p: DVec3, error: DVec3, n: DVec3, w: DVec3) -> DVec3 {
+    let d = n.abs().dot(error);
+    let mut offset = n * d;
+    if w.dot(n) < 0.0 {offset = -offset};
+    let mut po = p + offset;
+    for i in 0..3 {
+        if offset[i] > 0.0 {po[i] = po[i].next_up()} else if offset[i] < 0.0 {po[i] = po[i].next_down()};
+    }
+    po
+}
+
+// true when something blocks the segment between two points that have already been offset off their surfaces
+pub fn occluded(world: &HittableList, from: DVec3, to: DVec3) -> bool {
+    let r = Ray::new(from, to - from, DVec3::one());
+    get_world_hit(&r, 0.0, 1.0 - SHADOW_EPSILON, world).is_some()
 }
 
 impl RayHit {
-    fn set_face_normal(&mut self, r:&Ray, outward_normal: DVec3) {
-        self.front = r.direction.dot(outward_normal) < 0.0;
-        self.normal = if self.front {outward_normal} else {-outward_normal};
+    // where rays leaving the surface in direction w start
+    pub fn spawn_point(&self, w: DVec3) -> DVec3 {
+        offset_ray_origin(self.hit_point, self.error, self.geometric_normal, w)
+    }
+
+    pub fn set_face_normal(&mut self, r:&Ray, outward_normal: DVec3) {
+        self.set_normals(r, outward_normal, outward_normal);
+    }
+
+    // front and back are decided by the geometry, the shading normal is flipped along with it
+    fn set_normals(&mut self, r:&Ray, outward_geometric: DVec3, outward_shading: DVec3) {
+        // vertex normals against the winding order follow the winding
+        let outward_shading = if outward_shading.dot(outward_geometric) < 0.0 {-outward_shading} else {outward_shading};
+        self.front = r.direction.dot(outward_geometric) < 0.0;
+        self.geometric_normal = if self.front {outward_geometric} else {-outward_geometric};
+        self.normal = if self.front {outward_shading} else {-outward_shading};
     }
 }
 
-pub trait Hittable {
+#[typetag::serde(tag = "type")]
+pub trait Hittable: Send + Sync {
     fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit>;
     fn bounding_box_hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> bool;
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        None
+    }
+
+    fn area(&self) -> f64 {
+        0.0
+    }
+
+    // primitives tested by one call to hit, for the cost heat map
+    fn primitive_count(&self) -> usize {
+        1
+    }
+
+    // lights that can't be hit return themselves here
+    fn punctual(&self) -> Option<&(dyn PunctualLight + Sync + Send)> {
+        None
+    }
+
+    // uniformly distributed point on the surface and its outward normal
+    fn sample_surface(&self) -> Option<(DVec3, DVec3)> {
+        None
+    }
 }
 
 pub type HittableList =  Vec<Box<dyn Hittable + Sync + Send>>;
 
-fn get_world_hit(r: &Ray, ray_tmin: f64, ray_tmax: f64, world:&HittableList) -> Option<RayHit> {
+thread_local! {
+    static INTERSECTION_TESTS: Cell<u64> = const { Cell::new(0) };
+}
+
+// bounding box and primitive tests done on this thread so far
+pub fn intersection_tests() -> u64 {
+    INTERSECTION_TESTS.with(|c| c.get())
+}
+
+fn count_tests(n: usize) {
+    INTERSECTION_TESTS.with(|c| c.set(c.get() + n as u64));
+}
+
+pub fn get_world_hit(r: &Ray, ray_tmin: f64, ray_tmax: f64, world:&HittableList) -> Option<RayHit> {
+    get_world_hit_index(r, ray_tmin, ray_tmax, world).map(|(_, rec)| rec)
+}
+
+// like get_world_hit, also returns the index of the object that was hit
+pub fn get_world_hit_index(r: &Ray, ray_tmin: f64, ray_tmax: f64, world:&HittableList) -> Option<(usize, RayHit)> {
     let mut closest_so_far = ray_tmax;
-    let mut closest_rec: Option<RayHit> = None;
+    let mut closest_rec: Option<(usize, RayHit)> = None;
 
-    for obj in world {
+    for (i, obj) in world.iter().enumerate() {
+        count_tests(1);
         if !obj.bounding_box_hit(r, ray_tmin, closest_so_far) {continue};
+        count_tests(obj.primitive_count());
         if let Some(rec) = obj.hit(r, ray_tmin, closest_so_far)  {
             closest_so_far = rec.hit_time;
-            closest_rec = Some(rec);
+            closest_rec = Some((i, rec));
         }
     }
     closest_rec
 }
 
+// indices of the objects that emit light
+pub fn emitters(world: &HittableList) -> Vec<usize> {
+    world.iter().enumerate()
+        .filter(|(_, obj)| obj.area() > 0.0 && obj.material().is_some_and(|m| m.is_emissive()))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct LightSample {
+    pub object: usize,
+    pub p: DVec3,
+    // outward normal
+    pub n: DVec3,
+    // area density of picking this light and point
+    pub pdf_pos: f64,
+    pub mat: Box<dyn Material + Sync + Send>,
+}
+
+impl LightSample {
+    // radiance leaving the light towards dir
+    pub fn emitted(&self, dir: DVec3, wavelengths: Option<Wavelengths>) -> DVec3 {
+        let mut r = Ray::new(self.p + dir, -dir, DVec3::one());
+        r.wavelengths = wavelengths;
+        let mut rec = RayHit{hit_point: self.p, normal: self.n, geometric_normal: self.n, mat: self.mat.clone(), hit_time: 1.0, front: true, uv: DVec2::zero(), tangent: DVec3::zero(), color: DVec3::one(), error: point_error(self.p)};
+        rec.set_face_normal(&r, self.n);
+        self.mat.emitted(&r, &rec)
+    }
+
+    // where rays leaving the light in direction dir start
+    pub fn spawn_point(&self, dir: DVec3) -> DVec3 {
+        offset_ray_origin(self.p, point_error(self.p), self.n, dir)
+    }
+
+    // cosine weighted direction leaving the light, on either side for two sided emitters.
+    // returns it with its solid angle density
+    pub fn sample_direction(&self) -> (DVec3, f64) {
+        let two_sided = self.mat.emits_two_sided();
+        let n = if two_sided && fastrand::bool() {-self.n} else {self.n};
+        let dir = (n + unit_samp()).normalized();
+        let pdf = dir.dot(n).max(0.0) / PI;
+        (dir, if two_sided {pdf / 2.0} else {pdf})
+    }
+}
+
+// picks one of the lights uniformly and a point on it uniformly by area
+pub fn sample_emitter(world: &HittableList, lights: &[usize]) -> Option<LightSample> {
+    if lights.is_empty() {return None};
+    let object = lights[fastrand::usize(..lights.len())];
+    let obj = &world[object];
+    let (p, n) = obj.sample_surface()?;
+    let mat = dyn_clone::clone_box(obj.material()?);
+    Some(LightSample{object, p, n, pdf_pos: 1.0 / (lights.len() as f64 * obj.area()), mat})
+}
+
+// radiance from one randomly chosen point on a light scattered back along r_in, shadowed.
+// lights behind the surface count too, only transmitting materials evaluate to more than zero there.
+// doesn't include the throughput of r_in
+pub fn direct_light(world: &HittableList, lights: &[usize], r_in: &Ray, rec: &RayHit) -> DVec3 {
+    let Some(light) = sample_emitter(world, lights) else {return DVec3::zero()};
+    let d = light.p - rec.hit_point;
+    let dist = d.mag();
+    let wi = d / dist;
+    let cos_light = light.n.dot(wi).abs();
+    let cos_surface = rec.normal.dot(wi).abs();
+    if cos_light <= 0.0 || cos_surface <= 0.0 {return DVec3::zero()};
+    let le = light.emitted(-wi, r_in.wavelengths);
+    if le == DVec3::zero() {return DVec3::zero()};
+    if occluded(world, rec.spawn_point(wi), light.spawn_point(-wi)) {return DVec3::zero()};
+    rec.mat.eval(r_in, rec, wi) * le * cos_light * cos_surface / (dist * dist * light.pdf_pos)
+}
+
+pub fn punctual_lights(world: &HittableList) -> Vec<usize> {
+    world.iter().enumerate()
+        .filter(|(_, obj)| obj.punctual().is_some())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// light from every punctual light scattered back along r_in, shadowed. they can't be hit, so this is
+// all they give, through transmitting materials too. doesn't include the throughput of r_in
+pub fn punctual_light(world: &HittableList, lights: &[usize], r_in: &Ray, rec: &RayHit) -> DVec3 {
+    if rec.mat.is_delta() {return DVec3::zero()};
+    let mut l = DVec3::zero();
+    for light in lights.iter().filter_map(|i| world[*i].punctual()) {
+        let Some((wi, dist, li)) = light.sample_li(rec.hit_point, r_in) else {continue};
+        let cos = rec.normal.dot(wi).abs();
+        if cos <= 0.0 {continue};
+        let shadow = Ray::new(rec.spawn_point(wi), wi, DVec3::one());
+        if get_world_hit(&shadow, 0.0, dist * (1.0 - SHADOW_EPSILON), world).is_some() {continue};
+        l += rec.mat.eval(r_in, rec, wi) * li * cos;
+    }
+    l
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Sphere {
     pub center: DVec3,
     pub radius: f64,
     pub mat: Box<dyn Material + Sync + Send>
 }
 
+#[typetag::serde]
 impl Hittable for Sphere {
     fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit> {
         let oc = r.origin - self.center;
@@ -75,26 +314,45 @@ impl Hittable for Sphere {
         if disc < 0.0 { return None};
         
         let sqrtd = disc.sqrt();
-        let mut root = (-half_b - sqrtd) / a;
+        // stable form of the two roots, avoids cancellation for rays starting on the surface
+        let q = -(half_b + sqrtd.copysign(half_b));
+        if q == 0.0 {return None};
+        let (near, far) = {
+            let (t0, t1) = (q / a, c / q);
+            if t0 < t1 {(t0, t1)} else {(t1, t0)}
+        };
+        let mut root = near;
         if root <= ray_tmin || ray_tmax <= root {
-            root = (-half_b + sqrtd) / a;
+            root = far;
             if root <= ray_tmin || ray_tmax <= root {
                 return None;
             }
         }
 
-        let p = r.clone().at(root);
-        let outward_normal = (p - self.center) / self.radius;
+        // reprojected onto the sphere, which keeps the error small
+        let d = r.clone().at(root) - self.center;
+        let d = d * (self.radius / d.mag());
+        let p = self.center + d;
+        let outward_normal = d / self.radius;
  
         let mut rec = RayHit {
             hit_time: root,
             hit_point: p,
             mat: self.mat.clone(),
             normal: outward_normal,
-            front: true
+            geometric_normal: outward_normal,
+            front: true,
+            // longitude and latitude, v = 0 at the bottom
+            uv: DVec2::new(
+                ((-outward_normal.z).atan2(outward_normal.x) + PI) / (2.0 * PI),
+                (-outward_normal.y).clamp(-1.0, 1.0).acos() / PI
+            ),
+            tangent: DVec3::zero(),
+            color: DVec3::one(),
+            error: d.abs() * gamma(5) + p.abs() * gamma(1)
         };
-        rec.set_face_normal(&r, outward_normal);
-        
+        rec.set_face_normal(r, outward_normal);
+        if !rec.front && !self.mat.two_sided() {return None};
 
         Some(rec)
     }
@@ -115,67 +373,399 @@ impl Hittable for Sphere {
         }
         true
     }
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        Some(self.mat.as_ref())
+    }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self) -> Option<(DVec3, DVec3)> {
+        let n = unit_samp();
+        Some((self.center + n * self.radius, n))
+    }
+}
+
+// parallelogram spanned by u and v from corner, facing along u x v
+#[derive(Serialize, Deserialize)]
+pub struct Quad {
+    pub corner: DVec3,
+    pub u: DVec3,
+    pub v: DVec3,
+    pub mat: Box<dyn Material + Sync + Send>
+}
+
+impl Quad {
+    fn intersect(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<(f64, DVec3, DVec2)> {
+        let n = self.u.cross(self.v);
+        let denom = n.dot(r.direction);
+        if denom.abs() < 1e-12 {return None};
+        let t = n.dot(self.corner - r.origin) / denom;
+        if t <= ray_tmin || ray_tmax <= t {return None};
+        let p = r.origin + r.direction * t;
+        let hp = p - self.corner;
+        let w = n / n.mag_sq();
+        let alpha = w.dot(hp.cross(self.v));
+        let beta = w.dot(self.u.cross(hp));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {return None};
+        Some((t, self.corner + alpha * self.u + beta * self.v, DVec2::new(alpha, beta)))
+    }
+}
+
+#[typetag::serde]
+impl Hittable for Quad {
+    fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit> {
+        let (t, p, uv) = self.intersect(r, ray_tmin, ray_tmax)?;
+        let outward_normal = self.u.cross(self.v).normalized();
+        let error = (self.corner.abs() + (self.u * uv.x).abs() + (self.v * uv.y).abs()) * gamma(5);
+        let mut rec = RayHit{hit_time: t, hit_point: p, mat: self.mat.clone(), normal: outward_normal, geometric_normal: outward_normal, front: true, uv, tangent: self.u.normalized(), color: DVec3::one(), error};
+        rec.set_face_normal(r, outward_normal);
+        if !rec.front && !self.mat.two_sided() {return None};
+        Some(rec)
+    }
+
+    fn bounding_box_hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> bool {
+        self.intersect(r, ray_tmin, ray_tmax).is_some()
+    }
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        Some(self.mat.as_ref())
+    }
+
+    fn area(&self) -> f64 {
+        self.u.cross(self.v).mag()
+    }
+
+    fn sample_surface(&self) -> Option<(DVec3, DVec3)> {
+        let p = self.corner + fastrand::f64() * self.u + fastrand::f64() * self.v;
+        Some((p, self.u.cross(self.v).normalized()))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Disc {
+    pub center: DVec3,
+    pub normal: DVec3,
+    pub radius: f64,
+    pub mat: Box<dyn Material + Sync + Send>
+}
+
+impl Disc {
+    fn intersect(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<(f64, DVec3)> {
+        let denom = self.normal.dot(r.direction);
+        if denom.abs() < 1e-12 {return None};
+        let t = self.normal.dot(self.center - r.origin) / denom;
+        if t <= ray_tmin || ray_tmax <= t {return None};
+        let p = r.origin + r.direction * t;
+        if (p - self.center).mag_sq() > self.radius * self.radius {return None};
+        // back onto the plane
+        let n = self.normal.normalized();
+        Some((t, p - n * n.dot(p - self.center)))
+    }
 }
 
+#[typetag::serde]
+impl Hittable for Disc {
+    fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit> {
+        let (t, p) = self.intersect(r, ray_tmin, ray_tmax)?;
+        let outward_normal = self.normal.normalized();
+        // angle around the center and distance from it
+        let (tangent, bitangent) = tangent_frame(outward_normal);
+        let d = p - self.center;
+        let uv = DVec2::new((d.dot(bitangent).atan2(d.dot(tangent)) + PI) / (2.0 * PI), d.mag() / self.radius);
+        let error = (p.abs() + self.center.abs()) * gamma(5);
+        let mut rec = RayHit{hit_time: t, hit_point: p, mat: self.mat.clone(), normal: outward_normal, geometric_normal: outward_normal, front: true, uv, tangent: DVec3::zero(), color: DVec3::one(), error};
+        rec.set_face_normal(r, outward_normal);
+        if !rec.front && !self.mat.two_sided() {return None};
+        Some(rec)
+    }
+
+    fn bounding_box_hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> bool {
+        self.intersect(r, ray_tmin, ray_tmax).is_some()
+    }
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        Some(self.mat.as_ref())
+    }
+
+    fn area(&self) -> f64 {
+        PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self) -> Option<(DVec3, DVec3)> {
+        let n = self.normal.normalized();
+        let (t, b) = tangent_frame(n);
+        let p = unit_disk_samp() * self.radius;
+        Some((self.center + t * p.x + b * p.y, n))
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct BoundingBox {
     pub min: DVec3,
     pub max: DVec3
 }
 
+impl BoundingBox {
+    pub const fn empty() -> BoundingBox {
+        BoundingBox{
+            min: DVec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: DVec3::new(-f64::INFINITY, -f64::INFINITY, -f64::INFINITY)
+        }
+    }
+
+    pub fn grow(&mut self, p: DVec3) {
+        self.min = self.min.min_by_component(p);
+        self.max = self.max.max_by_component(p);
+    }
+
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox{min: self.min.min_by_component(other.min), max: self.max.max_by_component(other.max)}
+    }
+
+    pub fn centroid(&self) -> DVec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    // distances where the ray enters and leaves the box, clipped to the ray's range
+    pub fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<(f64, f64)> {
+        let (mut t0, mut t1) = (ray_tmin, ray_tmax);
+        for i in 0..3 {
+            let inv = 1.0 / r.direction[i];
+            let near = (self.min[i] - r.origin[i]) * inv;
+            let far = (self.max[i] - r.origin[i]) * inv;
+            let (near, far) = if near > far {(far, near)} else {(near, far)};
+            t0 = t0.max(near);
+            t1 = t1.min(far * (1.0 + 2.0 * gamma(3)));
+            if t0 > t1 {return None};
+        }
+        Some((t0, t1))
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0.0 {return 0.0};
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(from = "MeshRecord")]
 pub struct Mesh {
-    pub tris: Vec<MeshTriangle>,
+    // object space, shared by every transform of the mesh
+    pub data: MeshData,
     pub mat: Box<dyn Material + Sync + Send>,
     pub position: DVec3,
+    #[serde(serialize_with = "serialize_rotor")]
     pub rotation: DRotor3,
-    pub transformed_tris: Vec<MeshTriangle>,
-    pub bounding_box: BoundingBox
+    // object space hierarchy over data.indices, which are kept in its leaf order
+    #[serde(skip_serializing)]
+    pub bvh: Bvh,
+    // world space
+    #[serde(skip_serializing)]
+    pub bounding_box: BoundingBox,
+    // running sum of triangle areas, for sampling points on emissive meshes
+    #[serde(skip_serializing)]
+    pub area_cdf: Vec<f64>,
+    // tangent space normal map, linear rgb with blue along the normal
+    pub normal_map: Option<Arc<Texture>>,
+    pub bump_map: Option<Arc<Texture>>,
+    // object space height of a white bump map texel
+    pub bump_scale: f64
+}
+
+fn calculate_area_cdf(data: &MeshData) -> Vec<f64> {
+    let mut total = 0.0;
+    (0..data.triangle_count()).map(|t| {
+        total += 0.5 * data.face_normal(t).mag();
+        total
+    }).collect()
 }
 
-fn calcuate_bounding_box(tris: Vec<MeshTriangle>) -> BoundingBox {
-    let mut min = DVec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
-    let mut max = DVec3::new(-f64::INFINITY, -f64::INFINITY, -f64::INFINITY);
-    for tri in tris.iter() {
-        min.x = min.x.min(tri.pos1.x).min(tri.pos2.x).min(tri.pos3.x);
-        min.y = min.y.min(tri.pos1.y).min(tri.pos2.y).min(tri.pos3.y);
-        min.z = min.z.min(tri.pos1.z).min(tri.pos2.z).min(tri.pos3.z);
-        max.x = max.x.max(tri.pos1.x).max(tri.pos2.x).max(tri.pos3.x);
-        max.y = max.y.max(tri.pos1.y).max(tri.pos2.y).max(tri.pos3.y);
-        max.z = max.z.max(tri.pos1.z).max(tri.pos2.z).max(tri.pos3.z);
+fn calcuate_bounding_box(data: &MeshData, position: DVec3, rotation: DRotor3) -> BoundingBox {
+    let mut b = BoundingBox::empty();
+    for i in 0..data.vertex_count() {
+        b.grow(data.position(i as u32).rotated_by(rotation) + position);
+    }
+    // room for the rounding of the transform
+    let pad = (b.min.abs().max_by_component(b.max.abs())) * gamma(3);
+    BoundingBox{min: b.min - pad, max: b.max + pad}
+}
+
+// a saved mesh, the hierarchy and the tables derived from the triangles are rebuilt on load
+#[derive(Deserialize)]
+struct MeshRecord {
+    data: MeshData,
+    mat: Box<dyn Material + Sync + Send>,
+    position: DVec3,
+    #[serde(deserialize_with = "deserialize_rotor")]
+    rotation: DRotor3,
+    normal_map: Option<Arc<Texture>>,
+    bump_map: Option<Arc<Texture>>,
+    bump_scale: f64
+}
+
+impl From<MeshRecord> for Mesh {
+    fn from(record: MeshRecord) -> Mesh {
+        let mut mesh = Mesh::new(record.data, record.mat);
+        mesh.transform(record.position, record.rotation);
+        Mesh{normal_map: record.normal_map, bump_map: record.bump_map, bump_scale: record.bump_scale, ..mesh}
     }
-    BoundingBox{min, max}
 }
 
 impl Mesh {
-    pub fn new(tris: Vec<MeshTriangle>, mat: Box<dyn Material + Sync + Send>) -> Mesh {
+    pub fn new(mut data: MeshData, mat: Box<dyn Material + Sync + Send>) -> Mesh {
+        let (bvh, order) = Bvh::build(data.triangle_count(), |t| {
+            let mut b = BoundingBox::empty();
+            for p in data.triangle(t) {
+                b.grow(p);
+            }
+            b
+        });
+        data.indices = order.iter().map(|&t| data.indices[t as usize]).collect();
         Mesh {
-            tris:tris.clone(),
-            mat, 
+            bvh,
+            area_cdf: calculate_area_cdf(&data),
+            bounding_box: calcuate_bounding_box(&data, DVec3::zero(), DRotor3::identity()),
+            data,
+            mat,
             position: DVec3::zero(),
             rotation: DRotor3::identity(),
-            transformed_tris: tris.clone(),
-            bounding_box: calcuate_bounding_box(tris)
+            normal_map: None,
+            bump_map: None,
+            bump_scale: 1.0
         }
     }
 
+    // only the placement is stored, rays are moved into object space instead of the mesh into the world
     pub fn transform(&mut self, pos: DVec3, rot:DRotor3) {
         self.position = pos;
         self.rotation = rot;
-        // transforms the mesh
-        let mut transformed_tri_list: Vec<MeshTriangle> = vec![];
-        for tri in self.tris.iter() {
-            let new_tri = MeshTriangle {
-                pos1: tri.pos1.rotated_by(self.rotation) + self.position,
-                pos2: tri.pos2.rotated_by(self.rotation) + self.position,
-                pos3: tri.pos3.rotated_by(self.rotation) + self.position,
-                norm1: tri.norm1.rotated_by(self.rotation),
-                norm2: tri.norm2.rotated_by(self.rotation),
-                norm3: tri.norm3.rotated_by(self.rotation)
-            };
-            transformed_tri_list.push(new_tri);
+        self.bounding_box = calcuate_bounding_box(&self.data, self.position, self.rotation);
+    }
+
+    fn to_world(&self, p: DVec3) -> DVec3 {
+        p.rotated_by(self.rotation) + self.position
+    }
+
+    // bound on the world space error of a point known to within error in object space
+    fn world_error(&self, p: DVec3, error: DVec3) -> DVec3 {
+        let m = self.rotation.into_matrix();
+        let abs = DMat3::new(m.cols[0].abs(), m.cols[1].abs(), m.cols[2].abs());
+        abs * (error + p.abs() * gamma(3)) + self.to_world(p).abs() * gamma(1)
+    }
+
+    // interpolated world space tangent, zero for meshes without uvs
+    fn tangent(&self, tri: [u32; 3], w: f64, u: f64, v: f64) -> DVec3 {
+        let t = tri.map(|i| self.data.tangent(i).map_or(DVec3::zero(), |t| t.0));
+        let t = t[0] * w + t[1] * u + t[2] * v;
+        if t.mag_sq() > 0.0 {t.normalized().rotated_by(self.rotation)} else {t}
+    }
+
+    // object space distance the triangle covers per unit of u and of v
+    fn uv_lengths(&self, tri: [u32; 3]) -> (f64, f64) {
+        let [p0, p1, p2] = tri.map(|i| self.data.position(i));
+        let [uv0, uv1, uv2] = tri.map(|i| self.data.uv(i));
+        let (dp1, dp2) = (p1 - p0, p2 - p0);
+        let (duv1, duv2) = (uv1 - uv0, uv2 - uv0);
+        let det = duv1.x * duv2.y - duv1.y * duv2.x;
+        if det.abs() < 1e-12 {return (1.0, 1.0)};
+        (((dp1 * duv2.y - dp2 * duv1.y) / det).mag(), ((dp2 * duv1.x - dp1 * duv2.x) / det).mag())
+    }
+
+    // interpolated object space normal at barycentrics (w, u, v), bent by the normal and bump maps
+    fn shading_normal(&self, tri: [u32; 3], w: f64, u: f64, v: f64) -> DVec3 {
+        let d = &self.data;
+        let mut n = (d.normal(tri[0]) * w + d.normal(tri[1]) * u + d.normal(tri[2]) * v).normalized();
+        if self.normal_map.is_none() && self.bump_map.is_none() {return n};
+        let (Some((t0, sign)), Some((t1, _)), Some((t2, _))) = (d.tangent(tri[0]), d.tangent(tri[1]), d.tangent(tri[2])) else {return n};
+        let uv = d.uv(tri[0]) * w + d.uv(tri[1]) * u + d.uv(tri[2]) * v;
+        let t = t0 * w + t1 * u + t2 * v;
+        let t = (t - n * n.dot(t)).normalized();
+        let b = n.cross(t) * sign;
+        if let Some(map) = &self.normal_map {
+            let c = map.sample(uv) * 2.0 - DVec3::one();
+            n = (t * c.x + b * c.y + n * c.z).normalized();
         }
-        self.transformed_tris = transformed_tri_list;
-        self.bounding_box = calcuate_bounding_box(self.transformed_tris.clone());
-    }   
+        if let Some(map) = &self.bump_map {
+            let (dpdu, dpdv) = self.uv_lengths(tri);
+            let (dhdu, dhdv) = map.height_gradient(uv, dpdu, dpdv);
+            n = (n - (t * dhdu + b * dhdv) * self.bump_scale).normalized();
+        }
+        n
+    }
+}
+
+fn max_dimension(v: DVec3) -> usize {
+    if v.x > v.y && v.x > v.z {0} else if v.y > v.z {1} else {2}
+}
+
+// watertight ray triangle test by woop, benthin and wald, for four triangles at once: the
+// triangles are moved into a space where the ray runs along +z from the origin, so edges shared by
+// two triangles give exactly opposite edge functions and no ray slips between them. lanes beyond
+// count are ignored, back faces are skipped when cull is set. returns the lane of the closest hit
+// past ray_tmin with its t and the barycentrics of p0, p1 and p2
+pub fn intersect_triangles(r: &Ray, p: [DVec3x4; 3], count: usize, cull: bool, ray_tmin: f64, ray_tmax: f64) -> Option<(usize, f64, f64, f64, f64)> {
+    let origin = DVec3x4::splat(r.origin);
+    let (p0t, p1t, p2t) = (p[0] - origin, p[1] - origin, p[2] - origin);
+    let kz = max_dimension(r.direction.abs());
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+    let axis = |v: DVec3x4, k: usize| match k {0 => v.x, 1 => v.y, _ => v.z};
+    let (mut x0, mut y0, mut z0) = (axis(p0t, kx), axis(p0t, ky), axis(p0t, kz));
+    let (mut x1, mut y1, mut z1) = (axis(p1t, kx), axis(p1t, ky), axis(p1t, kz));
+    let (mut x2, mut y2, mut z2) = (axis(p2t, kx), axis(p2t, ky), axis(p2t, kz));
+    let d = DVec3::new(r.direction[kx], r.direction[ky], r.direction[kz]);
+
+    // shear so the ray points along z
+    let (sx, sy, sz) = (f64x4::splat(-d.x / d.z), f64x4::splat(-d.y / d.z), f64x4::splat(1.0 / d.z));
+    x0 += sx * z0;
+    y0 += sy * z0;
+    x1 += sx * z1;
+    y1 += sy * z1;
+    x2 += sx * z2;
+    y2 += sy * z2;
+
+    let e0 = x1 * y2 - y1 * x2;
+    let e1 = x2 * y0 - y2 * x0;
+    let e2 = x0 * y1 - y0 * x1;
+    let zero = f64x4::ZERO;
+    let negative = e0.cmp_lt(zero) | e1.cmp_lt(zero) | e2.cmp_lt(zero);
+    let positive = e0.cmp_gt(zero) | e1.cmp_gt(zero) | e2.cmp_gt(zero);
+    let det = e0 + e1 + e2;
+
+    z0 *= sz;
+    z1 *= sz;
+    z2 *= sz;
+    let t_scaled = e0 * z0 + e1 * z1 + e2 * z2;
+    let limit = det * f64x4::splat(ray_tmax);
+    let in_front = (det.cmp_lt(zero) & t_scaled.cmp_lt(zero) & t_scaled.cmp_ge(limit))
+        | (det.cmp_gt(zero) & t_scaled.cmp_gt(zero) & t_scaled.cmp_le(limit));
+    let inv_det = f64x4::ONE / det;
+    let t = t_scaled * inv_det;
+
+    // t has to be clearly positive given the error of the computation above
+    let max_zt = z0.abs().max(z1.abs()).max(z2.abs());
+    let max_xt = x0.abs().max(x1.abs()).max(x2.abs());
+    let max_yt = y0.abs().max(y1.abs()).max(y2.abs());
+    let delta_z = f64x4::splat(gamma(3)) * max_zt;
+    let delta_x = f64x4::splat(gamma(5)) * (max_xt + max_zt);
+    let delta_y = f64x4::splat(gamma(5)) * (max_yt + max_zt);
+    let delta_e = f64x4::splat(2.0) * (f64x4::splat(gamma(2)) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+    let max_e = e0.abs().max(e1.abs()).max(e2.abs());
+    let delta_t = f64x4::splat(3.0) * (f64x4::splat(gamma(3)) * max_e * max_zt + delta_e * max_zt + delta_z * max_e) * inv_det.abs();
+
+    let mut hit = !(negative & positive) & in_front & t.cmp_gt(delta_t) & t.cmp_gt(f64x4::splat(ray_tmin));
+    if cull {
+        let facing = (p[1] - p[0]).cross(p[2] - p[0]).dot(DVec3x4::splat(r.direction));
+        hit &= facing.cmp_le(zero);
+    }
+    let mask = hit.move_mask();
+    let t = t.to_array();
+    let lane = (0..count).filter(|k| mask & (1 << k) != 0).min_by(|a, b| t[*a].total_cmp(&t[*b]))?;
+    Some((lane, t[lane], (e0 * inv_det).to_array()[lane], (e1 * inv_det).to_array()[lane], (e2 * inv_det).to_array()[lane]))
 }
 
 #[derive(PartialEq)]
@@ -183,50 +773,44 @@ enum bbPosition {
     Left, Middle, Right
 }
 
+#[typetag::serde]
 impl Hittable for Mesh {
     fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit> {
-        let mut current_hit: Option<RayHit> = None;
-        for tri in self.transformed_tris.iter() {
-            let edge12 = tri.pos2 - tri.pos1;
-            let edge13 = tri.pos3 - tri.pos1;
-            let norm = edge12.cross(edge13);
-            if norm.dot(r.direction) > 0.0 {
-                // backface
-                continue;
-            }
-            let ao = r.origin - tri.pos1;
-            let dao = ao.cross(r.direction);
-
-            let det = -(r.direction.dot(norm));
-            let inv_det = 1.0 / det;
-
-            let dst = ao.dot(norm) * inv_det;
-            if dst <= ray_tmin || ray_tmax <= dst {
-                continue;
-            }
-            let u = edge13.dot(dao) * inv_det;
-            let v = -(edge12.dot(dao)) * inv_det;
-            let w = 1.0 - u - v;
-
-            if det >= 1e-6 && dst >= 0.0 && u >= 0.0 && v >= 0.0 && w >= 0.0 {
-                let hit = RayHit{
-                    hit_point: r.origin + r.direction * dst,
-                    mat: self.mat.clone(),
-                    normal: (tri.norm1 * w + tri.norm2 * u + tri.norm3 * v).normalized(),
-                    hit_time: dst,
-                    front: true
-                };
-                if let Some(other_hit) = current_hit.clone() {
-                    // compare distances
-                    if dst < other_hit.hit_time {
-                        current_hit = Some(hit);
-                    }
-                } else {
-                    current_hit = Some(hit);
-                }
-            }
-        }
-        current_hit
+        // the rotation keeps lengths, so t is the same in object space
+        let inverse = self.rotation.reversed();
+        let local = Ray::new((r.origin - self.position).rotated_by(inverse), r.direction.rotated_by(inverse), r.color);
+        // closest triangle with its barycentrics, the hit record is only built for that one
+        let mut closest: Option<(usize, f64, f64, f64, f64)> = None;
+        let cull = !self.mat.two_sided();
+        let tests = self.bvh.traverse(&local, ray_tmax, |start, count, max_dst| {
+            // the last triangle fills the unused lanes
+            let corner = |c: usize| DVec3x4::from([0, 1, 2, 3].map(|k| self.data.triangle(start + k.min(count - 1))[c]));
+            let (lane, dst, w, u, v) = intersect_triangles(&local, [corner(0), corner(1), corner(2)], count, cull, ray_tmin, max_dst)?;
+            closest = Some((start + lane, dst, w, u, v));
+            Some(dst)
+        });
+        count_tests(tests);
+        let (t, dst, w, u, v) = closest?;
+        let tri = self.data.indices[t];
+        let [p0, p1, p2] = self.data.triangle(t);
+        // from the barycentrics rather than the ray, that keeps the error small
+        let (p0, p1, p2) = (p0 * w, p1 * u, p2 * v);
+        let p = p0 + p1 + p2;
+        let mut hit = RayHit{
+            hit_point: self.to_world(p),
+            mat: self.mat.clone(),
+            normal: DVec3::zero(),
+            geometric_normal: DVec3::zero(),
+            hit_time: dst,
+            front: true,
+            uv: self.data.uv(tri[0]) * w + self.data.uv(tri[1]) * u + self.data.uv(tri[2]) * v,
+            tangent: self.tangent(tri, w, u, v),
+            color: self.data.color(tri[0]) * w + self.data.color(tri[1]) * u + self.data.color(tri[2]) * v,
+            error: self.world_error(p, (p0.abs() + p1.abs() + p2.abs()) * gamma(7))
+        };
+        let geometric = self.data.face_normal(t).normalized().rotated_by(self.rotation);
+        hit.set_normals(r, geometric, self.shading_normal(tri, w, u, v).rotated_by(self.rotation));
+        Some(hit)
     }
 
     fn bounding_box_hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> bool {
@@ -279,6 +863,31 @@ impl Hittable for Mesh {
 
         true
     }
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        Some(self.mat.as_ref())
+    }
+
+    // hit counts the boxes and triangles it visits itself
+    fn primitive_count(&self) -> usize {
+        0
+    }
+
+    fn area(&self) -> f64 {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    fn sample_surface(&self) -> Option<(DVec3, DVec3)> {
+        let target = fastrand::f64() * self.area();
+        let i = self.area_cdf.partition_point(|a| *a < target).min(self.data.triangle_count().checked_sub(1)?);
+        let [p0, p1, p2] = self.data.triangle(i);
+        // uniform barycentrics
+        let su = fastrand::f64().sqrt();
+        let (b0, b1) = (1.0 - su, fastrand::f64() * su);
+        let p = p0 * b0 + p1 * b1 + p2 * (1.0 - b0 - b1);
+        let n = self.data.face_normal(i).normalized().rotated_by(self.rotation);
+        Some((self.to_world(p), n))
+    }
 }
 
 pub fn unit_samp() -> DVec3 {
@@ -298,56 +907,88 @@ fn unit_hemi_samp(normal: DVec3) -> DVec3 {
     if v.dot(normal) > 0.0 {v} else {-v}
 }
 
-fn environment_light(ray:Ray) -> DVec3 {
+pub fn environment_light(ray:Ray) -> DVec3 {
     let unit_direction = ray.direction.normalized();
     let a = 0.5 * (unit_direction.y + 1.0);
     let sky_gradient = (1.0 - a) * DVec3::new(0.68, 0.98, 1.00) + a * DVec3::new(0.5, 0.66, 1.0);
     
-    let sun = ray.direction.dot(DVec3::new(0.5, 0.5, 0.0)).max(0.0).powf(10.0) * 1.0;
-    //sky_gradient + DVec3::one() * sun
-    sky_gradient
+    // the sun is a DirectionalLight in the scene, sampled explicitly
+    ray.illuminant(sky_gradient)
 }
 
-pub fn ray_color(ray: Ray, world: &HittableList, depth:i32) -> DVec3 {
-    
-    if depth == 0 {return DVec3::zero()};
+pub fn square_samp(u:DVec3, v:DVec3) -> DVec3 {
+    let x = fastrand::f64() - 0.5;
+    let y = fastrand::f64() - 0.5;
+    (x * u) + (y * v)
+}
 
-    if let Some(rec) = get_world_hit(&ray, 0.001, f64::INFINITY, world)  {
-        if let Some(scattered) = rec.mat.scatter(&ray, &rec) {
-            if scattered.emissive {
-                return scattered.color;
-            } else {
-                let current_color = ray.color * scattered.color;
-                if current_color.x < 0.01 && current_color.y < 0.01 && current_color.z < 0.01 {
-                    return current_color;
-                } else {
-                    return current_color * ray_color(scattered, world, depth - 1);
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lights::PointLight;
+    use crate::materials::Lambertian;
+    use crate::obj_loader::generate_tangents;
+    use crate::specialty::ThinSheet;
+
+    // square of the given side in the xy plane with uvs running 0..tiles, bumped by a sine along u
+    fn bumped_square(side: f64, tiles: f64, bump_scale: f64) -> Mesh {
+        let mut data = MeshData::new(false);
+        for (x, y) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+            data.push_vertex(DVec3::new(x, y, 0.0) * side, DVec3::unit_z(), Some(DVec2::new(x, y) * tiles));
         }
+        data.indices = vec![[0, 1, 2], [0, 2, 3]];
+        generate_tangents(&mut data);
+        let width = 256;
+        let texels: Vec<DVec3> = (0..width).map(|x| DVec3::broadcast(0.5 + 0.5 * (2.0 * PI * (x as f64 + 0.5) / width as f64).sin())).collect();
+        let map = Texture{width, height: 1, data: texels, average: DVec3::broadcast(0.5)};
+        let mesh = Mesh::new(data, Box::new(Lambertian{albedo: DVec3::broadcast(0.5)}));
+        Mesh{bump_map: Some(Arc::new(map)), bump_scale, ..mesh}
     }
 
-    environment_light(ray)
-    //DVec3::zero()
-}
+    #[test]
+    fn bump_slope_follows_the_surface_not_the_uvs() {
+        let small = bumped_square(1.0, 1.0, 0.01);
+        let large = bumped_square(2.0, 1.0, 0.02);
+        let tiled = bumped_square(1.0, 2.0, 0.01);
+        let mut tilt: [f64; 2] = [0.0; 2];
+        for k in 1..20 {
+            let u = k as f64 / 40.0;
+            let (a, b) = (small.shading_normal([0, 1, 2], 1.0 - u - 0.1, u, 0.1), large.shading_normal([0, 1, 2], 1.0 - u - 0.1, u, 0.1));
+            // the same bumps at twice the size look the same
+            assert!((a - b).mag() < 1e-9, "{:?} {:?}", a, b);
+            tilt[0] = tilt[0].max(a.x.abs());
+            tilt[1] = tilt[1].max(tiled.shading_normal([0, 1, 2], 1.0 - u - 0.1, u, 0.1).x.abs());
+        }
+        // steepest slope of 0.01 * 0.5 * sin(2 pi x) is 0.01 * pi, twice that when the uvs tile twice
+        assert!((tilt[0] - 0.01 * PI).abs() < 1e-3, "{}", tilt[0]);
+        assert!((tilt[1] - 0.02 * PI).abs() < 2e-3, "{}", tilt[1]);
+    }
 
-pub fn depth_check(ray: Ray, world: &HittableList, depth:i32) -> i32 {
-    if depth == 0 {return 0};
+    // hit on a sheet in the xy plane, seen from above
+    fn sheet_hit(mat: Box<dyn Material + Sync>) -> (Ray, RayHit) {
+        let r = Ray::new(DVec3::unit_z(), -DVec3::unit_z(), DVec3::one());
+        let rec = RayHit{
+            hit_point: DVec3::zero(), normal: DVec3::unit_z(), geometric_normal: DVec3::unit_z(), mat, hit_time: 1.0, front: true,
+            uv: DVec2::zero(), tangent: DVec3::zero(), color: DVec3::one(), error: DVec3::zero()
+        };
+        (r, rec)
+    }
 
-    if let Some(rec) = get_world_hit(&ray, 0.001, f64::INFINITY, world)  {
-        if let Some(scattered) = rec.mat.scatter(&ray, &rec) {
-            if scattered.emissive {
-                return 1;
-            } else {
-                return depth_check(scattered, world, depth - 1) + 1;
-            }
+    #[test]
+    fn punctual_lights_shine_through_transmitting_surfaces() {
+        let sheet = ThinSheet{reflectance: DVec3::broadcast(0.2), transmittance: DVec3::broadcast(0.6)};
+        let light = |z: f64| -> HittableList {
+            vec![Box::new(PointLight{position: DVec3::new(0.0, 0.0, z), radius: 0.0, color: DVec3::one(), intensity: 4.0})]
+        };
+        for (z, expected) in [(2.0, 0.2), (-2.0, 0.6)] {
+            let world = light(z);
+            let (r, rec) = sheet_hit(Box::new(sheet.clone()));
+            let l = punctual_light(&world, &punctual_lights(&world), &r, &rec);
+            assert!((l - DVec3::broadcast(expected / PI)).mag() < 1e-9, "{} {:?}", z, l);
         }
+        // surfaces that only reflect stay dark from behind
+        let world = light(-2.0);
+        let (r, rec) = sheet_hit(Box::new(Lambertian{albedo: DVec3::one()}));
+        assert_eq!(punctual_light(&world, &punctual_lights(&world), &r, &rec), DVec3::zero());
     }
-    1
 }
-
-pub fn square_samp(u:DVec3, v:DVec3) -> DVec3 {
-    let x = fastrand::f64() - 0.5;
-    let y = fastrand::f64() - 0.5;
-    (x * u) + (y * v)
-}
\ No newline at end of file
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io::BufWriter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use ultraviolet::{DBivec3, DRotor3};
+use crate::controls::RenderSettings;
+use crate::navigation::ViewState;
+use crate::raytracing::HittableList;
+
+// everything needed to render a scene again. shapes and materials are saved with a "type" tag
+// naming their struct, loaded meshes and textures are stored inline rather than as paths
+#[derive(Deserialize)]
+pub struct Scene {
+    pub view: ViewState,
+    pub settings: RenderSettings,
+    pub world: HittableList,
+}
+
+#[derive(Serialize)]
+struct SceneRef<'a> {
+    view: &'a ViewState,
+    settings: &'a RenderSettings,
+    world: &'a HittableList,
+}
+
+pub fn save_scene(filename: &str, world: &HittableList, view: &ViewState, settings: &RenderSettings) -> Result<(), String> {
+    let file = File::create(filename).map_err(|e| format!("couldn't create {}: {}", filename, e))?;
+    serde_json::to_writer(BufWriter::new(file), &SceneRef{view, settings, world})
+        .map_err(|e| format!("couldn't save {}: {}", filename, e))
+}
+
+pub fn load_scene(filename: &str) -> Result<Scene, String> {
+    let data = std::fs::read(filename).map_err(|e| format!("couldn't open {}: {}", filename, e))?;
+    parse_scene(&data).map_err(|e| format!("couldn't load {}: {}", filename, e))
+}
+
+// the contents of a scene file, e.g. as sent to render farm workers
+pub fn parse_scene(data: &[u8]) -> Result<Scene, String> {
+    serde_json::from_slice(data).map_err(|e| e.to_string())
+}
+
+// rotors as [s, xy, xz, yz], ultraviolet only serializes the f32 ones
+pub fn serialize_rotor<S: Serializer>(r: &DRotor3, serializer: S) -> Result<S::Ok, S::Error> {
+    [r.s, r.bv.xy, r.bv.xz, r.bv.yz].serialize(serializer)
+}
+
+pub fn deserialize_rotor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DRotor3, D::Error> {
+    let [s, xy, xz, yz] = <[f64; 4]>::deserialize(deserializer)?;
+    Ok(DRotor3::new(s, DBivec3::new(xy, xz, yz)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use ultraviolet::{DVec3, Vec3};
+    use crate::color::OutputTransform;
+    use crate::hair::{Curve, CurveType, Curves};
+    use crate::integrator::IntegratorKind;
+    use crate::lights::{AreaLight, IesProfile, Intensity};
+    use crate::materials::{Layer, Lambertian, Metal, Weight};
+    use crate::obj_loader::MeshData;
+    use crate::point_cloud::{PointCloud, PointKind};
+    use crate::raytracing::{BoundingBox, Mesh, Sphere};
+    use crate::sdf::{Sdf, SdfShape};
+
+    const IES: &str = "IESNA:LM-63-2002\nTILT=NONE\n1 1000 2 3 2 1 2 0 0 0\n1.0 1.0 50\n\
+        0 45 90\n0 90\n100 50 0\n100, 25, 0\n";
+
+    fn lambertian() -> Box<Lambertian> {
+        Box::new(Lambertian{albedo: DVec3::new(0.5, 0.4, 0.3)})
+    }
+
+    fn to_json(scene: &SceneRef) -> String {
+        serde_json::to_string(scene).unwrap()
+    }
+
+    #[test]
+    fn scenes_round_trip() {
+        let mut data = MeshData::new(false);
+        for p in [DVec3::zero(), DVec3::unit_x(), DVec3::unit_z()] {
+            data.push_vertex(p, DVec3::unit_y(), None);
+        }
+        data.indices.push([0, 1, 2]);
+        let mut mesh = Mesh::new(data, lambertian());
+        mesh.transform(DVec3::new(1.0, 2.0, 3.0), DRotor3::from_rotation_xz(0.5));
+
+        let curve = Curve{points: [DVec3::zero(), DVec3::unit_y(), DVec3::new(1.0, 1.0, 0.0), DVec3::unit_x()], width: [0.1, 0.05]};
+        let points = vec![Vec3::zero(), Vec3::unit_x()];
+        let ies = Arc::new(IesProfile::parse(IES).unwrap());
+        let light = AreaLight::new(DVec3::one(), Intensity::Lumens(800.0), 4.0 * std::f64::consts::PI, false, None, Some(ies));
+        let coated = Layer{coat: Box::new(Metal{albedo: DVec3::one(), fuzz: 0.1}), base: lambertian(), weight: Weight::Constant(0.3), ior: 1.5};
+        let world: HittableList = vec![
+            Box::new(Sphere{center: DVec3::new(0.0, -1.0, 0.0), radius: 1.0, mat: Box::new(coated)}),
+            Box::new(mesh),
+            Box::new(PointCloud::new(points, vec![], vec![], 0.05, PointKind::Sphere, lambertian())),
+            Box::new(Curves::new(vec![curve], CurveType::Cylinder, lambertian())),
+            Box::new(SdfShape::new(Sdf::Torus{major: 1.0, minor: 0.25}, BoundingBox{min: DVec3::new(-1.25, -0.25, -1.25), max: DVec3::new(1.25, 0.25, 1.25)}, lambertian())),
+            Box::new(Sphere{center: DVec3::new(0.0, 4.0, 0.0), radius: 1.0, mat: Box::new(light)}),
+        ];
+        let view = ViewState{lookfrom: DVec3::new(13.0, 2.0, 3.0), lookat: DVec3::zero(), vup: DVec3::unit_y(), vfov: 20.0, defocus_angle: 0.1, focus_dist: 10.0, interacting: false};
+        let settings = RenderSettings{width: 64, height: 48, samples: 2, max_depth: 16, spectral: true, integrator: IntegratorKind::Bidirectional,
+            target_passes: 8, output: OutputTransform::default(), paused: false, generation: 0};
+
+        let saved = to_json(&SceneRef{view: &view, settings: &settings, world: &world});
+        let scene = parse_scene(saved.as_bytes()).unwrap();
+        assert_eq!(scene.world.len(), world.len());
+        assert_eq!(to_json(&SceneRef{view: &scene.view, settings: &scene.settings, world: &scene.world}), saved);
+    }
+}
@@ -0,0 +1,242 @@
+use ultraviolet::{DVec2, DVec3};
+use crate::materials::Material;
+use crate::raytracing::{BoundingBox, Hittable, Ray, RayHit};
+use serde::{Deserialize, Serialize};
+
+// distance function tree. operators that bend space (twist) or blend shapes overestimate the
+// distance, SdfShape::step makes up for that
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Sdf {
+    Sphere{radius: f64},
+    Box{half_size: DVec3},
+    // in the xz plane
+    Torus{major: f64, minor: f64},
+    // along y
+    Cylinder{radius: f64, half_height: f64},
+    // power 8 gives the classic bulb, centered on the origin with a radius of about 1.2
+    Mandelbulb{power: f64, iterations: usize},
+    Translate{offset: DVec3, shape: Box<Sdf>},
+    Scale{factor: f64, shape: Box<Sdf>},
+    // blends over a distance of k, 0 gives the sharp union
+    SmoothUnion{a: Box<Sdf>, b: Box<Sdf>, k: f64},
+    // cuts b out of a
+    SmoothSubtraction{a: Box<Sdf>, b: Box<Sdf>, k: f64},
+    // rotates the xz plane by rate radians per unit of y
+    Twist{rate: f64, shape: Box<Sdf>},
+    // copies the shape every period along each axis, 0 leaves an axis alone
+    Repeat{period: DVec3, shape: Box<Sdf>},
+}
+
+// polynomial smooth minimum by inigo quilez
+fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+    if k <= 0.0 {return a.min(b)};
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+    b + (a - b) * h - k * h * (1.0 - h)
+}
+
+fn mandelbulb(p: DVec3, power: f64, iterations: usize) -> f64 {
+    let mut z = p;
+    let mut dr = 1.0;
+    let mut r = 0.0;
+    for _ in 0..iterations {
+        r = z.mag();
+        if r > 2.0 || r == 0.0 {break};
+        let theta = (z.z / r).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+        let zr = r.powf(power);
+        z = DVec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()) * zr + p;
+    }
+    if r == 0.0 {return 0.0};
+    0.5 * r.ln() * r / dr
+}
+
+impl Sdf {
+    // signed distance from p to the surface, negative inside
+    pub fn distance(&self, p: DVec3) -> f64 {
+        match self {
+            Sdf::Sphere{radius} => p.mag() - radius,
+            Sdf::Box{half_size} => {
+                let q = p.abs() - *half_size;
+                q.max_by_component(DVec3::zero()).mag() + q.component_max().min(0.0)
+            }
+            Sdf::Torus{major, minor} => DVec2::new(DVec2::new(p.x, p.z).mag() - major, p.y).mag() - minor,
+            Sdf::Cylinder{radius, half_height} => {
+                let d = DVec2::new(DVec2::new(p.x, p.z).mag() - radius, p.y.abs() - half_height);
+                d.max_by_component(DVec2::zero()).mag() + d.x.max(d.y).min(0.0)
+            }
+            Sdf::Mandelbulb{power, iterations} => mandelbulb(p, *power, *iterations),
+            Sdf::Translate{offset, shape} => shape.distance(p - *offset),
+            Sdf::Scale{factor, shape} => shape.distance(p / *factor) * factor,
+            Sdf::SmoothUnion{a, b, k} => smooth_min(a.distance(p), b.distance(p), *k),
+            Sdf::SmoothSubtraction{a, b, k} => -smooth_min(-a.distance(p), b.distance(p), *k),
+            Sdf::Twist{rate, shape} => {
+                let (s, c) = (rate * p.y).sin_cos();
+                shape.distance(DVec3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z))
+            }
+            Sdf::Repeat{period, shape} => {
+                let wrap = |x: f64, period: f64| if period > 0.0 {x - period * (x / period).round()} else {x};
+                shape.distance(DVec3::new(wrap(p.x, period.x), wrap(p.y, period.y), wrap(p.z, period.z)))
+            }
+        }
+    }
+}
+
+// sphere traced distance function, only looked for inside bounds
+#[derive(Serialize, Deserialize)]
+pub struct SdfShape {
+    pub sdf: Sdf,
+    pub mat: Box<dyn Material + Sync + Send>,
+    // has to contain the whole surface, repeated shapes are cut off at its sides
+    pub bounds: BoundingBox,
+    // fraction of the distance bound taken per step, below 1 for twisted or blended trees
+    pub step: f64,
+    // distance counted as touching the surface, also the normal's finite difference
+    pub epsilon: f64,
+    pub max_steps: usize,
+}
+
+impl SdfShape {
+    pub fn new(sdf: Sdf, bounds: BoundingBox, mat: Box<dyn Material + Sync + Send>) -> SdfShape {
+        SdfShape{sdf, mat, bounds, step: 1.0, epsilon: 1e-5, max_steps: 512}
+    }
+
+    // gradient from the four point tetrahedron difference
+    fn normal(&self, p: DVec3) -> DVec3 {
+        let h = self.epsilon;
+        let k = [DVec3::new(1.0, -1.0, -1.0), DVec3::new(-1.0, -1.0, 1.0), DVec3::new(-1.0, 1.0, -1.0), DVec3::new(1.0, 1.0, 1.0)];
+        let n = k.iter().fold(DVec3::zero(), |n, k| n + *k * self.sdf.distance(p + *k * h));
+        if n.mag_sq() > 0.0 {n.normalized()} else {DVec3::unit_y()}
+    }
+}
+
+#[typetag::serde]
+impl Hittable for SdfShape {
+    fn hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> Option<RayHit> {
+        let (t0, t1) = self.bounds.hit(r, ray_tmin, ray_tmax)?;
+        let speed = r.direction.mag();
+        // rays leaving the inside of the shape march on the negated distance
+        let side = if self.sdf.distance(r.clone().at(t0)) < 0.0 {-1.0} else {1.0};
+        let mut t = t0;
+        for _ in 0..self.max_steps {
+            let p = r.clone().at(t);
+            let d = side * self.sdf.distance(p);
+            if d < self.epsilon {
+                if t <= ray_tmin {return None};
+                let mut rec = RayHit{
+                    hit_point: p,
+                    mat: self.mat.clone(),
+                    normal: DVec3::zero(),
+                    geometric_normal: DVec3::zero(),
+                    hit_time: t,
+                    front: true,
+                    uv: DVec2::zero(),
+                    tangent: DVec3::zero(),
+                    color: DVec3::one(),
+                    // the surface is only known to within epsilon, spawned rays have to clear that shell
+                    error: DVec3::broadcast(self.epsilon * 4.0)
+                };
+                rec.set_face_normal(r, self.normal(p));
+                return Some(rec);
+            }
+            t += d * self.step / speed;
+            if t > t1 {return None};
+        }
+        None
+    }
+
+    fn bounding_box_hit(&self, r: &Ray, ray_tmin: f64, ray_tmax: f64) -> bool {
+        self.bounds.hit(r, ray_tmin, ray_tmax).is_some()
+    }
+
+    fn material(&self) -> Option<&(dyn Material + Sync + Send + 'static)> {
+        Some(self.mat.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Lambertian;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn primitive_distances() {
+        let sphere = Sdf::Sphere{radius: 1.0};
+        assert!(close(sphere.distance(DVec3::new(0.0, 3.0, 0.0)), 2.0));
+        assert!(close(sphere.distance(DVec3::zero()), -1.0));
+
+        let cube = Sdf::Box{half_size: DVec3::new(1.0, 2.0, 3.0)};
+        assert!(close(cube.distance(DVec3::new(3.0, 0.0, 0.0)), 2.0));
+        assert!(close(cube.distance(DVec3::new(2.0, 3.0, 3.0)), 2f64.sqrt()));
+        assert!(close(cube.distance(DVec3::new(0.5, 0.0, 0.0)), -0.5));
+
+        let torus = Sdf::Torus{major: 2.0, minor: 0.5};
+        assert!(close(torus.distance(DVec3::new(2.0, 0.0, 0.0)), -0.5));
+        assert!(close(torus.distance(DVec3::new(0.0, 0.0, 0.0)), 1.5));
+        assert!(close(torus.distance(DVec3::new(0.0, 1.0, -2.0)), 0.5));
+
+        let cylinder = Sdf::Cylinder{radius: 1.0, half_height: 2.0};
+        assert!(close(cylinder.distance(DVec3::new(0.0, 0.0, 3.0)), 2.0));
+        assert!(close(cylinder.distance(DVec3::new(0.0, 5.0, 0.0)), 3.0));
+        assert!(close(cylinder.distance(DVec3::new(0.0, 1.0, 0.5)), -0.5));
+    }
+
+    #[test]
+    fn operator_distances() {
+        let unit = || Box::new(Sdf::Sphere{radius: 1.0});
+        let moved = Sdf::Translate{offset: DVec3::new(5.0, 0.0, 0.0), shape: unit()};
+        assert!(close(moved.distance(DVec3::new(5.0, 2.0, 0.0)), 1.0));
+        let scaled = Sdf::Scale{factor: 3.0, shape: unit()};
+        assert!(close(scaled.distance(DVec3::new(0.0, 0.0, 4.0)), 1.0));
+
+        let far = || Box::new(Sdf::Translate{offset: DVec3::new(3.0, 0.0, 0.0), shape: unit()});
+        let union = Sdf::SmoothUnion{a: unit(), b: far(), k: 0.0};
+        assert!(close(union.distance(DVec3::new(-2.0, 0.0, 0.0)), 1.0));
+        assert!(close(union.distance(DVec3::new(4.5, 0.0, 0.0)), 0.5));
+        // blending only ever adds material
+        let blend = Sdf::SmoothUnion{a: unit(), b: far(), k: 0.5};
+        assert!(blend.distance(DVec3::new(1.5, 0.0, 0.0)) < union.distance(DVec3::new(1.5, 0.0, 0.0)));
+
+        let cut = Sdf::SmoothSubtraction{a: Box::new(Sdf::Sphere{radius: 2.0}), b: unit(), k: 0.0};
+        assert!(close(cut.distance(DVec3::zero()), 1.0));
+        assert!(close(cut.distance(DVec3::new(1.5, 0.0, 0.0)), -0.5));
+
+        // twisting something round about y changes nothing
+        let twisted = Sdf::Twist{rate: 1.3, shape: Box::new(Sdf::Cylinder{radius: 1.0, half_height: 2.0})};
+        assert!(close(twisted.distance(DVec3::new(0.7, 0.4, 2.1)), Sdf::Cylinder{radius: 1.0, half_height: 2.0}.distance(DVec3::new(0.7, 0.4, 2.1))));
+
+        let grid = Sdf::Repeat{period: DVec3::new(4.0, 0.0, 4.0), shape: unit()};
+        assert!(close(grid.distance(DVec3::new(8.0, 0.0, -4.0)), -1.0));
+        assert!(close(grid.distance(DVec3::new(10.0, 0.0, 0.0)), 1.0));
+        assert!(close(grid.distance(DVec3::new(0.0, 4.0, 0.0)), 3.0));
+    }
+
+    #[test]
+    fn mandelbulb_distance_is_a_bound() {
+        let bulb = Sdf::Mandelbulb{power: 8.0, iterations: 12};
+        // everything lies within about 1.2 of the center, the estimate never overshoots that
+        for p in [DVec3::new(3.0, 0.0, 0.0), DVec3::new(0.0, -2.0, 2.0), DVec3::new(1.5, 1.5, 1.5)] {
+            let d = bulb.distance(p);
+            assert!(d > 0.0 && d <= p.mag() - 1.0, "{:?} {}", p, d);
+        }
+        assert!(bulb.distance(DVec3::new(0.0, 0.0, 0.5)) < 1e-3);
+    }
+
+    #[test]
+    fn marching_finds_the_surface() {
+        let bounds = BoundingBox{min: DVec3::broadcast(-1.5), max: DVec3::broadcast(1.5)};
+        let shape = SdfShape::new(Sdf::Sphere{radius: 1.0}, bounds, Box::new(Lambertian{albedo: DVec3::one()}));
+        let r = Ray::new(DVec3::new(0.3, 0.0, -5.0), DVec3::new(0.0, 0.0, 2.0), DVec3::one());
+        let rec = shape.hit(&r, 1e-3, f64::INFINITY).unwrap();
+        let z = -(1.0 - 0.3f64 * 0.3).sqrt();
+        assert!((rec.hit_point - DVec3::new(0.3, 0.0, z)).mag() < 1e-4);
+        assert!((rec.hit_time - (z + 5.0) / 2.0).abs() < 1e-4);
+        assert!((rec.normal - DVec3::new(0.3, 0.0, z)).mag() < 1e-3);
+        let miss = Ray::new(DVec3::new(1.2, 0.0, -5.0), DVec3::unit_z(), DVec3::one());
+        assert!(shape.hit(&miss, 1e-3, f64::INFINITY).is_none());
+    }
+}
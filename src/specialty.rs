@@ -0,0 +1,327 @@
+use std::f64::consts::PI;
+use ultraviolet::DVec3;
+use crate::color::{luminance, xyz_to_srgb};
+use crate::materials::Material;
+use crate::principled::{Frame, cosine_sample, ggx_d, ggx_g, ggx_sample, reflect, schlick_weight};
+use crate::raytracing::{Ray, RayHit};
+use crate::spectral::{LAMBDA_MAX, LAMBDA_MIN, cie_xyz};
+use serde::{Deserialize, Serialize};
+
+// wavelengths the thin film is evaluated at when tracing rgb
+const FILM_SAMPLES: usize = 16;
+
+// direction the light leaves in, back along the incoming ray
+fn outgoing(r_in: &Ray) -> DVec3 {
+    -r_in.direction.normalized()
+}
+
+// conductor with an anisotropic ggx lobe, the highlight stretches across the brushing direction
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BrushedMetal {
+    // reflectance at normal incidence
+    pub albedo: DVec3,
+    // roughness along and across the tangent
+    pub roughness_u: f64,
+    pub roughness_v: f64,
+    // brushing direction in world space, projected onto the surface. None brushes along the
+    // surface's own tangent, the u direction of meshes with uvs
+    pub tangent: Option<DVec3>,
+}
+
+impl BrushedMetal {
+    fn alphas(&self) -> (f64, f64) {
+        ((self.roughness_u * self.roughness_u).max(1e-3), (self.roughness_v * self.roughness_v).max(1e-3))
+    }
+
+    fn frame(&self, rec: &RayHit) -> Frame {
+        Frame::with_tangent(rec.normal, self.tangent.unwrap_or(rec.tangent))
+    }
+}
+
+#[typetag::serde]
+impl Material for BrushedMetal {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let frame = self.frame(rec);
+        let wo = frame.to_local(outgoing(r_in));
+        if wo.z <= 0.0 {return None};
+        let (ax, ay) = self.alphas();
+        let h = ggx_sample(ax, ay);
+        let wi = reflect(wo, h);
+        if wi.z <= 0.0 {return None};
+        let f0 = r_in.reflectance(self.albedo);
+        let fresnel = f0 + (DVec3::one() - f0) * schlick_weight(wo.dot(h));
+        // f cos / pdf with the pdf D cos(h) / (4 wo.h)
+        let weight = fresnel * (ggx_g(wo, wi, ax, ay) * wo.dot(h) / (wo.z * h.z));
+        Some(r_in.scatter_from(rec, frame.to_world(wi), r_in.color * weight))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let frame = self.frame(rec);
+        let (wo, wi) = (frame.to_local(outgoing(r_in)), frame.to_local(wi.normalized()));
+        if wo.z <= 0.0 || wi.z <= 0.0 {return DVec3::zero()};
+        let (ax, ay) = self.alphas();
+        let h = (wo + wi).normalized();
+        let f0 = r_in.reflectance(self.albedo);
+        let fresnel = f0 + (DVec3::one() - f0) * schlick_weight(wo.dot(h));
+        fresnel * (ggx_d(h, ax, ay) * ggx_g(wo, wi, ax, ay) / (4.0 * wo.z * wi.z))
+    }
+
+    fn pdf(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        let frame = self.frame(rec);
+        let (wo, wi) = (frame.to_local(outgoing(r_in)), frame.to_local(wi.normalized()));
+        if wo.z <= 0.0 || wi.z <= 0.0 {return 0.0};
+        let (ax, ay) = self.alphas();
+        let h = (wo + wi).normalized();
+        ggx_d(h, ax, ay) * h.z / (4.0 * wo.dot(h))
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.albedo
+    }
+}
+
+// smooth surface under a thin coating whose interference tints the reflection by angle and thickness.
+// a substrate ior of 1 is a free standing film like a soap bubble, light then passes straight through
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ThinFilm {
+    // in nanometers
+    pub thickness: f64,
+    pub film_ior: f64,
+    pub substrate_ior: f64,
+}
+
+// fresnel amplitude coefficients for s and p polarization, or None past the critical angle
+fn fresnel_amplitudes(cos_i: f64, n1: f64, n2: f64) -> Option<(f64, f64, f64)> {
+    let sin2_t = (n1 / n2).powi(2) * (1.0 - cos_i * cos_i);
+    if sin2_t >= 1.0 {return None};
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let rs = (n1 * cos_i - n2 * cos_t) / (n1 * cos_i + n2 * cos_t);
+    let rp = (n2 * cos_i - n1 * cos_t) / (n2 * cos_i + n1 * cos_t);
+    Some((rs, rp, cos_t))
+}
+
+impl ThinFilm {
+    // airy reflectance of the film at one wavelength, averaged over both polarizations
+    fn reflectance(&self, cos_i: f64, n_outside: f64, n_inside: f64, lambda: f64) -> f64 {
+        let Some((rs12, rp12, cos_f)) = fresnel_amplitudes(cos_i, n_outside, self.film_ior) else {return 1.0};
+        let Some((rs23, rp23, _)) = fresnel_amplitudes(cos_f, self.film_ior, n_inside) else {return 1.0};
+        let phase = 4.0 * PI * self.film_ior * self.thickness * cos_f / lambda;
+        let airy = |r12: f64, r23: f64| {
+            let cross = 2.0 * r12 * r23 * phase.cos();
+            (r12 * r12 + r23 * r23 + cross) / (1.0 + r12 * r12 * r23 * r23 + cross)
+        };
+        ((airy(rs12, rs23) + airy(rp12, rp23)) / 2.0).clamp(0.0, 1.0)
+    }
+
+    // per wavelength in spectral mode, otherwise the spectrum is integrated against the cie curves
+    // and normalized so that a flat reflectance stays white
+    fn reflectance_color(&self, r_in: &Ray, cos_i: f64, n_outside: f64, n_inside: f64) -> DVec3 {
+        if let Some(w) = &r_in.wavelengths {
+            return w.lambda.map(|l| self.reflectance(cos_i, n_outside, n_inside, l));
+        }
+        let (mut xyz, mut white) = (DVec3::zero(), DVec3::zero());
+        for i in 0..FILM_SAMPLES {
+            let lambda = LAMBDA_MIN + (i as f64 + 0.5) * (LAMBDA_MAX - LAMBDA_MIN) / FILM_SAMPLES as f64;
+            let cmf = cie_xyz(lambda);
+            xyz += cmf * self.reflectance(cos_i, n_outside, n_inside, lambda);
+            white += cmf;
+        }
+        let (rgb, white) = (xyz_to_srgb() * xyz, xyz_to_srgb() * white);
+        (rgb / white).max_by_component(DVec3::zero()).min_by_component(DVec3::one())
+    }
+}
+
+#[typetag::serde]
+impl Material for ThinFilm {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let unit_direction = r_in.direction.normalized();
+        let cos_i = (-unit_direction).dot(rec.normal).clamp(0.0, 1.0);
+        let (n_outside, n_inside) = if rec.front {(1.0, self.substrate_ior)} else {(self.substrate_ior, 1.0)};
+        let mirrored = unit_direction - 2.0 * unit_direction.dot(rec.normal) * rec.normal;
+
+        // past the critical angle of the substrate nothing gets through, whatever the film does
+        let Some((_, _, cos_t)) = fresnel_amplitudes(cos_i, n_outside, n_inside) else {
+            return Some(r_in.scatter_from(rec, mirrored, r_in.color));
+        };
+        let r = self.reflectance_color(r_in, cos_i, n_outside, n_inside);
+        let p = (r.dot(DVec3::one()) / 3.0).clamp(1e-3, 1.0 - 1e-3);
+        if fastrand::f64() < p {
+            return Some(r_in.scatter_from(rec, mirrored, r_in.color * r / p));
+        }
+        let eta = n_outside / n_inside;
+        let direction = eta * unit_direction + (eta * cos_i - cos_t) * rec.normal;
+        Some(r_in.scatter_from(rec, direction, r_in.color * (DVec3::one() - r) / (1.0 - p)))
+    }
+}
+
+// paper, leaves and lampshades: diffuse reflection on the lit side and diffuse transmission to the other
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ThinSheet {
+    pub reflectance: DVec3,
+    pub transmittance: DVec3,
+}
+
+impl ThinSheet {
+    fn reflect_probability(&self) -> f64 {
+        let (r, t) = (luminance(self.reflectance), luminance(self.transmittance));
+        if r + t <= 0.0 {1.0} else {r / (r + t)}
+    }
+}
+
+#[typetag::serde]
+impl Material for ThinSheet {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let frame = Frame::new(rec.normal);
+        let p = self.reflect_probability();
+        let mut wi = cosine_sample();
+        let color = if fastrand::f64() < p {
+            r_in.reflectance(self.reflectance) / p
+        } else {
+            wi.z = -wi.z;
+            r_in.reflectance(self.transmittance) / (1.0 - p)
+        };
+        Some(r_in.scatter_from(rec, frame.to_world(wi), r_in.color * color))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        if wi.dot(rec.normal) >= 0.0 {r_in.reflectance(self.reflectance) / PI} else {r_in.reflectance(self.transmittance) / PI}
+    }
+
+    fn pdf(&self, _r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        let cos = wi.normalized().dot(rec.normal);
+        let p = self.reflect_probability();
+        if cos >= 0.0 {p * cos / PI} else {(1.0 - p) * -cos / PI}
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.reflectance
+    }
+}
+
+// cloth: a diffuse base under a retro and grazing sheen from fibers, estevez and kulla's charlie sheen
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Velvet {
+    pub base: DVec3,
+    pub sheen: DVec3,
+    pub roughness: f64,
+}
+
+impl Velvet {
+    fn f(&self, r_in: &Ray, wo: DVec3, wi: DVec3) -> DVec3 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {return DVec3::zero()};
+        let h = (wo + wi).normalized();
+        let inv_alpha = 1.0 / self.roughness.clamp(0.07, 1.0).powi(2);
+        let sin_h = (1.0 - h.z * h.z).max(0.0).sqrt();
+        let d = (2.0 + inv_alpha) * sin_h.powf(inv_alpha) / (2.0 * PI);
+        // neubelt and pettineo's visibility term
+        let v = 1.0 / (4.0 * (wi.z + wo.z - wi.z * wo.z));
+        r_in.reflectance(self.base) / PI + r_in.reflectance(self.sheen) * d * v
+    }
+}
+
+#[typetag::serde]
+impl Material for Velvet {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let frame = Frame::new(rec.normal);
+        let wo = frame.to_local(outgoing(r_in));
+        let wi = cosine_sample();
+        let weight = self.f(r_in, wo, wi) * PI;
+        Some(r_in.scatter_from(rec, frame.to_world(wi), r_in.color * weight))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let frame = Frame::new(rec.normal);
+        self.f(r_in, frame.to_local(outgoing(r_in)), frame.to_local(wi.normalized()))
+    }
+
+    fn pdf(&self, _r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        wi.normalized().dot(rec.normal).max(0.0) / PI
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.base
+    }
+}
+
+// rough diffuse, sigma is the standard deviation of the facet slopes in radians. sigma 0 is lambertian
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OrenNayar {
+    pub albedo: DVec3,
+    pub sigma: f64,
+}
+
+impl OrenNayar {
+    fn f(&self, r_in: &Ray, wo: DVec3, wi: DVec3) -> DVec3 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {return DVec3::zero()};
+        let s2 = self.sigma * self.sigma;
+        let a = 1.0 - 0.5 * s2 / (s2 + 0.33);
+        let b = 0.45 * s2 / (s2 + 0.09);
+        let sin_o = (1.0 - wo.z * wo.z).max(0.0).sqrt();
+        let sin_i = (1.0 - wi.z * wi.z).max(0.0).sqrt();
+        // cos(phi_i - phi_o) from the projections onto the tangent plane
+        let cos_phi = if sin_o > 1e-4 && sin_i > 1e-4 {
+            ((wo.x * wi.x + wo.y * wi.y) / (sin_o * sin_i)).max(0.0)
+        } else {0.0};
+        let (sin_alpha, tan_beta) = if wi.z > wo.z {(sin_o, sin_i / wi.z)} else {(sin_i, sin_o / wo.z)};
+        r_in.reflectance(self.albedo) / PI * (a + b * cos_phi * sin_alpha * tan_beta)
+    }
+}
+
+#[typetag::serde]
+impl Material for OrenNayar {
+    fn scatter(&self, r_in: &Ray, rec: &RayHit) -> Option<Ray> {
+        let frame = Frame::new(rec.normal);
+        let wo = frame.to_local(outgoing(r_in));
+        let wi = cosine_sample();
+        let weight = self.f(r_in, wo, wi) * PI;
+        Some(r_in.scatter_from(rec, frame.to_world(wi), r_in.color * weight))
+    }
+
+    fn eval(&self, r_in: &Ray, rec: &RayHit, wi: DVec3) -> DVec3 {
+        let frame = Frame::new(rec.normal);
+        self.f(r_in, frame.to_local(outgoing(r_in)), frame.to_local(wi.normalized()))
+    }
+
+    fn pdf(&self, _r_in: &Ray, rec: &RayHit, wi: DVec3) -> f64 {
+        wi.normalized().dot(rec.normal).max(0.0) / PI
+    }
+
+    fn is_delta(&self) -> bool {
+        false
+    }
+
+    fn albedo(&self) -> DVec3 {
+        self.albedo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Lambertian;
+    use ultraviolet::DVec2;
+
+    #[test]
+    fn brushing_direction_wins_over_the_surface_tangent() {
+        let rec = RayHit{
+            hit_point: DVec3::zero(), normal: DVec3::unit_z(), geometric_normal: DVec3::unit_z(),
+            mat: Box::new(Lambertian{albedo: DVec3::one()}), hit_time: 1.0, front: true,
+            uv: DVec2::zero(), tangent: DVec3::unit_y(), color: DVec3::one(), error: DVec3::zero()
+        };
+        let metal = |tangent| BrushedMetal{albedo: DVec3::one(), roughness_u: 0.1, roughness_v: 0.4, tangent};
+        assert_eq!(metal(Some(DVec3::new(1.0, 0.0, 0.5))).frame(&rec).t, DVec3::unit_x());
+        assert_eq!(metal(None).frame(&rec).t, DVec3::unit_y());
+    }
+}
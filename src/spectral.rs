@@ -0,0 +1,215 @@
+use std::sync::OnceLock;
+use ultraviolet::DVec3;
+use crate::color::xyz_to_srgb;
+use serde::{Deserialize, Serialize};
+
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 720.0;
+// wavelengths carried by every path, the first one is the hero
+pub const WAVELENGTH_COUNT: usize = 3;
+
+// smits' rgb to spectrum basis, 10 bins spanning LAMBDA_MIN..LAMBDA_MAX
+const SMITS_WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// CIE standard illuminant D65, 10nm steps from 380nm to 720nm
+const D65: [f64; 35] = [
+    49.98, 54.65, 82.75, 91.49, 93.43, 86.68, 104.86, 117.01, 117.81, 114.86,
+    115.92, 108.81, 109.35, 107.80, 104.79, 107.69, 104.41, 104.05, 100.00, 96.33,
+    95.79, 88.69, 90.01, 89.60, 87.70, 83.29, 83.70, 80.03, 80.21, 82.28,
+    78.28, 69.72, 71.61, 74.35, 61.60,
+];
+
+fn lerp_table(table: &[f64], lambda: f64) -> f64 {
+    let n = table.len();
+    // table entries sit at bin centers
+    let bin = (LAMBDA_MAX - LAMBDA_MIN) / n as f64;
+    let x = ((lambda - LAMBDA_MIN) / bin - 0.5).clamp(0.0, (n - 1) as f64);
+    let i = (x.floor() as usize).min(n - 2);
+    let f = x - i as f64;
+    table[i] * (1.0 - f) + table[i + 1] * f
+}
+
+fn d65(lambda: f64) -> f64 {
+    let x = ((lambda - LAMBDA_MIN) / 10.0).clamp(0.0, (D65.len() - 1) as f64);
+    let i = (x.floor() as usize).min(D65.len() - 2);
+    let f = x - i as f64;
+    D65[i] * (1.0 - f) + D65[i + 1] * f
+}
+
+fn lobe(x: f64, mu: f64, sigma_lo: f64, sigma_hi: f64) -> f64 {
+    let t = (x - mu) / if x < mu {sigma_lo} else {sigma_hi};
+    (-0.5 * t * t).exp()
+}
+
+// CIE 1931 2 degree observer, wyman et al. multi-lobe fit
+pub fn cie_xyz(lambda: f64) -> DVec3 {
+    let x = 1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8);
+    DVec3::new(x, y, z)
+}
+
+// luminance of D65 over the sampled range, used so an rgb (1, 1, 1) emitter comes out as white with Y = 1
+fn d65_luminance() -> f64 {
+    static Y: OnceLock<f64> = OnceLock::new();
+    *Y.get_or_init(|| {
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        (0..steps).map(|i| {
+            let lambda = LAMBDA_MIN + i as f64 + 0.5;
+            d65(lambda) * cie_xyz(lambda).y
+        }).sum()
+    })
+}
+
+// smits' method, a smooth reflectance spectrum in [0, 1] that reproduces the rgb color
+pub fn rgb_to_reflectance(rgb: DVec3, lambda: f64) -> f64 {
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    let s = |table: &[f64; 10]| lerp_table(table, lambda);
+    if r <= g && r <= b {
+        if g <= b {
+            r * s(&SMITS_WHITE) + (g - r) * s(&SMITS_CYAN) + (b - g) * s(&SMITS_BLUE)
+        } else {
+            r * s(&SMITS_WHITE) + (b - r) * s(&SMITS_CYAN) + (g - b) * s(&SMITS_GREEN)
+        }
+    } else if g <= r && g <= b {
+        if r <= b {
+            g * s(&SMITS_WHITE) + (r - g) * s(&SMITS_MAGENTA) + (b - r) * s(&SMITS_BLUE)
+        } else {
+            g * s(&SMITS_WHITE) + (b - g) * s(&SMITS_MAGENTA) + (r - b) * s(&SMITS_RED)
+        }
+    } else if r <= g {
+        b * s(&SMITS_WHITE) + (r - b) * s(&SMITS_YELLOW) + (g - r) * s(&SMITS_GREEN)
+    } else {
+        b * s(&SMITS_WHITE) + (g - b) * s(&SMITS_YELLOW) + (r - g) * s(&SMITS_RED)
+    }
+}
+
+// emission spectra are reflectance spectra lit by D65
+pub fn rgb_to_illuminant(rgb: DVec3, lambda: f64) -> f64 {
+    rgb_to_reflectance(rgb, lambda) * d65(lambda) / d65_luminance()
+}
+
+// hero wavelength sampling: one uniform wavelength plus evenly rotated companions
+#[derive(Clone, Copy)]
+pub struct Wavelengths {
+    pub lambda: DVec3,
+    // set once a wavelength dependent event has killed the companions
+    pub secondary_terminated: bool,
+}
+
+impl Wavelengths {
+    pub fn sample() -> Wavelengths {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let hero = fastrand::f64() * range;
+        let offset = |i: usize| LAMBDA_MIN + (hero + i as f64 * range / WAVELENGTH_COUNT as f64) % range;
+        Wavelengths{lambda: DVec3::new(offset(0), offset(1), offset(2)), secondary_terminated: false}
+    }
+
+    pub fn hero(&self) -> f64 {
+        self.lambda.x
+    }
+
+    pub fn reflectance(&self, rgb: DVec3) -> DVec3 {
+        self.lambda.map(|l| rgb_to_reflectance(rgb, l))
+    }
+
+    pub fn illuminant(&self, rgb: DVec3) -> DVec3 {
+        self.lambda.map(|l| rgb_to_illuminant(rgb, l))
+    }
+
+    // drops the companion wavelengths, returns the throughput weight that keeps the hero unbiased
+    pub fn terminate_secondary(&mut self) -> DVec3 {
+        if self.secondary_terminated {return DVec3::new(1.0, 0.0, 0.0)};
+        self.secondary_terminated = true;
+        DVec3::new(WAVELENGTH_COUNT as f64, 0.0, 0.0)
+    }
+
+    // monte carlo estimate of XYZ from per wavelength radiance, then linear rec.709
+    pub fn radiance_to_rgb(&self, radiance: DVec3) -> DVec3 {
+        let pdf = 1.0 / (LAMBDA_MAX - LAMBDA_MIN);
+        let mut xyz = DVec3::zero();
+        for i in 0..WAVELENGTH_COUNT {
+            xyz += cie_xyz(self.lambda[i]) * radiance[i] / pdf;
+        }
+        xyz_to_srgb() * (xyz / WAVELENGTH_COUNT as f64)
+    }
+}
+
+// wavelength dependent index of refraction, wavelengths in nm
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum Dispersion {
+    None,
+    // n = a + b / lambda^2, lambda in micrometres
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + sum(b_i lambda^2 / (lambda^2 - c_i)), lambda in micrometres
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+impl Dispersion {
+    // cauchy fit through the d line index and the abbe number
+    pub fn from_abbe(ior: f64, abbe: f64) -> Dispersion {
+        let (d, f, c) = (0.5876_f64, 0.4861_f64, 0.6563_f64);
+        let b = (ior - 1.0) / (abbe * (1.0 / (f * f) - 1.0 / (c * c)));
+        Dispersion::Cauchy{a: ior - b / (d * d), b}
+    }
+
+    pub fn bk7() -> Dispersion {
+        Dispersion::Sellmeier{
+            b: [1.03961212, 0.231792344, 1.01046945],
+            c: [0.00600069867, 0.0200179144, 103.560653],
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Dispersion::None)
+    }
+
+    pub fn ior(&self, base_ior: f64, lambda: f64) -> f64 {
+        let um = lambda / 1000.0;
+        let um2 = um * um;
+        match self {
+            Dispersion::None => base_ior,
+            Dispersion::Cauchy{a, b} => a + b / um2,
+            Dispersion::Sellmeier{b, c} => {
+                (1.0 + (0..3).map(|i| b[i] * um2 / (um2 - c[i])).sum::<f64>()).sqrt()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fraunhofer d, f and c lines in nm
+    const D: f64 = 587.56;
+    const F: f64 = 486.13;
+    const C: f64 = 656.27;
+
+    fn abbe(dispersion: &Dispersion) -> f64 {
+        (dispersion.ior(0.0, D) - 1.0) / (dispersion.ior(0.0, F) - dispersion.ior(0.0, C))
+    }
+
+    #[test]
+    fn bk7_matches_the_catalog() {
+        let glass = Dispersion::bk7();
+        assert!((glass.ior(1.5, D) - 1.5168).abs() < 1e-4);
+        assert!((abbe(&glass) - 64.17).abs() < 0.1);
+        assert!(glass.ior(1.5, 400.0) > glass.ior(1.5, 700.0));
+    }
+
+    #[test]
+    fn abbe_fit_gives_back_its_inputs() {
+        let glass = Dispersion::from_abbe(1.62, 36.0);
+        assert!((glass.ior(1.62, D) - 1.62).abs() < 1e-3);
+        assert!((abbe(&glass) - 36.0).abs() < 0.1);
+        assert!(Dispersion::None.ior(1.33, 400.0) == 1.33);
+    }
+}
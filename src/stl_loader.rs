@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use ultraviolet::DVec3;
+use crate::obj_loader::{MeshData, position_key};
+
+fn read_f32(data: &[u8], at: usize) -> f64 {
+    f32::from_le_bytes(data[at..at + 4].try_into().unwrap()) as f64
+}
+
+fn read_vec(data: &[u8], at: usize) -> DVec3 {
+    DVec3::new(read_f32(data, at), read_f32(data, at + 4), read_f32(data, at + 8))
+}
+
+// facets of a binary file, normal first
+fn binary_facets(data: &[u8]) -> Vec<[DVec3; 4]> {
+    let count = u32::from_le_bytes(data[80..84].try_into().unwrap()) as usize;
+    (0..count).map(|i| {
+        let at = 84 + i * 50;
+        [0, 12, 24, 36].map(|offset| read_vec(data, at + offset))
+    }).collect()
+}
+
+fn ascii_facets(text: &str, filename: &str) -> Result<Vec<[DVec3; 4]>, String> {
+    let mut facets = vec![];
+    let mut normal = DVec3::zero();
+    let mut vertices = vec![];
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let parse = |s: &[&str]| -> Result<DVec3, String> {
+            let v: Result<Vec<f64>, _> = s.iter().map(|w| w.parse::<f64>()).collect();
+            match v.as_deref() {
+                Ok([x, y, z]) => Ok(DVec3::new(*x, *y, *z)),
+                _ => Err(format!("bad stl line {:?} in {}", line.trim(), filename)),
+            }
+        };
+        match words.as_slice() {
+            ["facet", "normal", rest @ ..] => {
+                normal = parse(rest)?;
+                vertices.clear();
+            }
+            ["vertex", rest @ ..] => vertices.push(parse(rest)?),
+            // polygons with more than three corners are fanned
+            ["endfacet"] => {
+                for k in 1..vertices.len().saturating_sub(1) {
+                    facets.push([normal, vertices[0], vertices[k], vertices[k + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(facets)
+}
+
+// ascii or binary stl. the stored facet normals are often wrong so the winding decides, and
+// corners are only shared between facets facing the same way, which keeps the flat look
+pub fn load_stl(filename: &str) -> Result<MeshData, String> {
+    let mut data = vec![];
+    BufReader::new(File::open(filename).map_err(|e| format!("couldn't open {}: {}", filename, e))?)
+        .read_to_end(&mut data).map_err(|e| format!("couldn't read {}: {}", filename, e))?;
+    parse_stl(&data, filename)
+}
+
+// the file's bytes, filename is only for the errors
+pub fn parse_stl(data: &[u8], filename: &str) -> Result<MeshData, String> {
+
+    // binary files can start with "solid" too, their size is the reliable tell
+    let binary = data.len() >= 84 && data.len() == 84 + 50 * u32::from_le_bytes(data[80..84].try_into().unwrap()) as usize;
+    let facets = if binary {
+        binary_facets(data)
+    } else if data.starts_with(b"solid") {
+        ascii_facets(&String::from_utf8_lossy(data), filename)?
+    } else {
+        return Err(format!("{} isn't an stl file", filename));
+    };
+
+    let mut mesh = MeshData::new(true);
+    let mut vertices: HashMap<([u64; 3], [u64; 3]), u32> = HashMap::new();
+    for [stored, p0, p1, p2] in facets {
+        let winding = (p1 - p0).cross(p2 - p0);
+        let normal = if winding.mag_sq() > 0.0 {winding.normalized()} else if stored.mag_sq() > 0.0 {stored.normalized()} else {continue};
+        let tri = [p0, p1, p2].map(|p| match vertices.get(&(position_key(p), position_key(normal))) {
+            Some(&i) => i,
+            None => {
+                let i = mesh.push_vertex(p, normal, None);
+                vertices.insert((position_key(p), position_key(normal)), i);
+                i
+            }
+        });
+        mesh.indices.push(tri);
+    }
+    if mesh.indices.is_empty() {return Err(format!("{} has no facets", filename))};
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two triangles of a unit square in the xy plane, the second with a wrong stored normal
+    const SQUARE: [[[f32; 3]; 4]; 2] = [
+        [[0.0, 0.0, 1.0], [0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+        [[0.0, 0.0, -1.0], [0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+    ];
+
+    fn check_square(mesh: &MeshData) {
+        assert_eq!(mesh.triangle_count(), 2);
+        // the diagonal's ends are shared, the winding wins over the stored normal
+        assert_eq!(mesh.vertex_count(), 4);
+        for i in 0..4 {
+            assert!((mesh.normal(i) - DVec3::unit_z()).mag() < 1e-6);
+        }
+        assert!((mesh.position(mesh.indices[1][2]) - DVec3::new(0.0, 1.0, 0.0)).mag() < 1e-6);
+    }
+
+    fn binary(header: &[u8]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(80, b' ');
+        data.extend(2u32.to_le_bytes());
+        for facet in SQUARE {
+            for x in facet.iter().flatten() {
+                data.extend(x.to_le_bytes());
+            }
+            data.extend([0, 0]);
+        }
+        data
+    }
+
+    #[test]
+    fn binary_stl() {
+        check_square(&parse_stl(&binary(b"made by hand"), "square.stl").unwrap());
+        // some exporters start the binary header with solid as well
+        check_square(&parse_stl(&binary(b"solid square"), "square.stl").unwrap());
+    }
+
+    #[test]
+    fn ascii_stl() {
+        let mut text = "solid square\n".to_string();
+        for [n, a, b, c] in SQUARE {
+            text += &format!("  facet normal {} {} {}\n    outer loop\n", n[0], n[1], n[2]);
+            for p in [a, b, c] {
+                text += &format!("      vertex {:e} {:e} {:e}\n", p[0], p[1], p[2]);
+            }
+            text += "    endloop\n  endfacet\n";
+        }
+        text += "endsolid square\n";
+        check_square(&parse_stl(text.as_bytes(), "square.stl").unwrap());
+    }
+
+    #[test]
+    fn broken_stl_files() {
+        assert!(parse_stl(b"not an stl", "x.stl").is_err());
+        assert!(parse_stl(b"solid empty\nendsolid empty\n", "x.stl").is_err());
+        assert!(parse_stl(b"solid bad\nfacet normal 0 0 1\nvertex 0 zero 0\n", "x.stl").is_err());
+    }
+}
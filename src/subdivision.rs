@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use ultraviolet::{DVec2, DVec3};
+use crate::obj_loader::{MeshData, generate_tangents};
+
+// faces meeting at a steeper angle than this keep separate normals after subdivision
+const SMOOTH_ANGLE: f64 = 60.0;
+
+// polygon mesh before triangulation, faces refer to shared positions so neighbours can be found
+#[derive(Clone, Default)]
+pub struct Cage {
+    pub positions: Vec<DVec3>,
+    pub faces: Vec<Vec<u32>>,
+    // one per face corner, empty without a uv layout
+    pub uvs: Vec<Vec<DVec2>>,
+    // sharpness of tagged edges keyed by their sorted ends, infinite for hard edges. every level
+    // takes one off the sharpness, open boundaries are always hard
+    pub creases: HashMap<(u32, u32), f64>,
+    // one per face, which of the meshes to_meshes makes it ends up in. empty puts every face in the first
+    pub groups: Vec<u32>,
+}
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    if a < b {(a, b)} else {(b, a)}
+}
+
+fn lerp(a: DVec3, b: DVec3, t: f64) -> DVec3 {
+    a + (b - a) * t
+}
+
+// who touches whom in a cage
+struct Topology {
+    edges: Vec<(u32, u32)>,
+    edge_index: HashMap<(u32, u32), usize>,
+    edge_faces: Vec<Vec<usize>>,
+    vertex_edges: Vec<Vec<usize>>,
+    vertex_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(cage: &Cage) -> Topology {
+        let mut topo = Topology{
+            edges: vec![],
+            edge_index: HashMap::new(),
+            edge_faces: vec![],
+            vertex_edges: vec![vec![]; cage.positions.len()],
+            vertex_faces: vec![vec![]; cage.positions.len()],
+        };
+        for (f, face) in cage.faces.iter().enumerate() {
+            for (i, &a) in face.iter().enumerate() {
+                topo.vertex_faces[a as usize].push(f);
+                let key = edge_key(a, face[(i + 1) % face.len()]);
+                let e = match topo.edge_index.get(&key) {
+                    Some(&e) => e,
+                    None => {
+                        let e = topo.edges.len();
+                        topo.edges.push(key);
+                        topo.edge_faces.push(vec![]);
+                        topo.vertex_edges[key.0 as usize].push(e);
+                        topo.vertex_edges[key.1 as usize].push(e);
+                        topo.edge_index.insert(key, e);
+                        e
+                    }
+                };
+                topo.edge_faces[e].push(f);
+            }
+        }
+        topo
+    }
+
+    fn edge(&self, a: u32, b: u32) -> usize {
+        self.edge_index[&edge_key(a, b)]
+    }
+
+    fn other_end(&self, e: usize, v: u32) -> u32 {
+        let (a, b) = self.edges[e];
+        if a == v {b} else {a}
+    }
+}
+
+// a corner's position index with the bits of its uv and normal
+type CornerKey = (u32, [u64; 2], [u64; 3]);
+
+impl Cage {
+    pub fn crease(&mut self, a: u32, b: u32, sharpness: f64) {
+        self.creases.insert(edge_key(a, b), sharpness);
+    }
+
+    pub fn is_triangles(&self) -> bool {
+        self.faces.iter().all(|f| f.len() == 3)
+    }
+
+    // loop subdivision for all triangle cages, catmull-clark for anything with quads or larger
+    // polygons, then triangulated with smooth normals and tangents. one mesh per group
+    pub fn subdivide(&self, levels: usize) -> Vec<MeshData> {
+        let mut cage = self.clone();
+        for _ in 0..levels {
+            cage = if cage.is_triangles() {cage.loop_step()} else {cage.catmull_clark_step()};
+        }
+        cage.to_meshes()
+    }
+
+    fn sharpness(&self, topo: &Topology, e: usize) -> f64 {
+        if topo.edge_faces[e].len() != 2 {return f64::INFINITY};
+        self.creases.get(&topo.edges[e]).copied().unwrap_or(0.0)
+    }
+
+    // new position of an edge given its smooth rule, sharp edges stay on the old edge
+    fn edge_rule(&self, topo: &Topology, e: usize, smooth: DVec3) -> DVec3 {
+        let (a, b) = topo.edges[e];
+        let mid = (self.positions[a as usize] + self.positions[b as usize]) * 0.5;
+        let s = self.sharpness(topo, e);
+        if s >= 1.0 {mid} else if s > 0.0 {lerp(smooth, mid, s)} else {smooth}
+    }
+
+    // new position of an old vertex given its smooth rule: two creases through it make it follow
+    // the crease curve, more pin it in place, as do the corners of open meshes
+    fn vertex_rule(&self, topo: &Topology, v: u32, smooth: DVec3) -> DVec3 {
+        let p = self.positions[v as usize];
+        let creases: Vec<(usize, f64)> = topo.vertex_edges[v as usize].iter()
+            .map(|&e| (e, self.sharpness(topo, e)))
+            .filter(|(_, s)| *s > 0.0)
+            .collect();
+        if creases.len() < 2 {return smooth};
+        let s = creases.iter().map(|(_, s)| s.min(1.0)).sum::<f64>() / creases.len() as f64;
+        let sharp = if creases.len() == 2 && topo.vertex_edges[v as usize].len() > 2 {
+            let (e1, e2) = (topo.other_end(creases[0].0, v), topo.other_end(creases[1].0, v));
+            (self.positions[e1 as usize] + p * 6.0 + self.positions[e2 as usize]) / 8.0
+        } else {p};
+        lerp(smooth, sharp, s)
+    }
+
+    // children of a crease are one step less sharp
+    fn child_creases(&self, topo: &Topology, edge_point: impl Fn(usize) -> u32) -> HashMap<(u32, u32), f64> {
+        let mut creases = HashMap::new();
+        for (&(a, b), &s) in &self.creases {
+            let Some(&e) = topo.edge_index.get(&(a, b)) else {continue};
+            if s <= 1.0 {continue};
+            let m = edge_point(e);
+            creases.insert(edge_key(a, m), s - 1.0);
+            creases.insert(edge_key(m, b), s - 1.0);
+        }
+        creases
+    }
+
+    // vertices first, then edge points, then face points
+    fn catmull_clark_step(&self) -> Cage {
+        let topo = Topology::new(self);
+        let (nv, ne) = (self.positions.len() as u32, topo.edges.len() as u32);
+        let face_points: Vec<DVec3> = self.faces.iter().map(|f| {
+            f.iter().fold(DVec3::zero(), |sum, &v| sum + self.positions[v as usize]) / f.len() as f64
+        }).collect();
+
+        let mut positions = Vec::with_capacity(self.positions.len() + topo.edges.len() + self.faces.len());
+        for v in 0..nv {
+            let (edges, faces) = (&topo.vertex_edges[v as usize], &topo.vertex_faces[v as usize]);
+            let p = self.positions[v as usize];
+            if edges.is_empty() || faces.is_empty() {
+                positions.push(p);
+                continue;
+            }
+            let n = edges.len() as f64;
+            let q = faces.iter().fold(DVec3::zero(), |sum, &f| sum + face_points[f]) / faces.len() as f64;
+            let r = edges.iter().fold(DVec3::zero(), |sum, &e| {
+                sum + (p + self.positions[topo.other_end(e, v) as usize]) * 0.5
+            }) / n;
+            positions.push(self.vertex_rule(&topo, v, (q + r * 2.0 + p * (n - 3.0)) / n));
+        }
+        for (e, &(a, b)) in topo.edges.iter().enumerate() {
+            let ends = self.positions[a as usize] + self.positions[b as usize];
+            let faces = &topo.edge_faces[e];
+            let smooth = if faces.len() == 2 {(ends + face_points[faces[0]] + face_points[faces[1]]) / 4.0} else {ends * 0.5};
+            positions.push(self.edge_rule(&topo, e, smooth));
+        }
+        positions.extend(&face_points);
+
+        let mut faces = vec![];
+        let mut uvs = vec![];
+        let mut groups = vec![];
+        for (f, face) in self.faces.iter().enumerate() {
+            let k = face.len();
+            let center = nv + ne + f as u32;
+            if let Some(&group) = self.groups.get(f) {
+                groups.extend(std::iter::repeat_n(group, k));
+            }
+            for i in 0..k {
+                let (prev, next) = ((i + k - 1) % k, (i + 1) % k);
+                faces.push(vec![
+                    face[i],
+                    nv + topo.edge(face[i], face[next]) as u32,
+                    center,
+                    nv + topo.edge(face[prev], face[i]) as u32
+                ]);
+                if let Some(uv) = self.uvs.get(f) {
+                    let average = uv.iter().fold(DVec2::zero(), |sum, t| sum + *t) / k as f64;
+                    uvs.push(vec![uv[i], (uv[i] + uv[next]) * 0.5, average, (uv[prev] + uv[i]) * 0.5]);
+                }
+            }
+        }
+        let creases = self.child_creases(&topo, |e| nv + e as u32);
+        Cage{positions, faces, uvs, creases, groups}
+    }
+
+    // vertices first, then edge points. triangles only
+    fn loop_step(&self) -> Cage {
+        let topo = Topology::new(self);
+        let nv = self.positions.len() as u32;
+
+        let mut positions = Vec::with_capacity(self.positions.len() + topo.edges.len());
+        for v in 0..nv {
+            let edges = &topo.vertex_edges[v as usize];
+            let p = self.positions[v as usize];
+            if edges.is_empty() {
+                positions.push(p);
+                continue;
+            }
+            let n = edges.len() as f64;
+            let beta = if edges.len() == 3 {3.0 / 16.0} else {3.0 / (8.0 * n)};
+            let ring = edges.iter().fold(DVec3::zero(), |sum, &e| sum + self.positions[topo.other_end(e, v) as usize]);
+            positions.push(self.vertex_rule(&topo, v, p * (1.0 - n * beta) + ring * beta));
+        }
+        for (e, &(a, b)) in topo.edges.iter().enumerate() {
+            let ends = self.positions[a as usize] + self.positions[b as usize];
+            let faces = &topo.edge_faces[e];
+            let smooth = if faces.len() == 2 {
+                // the corners across the edge
+                let opposite = |f: usize| {
+                    let v = *self.faces[f].iter().find(|&&v| v != a && v != b).unwrap_or(&a);
+                    self.positions[v as usize]
+                };
+                ends * 0.375 + (opposite(faces[0]) + opposite(faces[1])) * 0.125
+            } else {ends * 0.5};
+            positions.push(self.edge_rule(&topo, e, smooth));
+        }
+
+        let mut faces = vec![];
+        let mut uvs = vec![];
+        let mut groups = vec![];
+        for (f, face) in self.faces.iter().enumerate() {
+            if let Some(&group) = self.groups.get(f) {
+                groups.extend([group; 4]);
+            }
+            let [a, b, c] = [face[0], face[1], face[2]];
+            let (ab, bc, ca) = (nv + topo.edge(a, b) as u32, nv + topo.edge(b, c) as u32, nv + topo.edge(c, a) as u32);
+            faces.extend([vec![a, ab, ca], vec![ab, b, bc], vec![ca, bc, c], vec![ab, bc, ca]]);
+            if let Some(uv) = self.uvs.get(f) {
+                let (tab, tbc, tca) = ((uv[0] + uv[1]) * 0.5, (uv[1] + uv[2]) * 0.5, (uv[2] + uv[0]) * 0.5);
+                uvs.extend([vec![uv[0], tab, tca], vec![tab, uv[1], tbc], vec![tca, tbc, uv[2]], vec![tab, tbc, tca]]);
+            }
+        }
+        let creases = self.child_creases(&topo, |e| nv + e as u32);
+        Cage{positions, faces, uvs, creases, groups}
+    }
+
+    // fan triangulated, one mesh per group. corners average the normals of the faces around their
+    // position that don't turn away too sharply, so hard creases stay hard in the shading too.
+    // faces of other groups count as well, so group borders don't show in the shading
+    pub fn to_meshes(&self) -> Vec<MeshData> {
+        let mut tris: Vec<([u32; 3], [DVec2; 3], usize)> = vec![];
+        for (f, face) in self.faces.iter().enumerate() {
+            let uv = |i: usize| self.uvs.get(f).map_or(DVec2::zero(), |uv| uv[i]);
+            let group = self.groups.get(f).map_or(0, |g| *g as usize);
+            for k in 1..face.len().saturating_sub(1) {
+                tris.push(([face[0], face[k], face[k + 1]], [uv(0), uv(k), uv(k + 1)], group));
+            }
+        }
+        let face_normals: Vec<DVec3> = tris.iter().map(|(t, _, _)| {
+            let p = t.map(|i| self.positions[i as usize]);
+            (p[1] - p[0]).cross(p[2] - p[0])
+        }).collect();
+        let mut around: Vec<Vec<usize>> = vec![vec![]; self.positions.len()];
+        for (t, (tri, _, _)) in tris.iter().enumerate() {
+            for &v in tri {
+                around[v as usize].push(t);
+            }
+        }
+
+        let cos_limit = SMOOTH_ANGLE.to_radians().cos();
+        let count = self.groups.iter().max().map_or(1, |g| *g as usize + 1);
+        let mut meshes: Vec<MeshData> = (0..count).map(|_| MeshData::new(true)).collect();
+        let mut vertices: Vec<HashMap<CornerKey, u32>> = vec![HashMap::new(); count];
+        for (t, (tri, uv, group)) in tris.iter().enumerate() {
+            let (data, vertices) = (&mut meshes[*group], &mut vertices[*group]);
+            let own = face_normals[t].normalized();
+            let mut index = [0; 3];
+            for k in 0..3 {
+                let n = around[tri[k] as usize].iter()
+                    .filter(|&&o| face_normals[o].normalized().dot(own) >= cos_limit)
+                    .fold(DVec3::zero(), |sum, &o| sum + face_normals[o]);
+                let n = if n.mag_sq() > 0.0 {n.normalized()} else {own};
+                let key = (tri[k], [uv[k].x.to_bits(), uv[k].y.to_bits()], [n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]);
+                index[k] = *vertices.entry(key).or_insert_with(|| {
+                    data.push_vertex(self.positions[tri[k] as usize], n, (!self.uvs.is_empty()).then_some(uv[k]))
+                });
+            }
+            data.indices.push(index);
+        }
+        for data in &mut meshes {
+            generate_tangents(data);
+        }
+        meshes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the cube from -1 to 1 with outward quads
+    fn cube() -> Cage {
+        let positions = (0..8).map(|i| DVec3::new(
+            if i & 1 == 0 {-1.0} else {1.0}, if i & 2 == 0 {-1.0} else {1.0}, if i & 4 == 0 {-1.0} else {1.0}
+        )).collect();
+        let faces = vec![vec![0, 2, 3, 1], vec![4, 5, 7, 6], vec![0, 1, 5, 4], vec![2, 6, 7, 3], vec![0, 4, 6, 2], vec![1, 3, 7, 5]];
+        Cage{positions, faces, ..Cage::default()}
+    }
+
+    fn positions(data: &MeshData) -> Vec<DVec3> {
+        (0..data.vertex_count() as u32).map(|i| data.position(i)).collect()
+    }
+
+    #[test]
+    fn smooth_cube_rounds_off() {
+        let meshes = cube().subdivide(1);
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].triangle_count(), 6 * 4 * 2);
+        let p = positions(&meshes[0]);
+        // catmull-clark moves a corner of valence 3 to (face points + 2 edge midpoints) / 3
+        assert!(p.iter().any(|p| (*p - DVec3::broadcast(5.0 / 9.0)).mag() < 1e-6));
+        // only the face points stay on the cube
+        assert!(p.iter().all(|p| p.abs().component_max() <= 1.0));
+        assert_eq!(p.iter().filter(|p| p.abs().component_max() == 1.0).count(), 6);
+        for t in 0..meshes[0].triangle_count() {
+            let [p0, _, _] = meshes[0].triangle(t);
+            assert!(meshes[0].face_normal(t).dot(p0) > 0.0);
+        }
+    }
+
+    #[test]
+    fn hard_creases_keep_the_cube() {
+        let mut cage = cube();
+        for face in cage.faces.clone() {
+            for i in 0..4 {
+                cage.crease(face[i], face[(i + 1) % 4], f64::INFINITY);
+            }
+        }
+        let meshes = cage.subdivide(2);
+        let p = positions(&meshes[0]);
+        assert!(p.iter().all(|p| (p.abs().component_max() - 1.0).abs() < 1e-6));
+        assert!(p.iter().any(|p| (*p - DVec3::one()).mag() < 1e-6));
+        // the faces stay flat, so every normal points along an axis
+        for i in 0..meshes[0].vertex_count() as u32 {
+            assert!((meshes[0].normal(i).abs().component_max() - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn faces_keep_their_group() {
+        let mut cage = cube();
+        cage.groups = vec![0, 0, 1, 1, 1, 0];
+        let meshes = cage.subdivide(2);
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].triangle_count(), 3 * 16 * 2);
+        assert_eq!(meshes[1].triangle_count(), 3 * 16 * 2);
+    }
+}
@@ -0,0 +1,57 @@
+use ultraviolet::{DVec2, DVec3};
+use crate::color::{InputSpace, luminance};
+use serde::{Deserialize, Serialize};
+
+// rgb image looked up with wrapping uv coordinates, v points up as in obj files
+#[derive(Serialize, Deserialize)]
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<DVec3>,
+    // mean texel, for places that need a single value
+    pub average: DVec3,
+}
+
+impl Texture {
+    // color maps are usually srgb, normal and height maps linear
+    pub fn load(filename: &str, space: InputSpace) -> Result<Texture, String> {
+        let img = image::open(filename).map_err(|e| format!("couldn't load texture {}: {}", filename, e))?.into_rgb32f();
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        let data: Vec<DVec3> = img.pixels().map(|p| space.to_linear(DVec3::new(p[0] as f64, p[1] as f64, p[2] as f64))).collect();
+        let average = data.iter().fold(DVec3::zero(), |sum, c| sum + *c) / data.len().max(1) as f64;
+        Ok(Texture{width, height, data, average})
+    }
+
+    fn texel(&self, x: i64, y: i64) -> DVec3 {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.data[y * self.width + x]
+    }
+
+    // bilinear lookup
+    pub fn sample(&self, uv: DVec2) -> DVec3 {
+        let x = uv.x * self.width as f64 - 0.5;
+        let y = (1.0 - uv.y) * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    // grey value for height maps
+    pub fn height_at(&self, uv: DVec2) -> f64 {
+        luminance(self.sample(uv))
+    }
+
+    // height change per unit of surface length along u and v, from central differences one texel
+    // apart. dpdu and dpdv are how far the surface moves per unit of u and v, so the slope doesn't
+    // change with the texture's resolution or the uv layout's scale
+    pub fn height_gradient(&self, uv: DVec2, dpdu: f64, dpdv: f64) -> (f64, f64) {
+        let (du, dv) = (1.0 / self.width as f64, 1.0 / self.height as f64);
+        let dhdu = (self.height_at(uv + DVec2::new(du, 0.0)) - self.height_at(uv - DVec2::new(du, 0.0))) / (2.0 * du);
+        let dhdv = (self.height_at(uv + DVec2::new(0.0, dv)) - self.height_at(uv - DVec2::new(0.0, dv))) / (2.0 * dv);
+        (dhdu / dpdu.max(1e-12), dhdv / dpdv.max(1e-12))
+    }
+}